@@ -1,16 +1,14 @@
 mod channel;
 mod filter;
 
-use std::cell::RefCell;
+use std::io::{Read, Write};
 use std::io;
 use std::fmt;
-use std::fs::File;
-use std::rc::Rc;
 
-use crate::cpu::CPU;
 use crate::apu::channel::{DMC, Noise, SquareWave, TriangleWave, Voice};
 use crate::apu::filter::{Filter, HighPassFilter, LowPassFilter};
 use crate::console::NES_APU_CHANNELS;
+use crate::mapper::Mapper;
 use crate::mem::Memory;
 use crate::serde;
 
@@ -100,7 +98,7 @@ impl Memory for APU {
         }
     }
 
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         self.square1.save(output)?;
         self.square2.save(output)?;
         self.triangle.save(output)?;
@@ -115,13 +113,16 @@ impl Memory for APU {
         };
         serde::encode_u8(output, self.sequencer_value)?;
         serde::encode_u8(output, self.irq as u8)?;
+        serde::encode_u8(output, self.frame_irq as u8)?;
 
-        // TODO filters
+        for filter in &self.filters {
+            filter.save(output)?;
+        }
 
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.square1.load(input)?;
         self.square2.load(input)?;
         self.triangle.load(input)?;
@@ -138,8 +139,11 @@ impl Memory for APU {
 
         self.sequencer_value = serde::decode_u8(input)?;
         self.irq = serde::decode_u8(input)? != 0;
+        self.frame_irq = serde::decode_u8(input)? != 0;
 
-        // TODO filters
+        for filter in self.filters.iter_mut() {
+            filter.load(input)?;
+        }
 
         Ok(())
     }
@@ -148,6 +152,9 @@ impl Memory for APU {
 pub struct StepResult {
     pub trigger_irq: bool,
     pub signal:      Option<f32>,
+
+    // The number of cycles that the CPU should be stalled for
+    pub stall:       u64,
 }
 
 impl APU {
@@ -189,10 +196,6 @@ impl APU {
         self.dmc.reset();
     }
 
-    pub fn attach_cpu(&mut self, cpu: Rc<RefCell<CPU>>) {
-        self.dmc.cpu = Some(cpu);
-    }
-
     //  $4015   if-d nt21   DMC IRQ, frame IRQ, length counter statuses
     fn read_status(&mut self) -> u8 {
         let mut rv = 0;
@@ -360,7 +363,7 @@ impl APU {
         self.noise.step_length();
     }
 
-    fn step_timers(&mut self) {
+    fn step_timers(&mut self, mapper: &mut dyn Mapper) {
         // The triangle channel ticks on every cycle. The other channels tick on
         // every other cycle.
 
@@ -370,7 +373,7 @@ impl APU {
             self.square1.step_timer();
             self.square2.step_timer();
             self.noise.step_timer();
-            self.dmc.step_timer();
+            self.dmc.step_timer(mapper);
         }
     }
 
@@ -428,17 +431,18 @@ impl APU {
         }
     }
 
-    pub fn step(&mut self) -> StepResult {
+    pub fn step(&mut self, mapper: &mut dyn Mapper) -> StepResult {
         let mut res = StepResult{
             trigger_irq: false,
             signal:      None,
+            stall:       0,
         };
 
         let cycle1 = self.cycles as f32;
         self.cycles += 1;
         let cycle2 = self.cycles as f32;
 
-        self.step_timers();
+        self.step_timers(mapper);
 
         // https://wiki.nesdev.com/w/index.php/APU_Frame_Counter
         //
@@ -467,6 +471,9 @@ impl APU {
 
         res.trigger_irq = self.frame_irq || self.dmc.irq_flag();
 
+        res.stall = self.dmc.stall;
+        self.dmc.stall = 0;
+
         return res;
     }
 }
@@ -1,11 +1,10 @@
-use std::cell::RefCell;
+use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
-use std::rc::Rc;
 
-use crate::cpu::CPU;
 use crate::apu::channel::Voice;
+use crate::mapper::Mapper;
 use crate::mem::Memory;
+use crate::serde;
 
 const TIMER_TABLE: [u16; 16] = [
     0x01AC, 0x017C, 0x0154, 0x0140,
@@ -31,7 +30,9 @@ pub struct DMC {
     sample_length: u16,
     pub current_length: u16,
 
-    pub cpu: Option<Rc<RefCell<CPU>>>,
+    // The number of cycles that the CPU needs to be stalled for, because of
+    // memory reads done by the DMC
+    pub stall: u64,
 
     timer_period: u16,
     timer_value: u16,
@@ -48,8 +49,53 @@ impl Voice for DMC {
 }
 
 impl Memory for DMC {
-    fn save(&self, _output: &mut File) -> io::Result<()> { Ok(()) }
-    fn load(&mut self, _input: &mut File) -> io::Result<()> { Ok(()) }
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
+        serde::encode_u8(output, self.enabled as u8)?;
+        serde::encode_u8(output, self.buffer)?;
+
+        serde::encode_u8(output, self.irq_enabled as u8)?;
+        serde::encode_u8(output, self.irq_flag as u8)?;
+
+        serde::encode_u8(output, self.dmc_loop as u8)?;
+        serde::encode_u8(output, self.bit_count)?;
+        serde::encode_u8(output, self.shift_register)?;
+
+        serde::encode_u16(output, self.sample_address)?;
+        serde::encode_u16(output, self.current_address)?;
+        serde::encode_u16(output, self.sample_length)?;
+        serde::encode_u16(output, self.current_length)?;
+
+        serde::encode_u64(output, self.stall)?;
+
+        serde::encode_u16(output, self.timer_period)?;
+        serde::encode_u16(output, self.timer_value)?;
+
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.enabled = serde::decode_u8(input)? != 0;
+        self.buffer = serde::decode_u8(input)?;
+
+        self.irq_enabled = serde::decode_u8(input)? != 0;
+        self.irq_flag = serde::decode_u8(input)? != 0;
+
+        self.dmc_loop = serde::decode_u8(input)? != 0;
+        self.bit_count = serde::decode_u8(input)?;
+        self.shift_register = serde::decode_u8(input)?;
+
+        self.sample_address = serde::decode_u16(input)?;
+        self.current_address = serde::decode_u16(input)?;
+        self.sample_length = serde::decode_u16(input)?;
+        self.current_length = serde::decode_u16(input)?;
+
+        self.stall = serde::decode_u64(input)?;
+
+        self.timer_period = serde::decode_u16(input)?;
+        self.timer_value = serde::decode_u16(input)?;
+
+        Ok(())
+    }
 }
 
 impl DMC {
@@ -74,7 +120,7 @@ impl DMC {
             timer_period: 0,
             timer_value: 0,
 
-            cpu: None,
+            stall: 0,
         }
     }
 
@@ -125,7 +171,7 @@ impl DMC {
         self.sample_length = ((val as u16) << 4) + 1;
     }
 
-    fn step_reader(&mut self) {
+    fn step_reader(&mut self, mapper: &mut dyn Mapper) {
         // When the sample buffer is in an empty state and the bytes counter is non-zero,
         // the following occur: The sample buffer is filled with the next sample byte read
         // from memory at the current address, subject to whatever mapping hardware is
@@ -139,16 +185,13 @@ impl DMC {
             return;
         }
 
-        if let Some(cpu) = &self.cpu {
-            // TODO this is up to 4 extra cycles, but could be fewer
-            cpu.borrow_mut().stall(4);
+        // TODO this is up to 4 extra cycles, but could be fewer
+        self.stall += 4;
 
-            self.shift_register = cpu.borrow_mut().read(self.current_address);
-            debug!("shift_register={:02X}", self.shift_register);
-        } else {
-            error!("No CPU configured. This breaks the DMC.");
-            return;
-        }
+        // Samples always live in PRG-ROM, between $8000 and $FFFF, so the
+        // cartridge is the only thing that we ever need to read from.
+        self.shift_register = mapper.read(self.current_address);
+        debug!("shift_register={:02X}", self.shift_register);
 
         self.bit_count = 8;
 
@@ -191,14 +234,14 @@ impl DMC {
         self.bit_count -= 1;
     }
 
-    pub fn step_timer(&mut self) {
+    pub fn step_timer(&mut self, mapper: &mut dyn Mapper) {
         if !self.enabled {
             return
         }
 
         //debug!("timer_value={}, timer_period={}", self.timer_value, self.timer_period);
 
-        self.step_reader();
+        self.step_reader(mapper);
         if self.timer_value == 0 {
             self.timer_value = self.timer_period;
             self.step_shifter();
@@ -1,5 +1,5 @@
+use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
 
 use crate::apu::channel::Voice;
 use crate::mem::Memory;
@@ -69,7 +69,7 @@ impl Voice for Noise {
 }
 
 impl Memory for Noise {
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         serde::encode_u8(output, self.enabled as u8)?;
 
         match self.mode {
@@ -92,7 +92,7 @@ impl Memory for Noise {
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.enabled = serde::decode_u8(input)? != 0;
 
         match serde::decode_u8(input)? {
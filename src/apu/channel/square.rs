@@ -1,5 +1,5 @@
+use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
 
 use crate::apu::channel::Voice;
 use crate::mem::Memory;
@@ -86,7 +86,7 @@ impl Voice for SquareWave {
 }
 
 impl Memory for SquareWave {
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         serde::encode_u8(output, self.enabled as u8)?;
         serde::encode_u8(output, self.channel)?;
 
@@ -116,7 +116,7 @@ impl Memory for SquareWave {
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.enabled = serde::decode_u8(input)? != 0;
         self.channel = serde::decode_u8(input)?;
 
@@ -1,5 +1,5 @@
+use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
 
 use crate::apu::channel::Voice;
 use crate::mem::Memory;
@@ -54,7 +54,7 @@ impl Voice for TriangleWave {
 }
 
 impl Memory for TriangleWave {
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         serde::encode_u8(output, self.enabled as u8)?;
         serde::encode_u8(output, self.length_enabled as u8)?;
         serde::encode_u8(output, self.length_value)?;
@@ -67,7 +67,7 @@ impl Memory for TriangleWave {
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.enabled = serde::decode_u8(input)? != 0;
         self.length_enabled = serde::decode_u8(input)? != 0;
         self.length_value = serde::decode_u8(input)?;
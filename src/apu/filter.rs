@@ -1,6 +1,13 @@
 use std::f32::consts::PI;
+use std::io::{Read, Write};
+use std::io;
 
-pub trait Filter {
+use crate::mem::Memory;
+use crate::serde;
+
+// Only the previous input and output of a filter change as it runs, so they're
+// all that need saving.
+pub trait Filter: Memory + Send {
     fn process(&mut self, signal: f32) -> f32;
 }
 
@@ -21,6 +28,20 @@ impl Filter for LowPassFilter {
     }
 }
 
+impl Memory for LowPassFilter {
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
+        serde::encode_f32(output, self.prev_x)?;
+        serde::encode_f32(output, self.prev_y)?;
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.prev_x = serde::decode_f32(input)?;
+        self.prev_y = serde::decode_f32(input)?;
+        Ok(())
+    }
+}
+
 impl LowPassFilter {
     pub fn new_filter(sample_rate: f32, cutoff: f32) -> Self {
         let c = sample_rate / PI / cutoff;
@@ -53,6 +74,20 @@ impl Filter for HighPassFilter {
     }
 }
 
+impl Memory for HighPassFilter {
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
+        serde::encode_f32(output, self.prev_x)?;
+        serde::encode_f32(output, self.prev_y)?;
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.prev_x = serde::decode_f32(input)?;
+        self.prev_y = serde::decode_f32(input)?;
+        Ok(())
+    }
+}
+
 impl HighPassFilter {
     pub fn new_filter(sample_rate: f32, cutoff: f32) -> Self {
         let c = sample_rate / PI / cutoff;
@@ -0,0 +1,369 @@
+// Key and game controller bindings
+//
+// Bindings are read from bindings.conf in the user's config directory, which
+// is written out with the default bindings the first time the emulator runs.
+// The file is split into sections, with one binding per line,
+//
+//   [controller1]
+//   a = N
+//   a = Right Shift
+//
+// where keys are named the way SDL names them, and game controller buttons
+// are named the way SDL's controller mappings name them. An action can be
+// bound more than once, and any action that isn't mentioned keeps its default
+// bindings.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use nes::{Button, Hotkey, MovieStart};
+
+use sdl2::controller;
+use sdl2::keyboard::Keycode;
+
+const DEFAULT_BINDINGS: &str = "\
+# Key bindings for the nes emulator. Keys are named the way SDL names them, and
+# an action can be bound to more than one key by repeating it.
+
+[controller1]
+up      = W
+left    = A
+down    = S
+right   = D
+a       = N
+b       = M
+turbo_a = H
+turbo_b = J
+start   = Return
+select  = Space
+
+[controller2]
+up      = Up
+left    = Left
+down    = Down
+right   = Right
+a       = .
+b       = ,
+turbo_a = L
+turbo_b = K
+start   = Right Shift
+select  = /
+
+# Controllers 3 and 4, which are only read with a four player adapter, have no
+# keys by default, but can be bound in [controller3] and [controller4].
+
+# Game controller buttons, which are the same for every port: a, b, x, y, back,
+# start, dpup, dpdown, dpleft and dpright, among others. Turbo A and B can be
+# bound with turbo_a and turbo_b, like the keys above.
+[gamepad]
+up     = dpup
+left   = dpleft
+down   = dpdown
+right  = dpright
+a      = b
+a      = y
+b      = a
+b      = x
+start  = start
+select = back
+
+# The Power Pad's buttons, numbered the way they are on side B of the mat. The
+# keypad is laid out the same way.
+[power_pad]
+button_1  = Keypad 7
+button_2  = Keypad 8
+button_3  = Keypad 9
+button_4  = Keypad -
+button_5  = Keypad 4
+button_6  = Keypad 5
+button_7  = Keypad 6
+button_8  = Keypad +
+button_9  = Keypad 1
+button_10 = Keypad 2
+button_11 = Keypad 3
+button_12 = Keypad Enter
+
+[hotkeys]
+pause                      = P
+save_state                 = F2
+load_state                 = F3
+record_movie               = F5
+record_movie_from_power_on = F6
+play_movie                 = F7
+trace                      = F8
+dump_chr                   = F9
+debugger                   = F10
+reset                      = F12
+rewind                     = Backspace
+slot_0                     = 0
+slot_1                     = 1
+slot_2                     = 2
+slot_3                     = 3
+slot_4                     = 4
+slot_5                     = 5
+slot_6                     = 6
+slot_7                     = 7
+slot_8                     = 8
+slot_9                     = 9
+";
+
+// What a key does when it's pressed
+#[derive(Clone, Copy, Debug)]
+pub enum Action {
+    Button(usize, Button),
+    Turbo(usize, Button),
+    PowerPad(usize),
+    Hotkey(Hotkey),
+    Rewind,
+}
+
+#[derive(Debug)]
+pub enum BindingsError {
+    IO(io::Error),
+    Invalid(usize, String),
+}
+
+impl fmt::Display for BindingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BindingsError::IO(e) => write!(f, "{}", e),
+            BindingsError::Invalid(line, reason) => write!(f, "line {}: {}", line, reason),
+        }
+    }
+}
+
+// What a game controller button does, on whichever port the pad is in
+#[derive(Clone, Copy, Debug)]
+pub enum PadAction {
+    Button(Button),
+    Turbo(Button),
+}
+
+pub struct Bindings {
+    keys: HashMap<Keycode, Action>,
+    pad_buttons: HashMap<controller::Button, PadAction>,
+}
+
+// Every binding in a file, by section and action name, along with the line
+// that each one is on
+type Parsed = HashMap<(String, String), Vec<(usize, String)>>;
+
+impl Bindings {
+    // Loads the bindings from the config directory, writing the defaults there
+    // if there aren't any yet. Problems with the file are reported, and the
+    // defaults are used instead.
+    pub fn load() -> Self {
+        let path = match config_path() {
+            Some(path) => path,
+            None       => return Self::default(),
+        };
+
+        if !path.exists() {
+            let written = path.parent().map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| fs::write(&path, DEFAULT_BINDINGS));
+
+            match written {
+                Ok(()) => println!("wrote the default key bindings to {}", path.display()),
+                Err(e) => warn!("unable to write {}: {}", path.display(), e),
+            }
+
+            return Self::default();
+        }
+
+        match Self::load_file(&path) {
+            Ok(bindings) => bindings,
+            Err(e) => {
+                println!("unable to load key bindings from {}, {}", path.display(), e);
+                Self::default()
+            },
+        }
+    }
+
+    fn load_file(path: &Path) -> Result<Self, BindingsError> {
+        let text = fs::read_to_string(path).map_err(BindingsError::IO)?;
+
+        let user = parse(&text)?;
+
+        // The file's bindings go on top of the defaults, in case a key is
+        // bound to something else by default
+        let mut defaults = parse(DEFAULT_BINDINGS).expect("invalid default bindings");
+        defaults.retain(|action, _| !user.contains_key(action));
+
+        let mut bindings = Self::empty();
+        bindings.bind(&defaults).expect("invalid default bindings");
+        bindings.bind(&user)?;
+
+        Ok(bindings)
+    }
+
+    fn empty() -> Self {
+        Self {
+            keys: HashMap::new(),
+            pad_buttons: HashMap::new(),
+        }
+    }
+
+    fn bind(&mut self, parsed: &Parsed) -> Result<(), BindingsError> {
+        for ((section, name), values) in parsed {
+            let line = values[0].0;
+            let invalid = |line: usize, reason: String| BindingsError::Invalid(line, reason);
+
+            if section == "gamepad" {
+                let action = pad_action_for_name(name)
+                    .ok_or_else(|| invalid(line, format!("unknown button {} in [gamepad]", name)))?;
+
+                for (line, value) in values {
+                    let pad_button = controller::Button::from_string(value)
+                        .ok_or_else(|| invalid(*line, format!("unknown game controller button {}", value)))?;
+                    self.pad_buttons.insert(pad_button, action);
+                }
+
+                continue;
+            }
+
+            let action = match section.as_str() {
+                "controller1" | "controller2" | "controller3" | "controller4" => {
+                    let port = (section.as_bytes()[10] - b'1') as usize;
+                    pad_action_for_name(name).map(|action| match action {
+                        PadAction::Button(button) => Action::Button(port, button),
+                        PadAction::Turbo(button)  => Action::Turbo(port, button),
+                    })
+                },
+                "power_pad" => action_for_power_pad(name),
+                "hotkeys"   => action_for_hotkey(name),
+                _           => None,
+            }.ok_or_else(|| invalid(line, format!("unknown action {} in [{}]", name, section)))?;
+
+            for (line, value) in values {
+                // Letter keys are named in upper case, but their keycodes are
+                // the lower case letters
+                let key = Keycode::from_name(&value.to_lowercase())
+                    .or_else(|| Keycode::from_name(value))
+                    .ok_or_else(|| invalid(*line, format!("unknown key {}", value)))?;
+                self.keys.insert(key, action);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn action_for_key(&self, key: Keycode) -> Option<Action> {
+        self.keys.get(&key).copied()
+    }
+
+    pub fn action_for_pad_button(&self, button: controller::Button) -> Option<PadAction> {
+        self.pad_buttons.get(&button).copied()
+    }
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        let mut bindings = Self::empty();
+        bindings.bind(&parse(DEFAULT_BINDINGS).expect("invalid default bindings"))
+            .expect("invalid default bindings");
+        bindings
+    }
+}
+
+// Where the bindings live, following each platform's convention for config
+// files
+fn config_path() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(|home| PathBuf::from(home).join("Library/Application Support"))
+    } else {
+        env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+    };
+
+    dir.map(|dir| dir.join("nes").join("bindings.conf"))
+}
+
+fn parse(text: &str) -> Result<Parsed, BindingsError> {
+    let mut parsed = Parsed::new();
+    let mut section = None;
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            section = Some(line[1 .. line.len() - 1].trim().to_string());
+            continue;
+        }
+
+        let invalid = |reason: &str| BindingsError::Invalid(n + 1, reason.to_string());
+
+        let section = section.clone().ok_or_else(|| invalid("binding outside of a section"))?;
+        let eq = line.find('=').ok_or_else(|| invalid("expected action = key"))?;
+        let name = line[.. eq].trim().to_lowercase();
+        let value = line[eq + 1 ..].trim().to_string();
+
+        if value.is_empty() {
+            return Err(invalid("missing key"));
+        }
+
+        parsed.entry((section, name)).or_default().push((n + 1, value));
+    }
+
+    Ok(parsed)
+}
+
+fn pad_action_for_name(name: &str) -> Option<PadAction> {
+    let button = match name {
+        "up"      => Button::Up,
+        "left"    => Button::Left,
+        "down"    => Button::Down,
+        "right"   => Button::Right,
+        "a"       => Button::A,
+        "b"       => Button::B,
+        "start"   => Button::Start,
+        "select"  => Button::Select,
+        "turbo_a" => return Some(PadAction::Turbo(Button::A)),
+        "turbo_b" => return Some(PadAction::Turbo(Button::B)),
+        _         => return None,
+    };
+
+    Some(PadAction::Button(button))
+}
+
+fn action_for_power_pad(name: &str) -> Option<Action> {
+    let button: usize = name.strip_prefix("button_")?.parse().ok()?;
+    if !(1 ..= 12).contains(&button) {
+        return None;
+    }
+
+    Some(Action::PowerPad(button - 1))
+}
+
+fn action_for_hotkey(name: &str) -> Option<Action> {
+    let hotkey = match name {
+        "pause"                      => Hotkey::Pause,
+        "save_state"                 => Hotkey::SaveState,
+        "load_state"                 => Hotkey::LoadState,
+        "reset"                      => Hotkey::Reset,
+        "dump_chr"                   => Hotkey::DumpCHR,
+        "debugger"                   => Hotkey::Debug,
+        "trace"                      => Hotkey::Trace,
+        "record_movie"               => Hotkey::RecordMovie(MovieStart::SaveState),
+        "record_movie_from_power_on" => Hotkey::RecordMovie(MovieStart::PowerOn),
+        "play_movie"                 => Hotkey::PlayMovie,
+        "rewind"                     => return Some(Action::Rewind),
+        _ => {
+            let slot = name.strip_prefix("slot_")?.parse().ok()?;
+            if slot >= nes::SAVE_SLOTS {
+                return None;
+            }
+            Hotkey::SelectSlot(slot)
+        },
+    };
+
+    Some(Action::Hotkey(hotkey))
+}
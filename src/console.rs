@@ -1,29 +1,32 @@
-use std::cell::RefCell;
 use std::env;
 use std::fs;
 use std::fs::File;
-use std::io::Write;
-use std::process;
-use std::rc::Rc;
+use std::io::{Read, Write};
+use std::io;
+use std::path::PathBuf;
 use std::thread;
-use std::time::{Duration, Instant};
-
-use crate::apu::APU;
-use crate::controller::Controller;
-use crate::cpu::CPU;
-use crate::mapper::{Mapper, MapperEvent};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::vec::Drain;
+
+use crate::controller::Button;
+use crate::cpu::{TraceOptions, Tracer, CPU};
+use crate::debugger::{Debugger, Prompt};
+use crate::frontend::{AudioSink, Hotkey, InputEvent, InputSource, VideoSink};
+use crate::input::InputDevice;
+use crate::input;
 use crate::mem::{Memory, NESMemory};
-use crate::ppu::PPU;
+use crate::movie::{Frame, Movie, MovieError, MovieStart};
+use crate::multitap::Multitap;
+use crate::palette::Color;
+use crate::ppu::DebugView;
+use crate::rewind::{Rewind, RewindSettings};
+use crate::savestate::{SaveStateError, Reader, Writer};
+use crate::savestate;
+use crate::serde;
+use crate::symbols::Symbols;
 use crate::ines::CartridgeError;
 use crate::ines;
 
-use sdl2::audio::AudioSpecDesired;
-use sdl2::pixels::Color;
-use sdl2::pixels::PixelFormatEnum;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::rect::Rect;
-
 lazy_static!{
     pub static ref NES_PPU_DEBUG: bool = match env::var("NES_PPU_DEBUG") {
         Ok(val) => val != "" && val != "0",
@@ -34,63 +37,198 @@ lazy_static!{
         Ok(val) => val.parse().expect("invalid NES_APU_CHANNELS value"),
         Err(_)  => std::u8::MAX,
     };
+
+    // How many frames turbo buttons stay pressed and released for
+    pub static ref NES_TURBO_RATE: u32 = match env::var("NES_TURBO_RATE") {
+        Ok(val) => val.parse().expect("invalid NES_TURBO_RATE value"),
+        Err(_)  => 2,
+    };
 }
 
-const NES_FPS: f64 = 60.0;
-const FRAME_DURATION: Duration = Duration::from_millis(((1.0 / NES_FPS) * 1000.0) as u64);
+// How often battery backed PRG-RAM is written to disk while running, if it has
+// changed, so that a crash doesn't lose too much progress
+const BATTERY_FLUSH_FRAMES: u64 = 60 * 10;
+
+// The number of save state slots, which are selected with the number keys
+pub const SAVE_SLOTS: u8 = 10;
 
-// The queue is full of f32s, and we want to maintain roughly 16384 samples in
-// the queue at all times, so 4 * 16384 is the goal size.
-const AUDIO_QUEUE_HIGH_WATER_MARK: u32 = 4 * 16384;
+pub struct StepResult {
+    // The number of CPU cycles that the instruction took
+    pub cycles: u64,
+
+    // Whether or not the PPU finished rendering a frame during the instruction
+    pub frame_finished: bool,
+}
 
 pub struct Console {
+    // The CPU, which owns the system bus, which in turn owns the rest of the
     // NES components
-    cpu:        Rc<RefCell<CPU>>,
-    ppu:        Rc<RefCell<PPU>>,
-    apu:        Rc<RefCell<APU>>,
-    cartridge:  Rc<RefCell<Box<dyn Mapper>>>,
-    controller: Rc<RefCell<Controller>>,
-
-    // The absolute path on disk to save state to
-    save_path:  String,
+    cpu:        CPU,
+
+    // The name that save state files start with, and the slot that saving and
+    // loading currently use
+    save_name:  String,
+    slot:       u8,
+
+    // MD5 of the ROM file, so that save states can be matched to it
+    rom_hash:   [u8; 16],
+
+    // Where battery backed PRG-RAM is persisted, if the cartridge has any,
+    // along with what was last read from or written to there
+    battery_path: Option<PathBuf>,
+    battery_ram:  Vec<u8>,
+
+    // Recent states to go back to, if rewinding is enabled
+    rewind:       Option<Rewind>,
+
+    // The ROM's file name, and the checksum that FCEUX identifies it by, for
+    // movies
+    rom_name:     String,
+    rom_checksum: [u8; 16],
+
+    // The state right after the cartridge was loaded, for movies that start
+    // from power on
+    power_on:     Vec<u8>,
+
+    // The movie being recorded or played, where it's written to or read from,
+    // and whether the console was reset since the last recorded frame
+    movie:        MovieState,
+    movie_path:   String,
+    reset_pending: bool,
+
+    // Where the trace log is written, and what goes in it
+    trace_path:    String,
+    trace_options: TraceOptions,
+
+    // Whether the console has been powered up, or had a state loaded into it,
+    // so that `run' doesn't reset a game that's already running
+    powered_up:   bool,
+
+    // Whether the debugger was told to quit, which `run' stops for
+    quitting:     bool,
+}
+
+enum MovieState {
+    Idle,
+    Recording(Movie),
+    Playing(Movie, usize),
 }
 
 impl Console {
-    pub fn new_nes_console(rom_path: &String) -> Result<Self, CartridgeError> {
+    pub fn new_nes_console(rom_path: &str) -> Result<Self, CartridgeError> {
         let full_path = fs::canonicalize(rom_path).map_err(CartridgeError::IO)?;
         info!("loading cartridge: {}", full_path.display());
         let basename_path = full_path.file_name().unwrap().to_str().unwrap();
-        let save_path = format!("{:x}.data", md5::compute(basename_path)).into();
-
-        let mut fh = File::open(full_path).map_err(CartridgeError::IO)?;
-        let cartridge = ines::load_file_into_memory(&mut fh)?;
-
-        let ppu = Rc::new(RefCell::new(PPU::new_nes_ppu(cartridge.clone())));
-        let apu = Rc::new(RefCell::new(APU::new_nes_apu()));
-        let controller = Rc::new(RefCell::new(Controller::new_controller()));
-        let mem = NESMemory::new_nes_mem(
-            ppu.clone(),
-            apu.clone(),
-            controller.clone()
-        );
-        let cpu = Rc::new(RefCell::new(CPU::new_cpu(Box::new(mem))));
-        apu.borrow_mut().attach_cpu(cpu.clone());
-
-        Ok(Self {
-            cpu:        cpu,
-            ppu:        ppu,
-            apu:        apu,
-            cartridge:  cartridge,
-            controller: controller,
-            save_path:  save_path,
-        })
+        let save_name = format!("{:x}", md5::compute(basename_path));
+
+        let rom = fs::read(&full_path).map_err(CartridgeError::IO)?;
+        let rom_hash = md5::compute(&rom).0;
+
+        // Everything after the header and the trainer, if there is one
+        let data_start = if rom.len() > 6 && rom[6] & 0x04 != 0 { 16 + 512 } else { 16 };
+        let rom_checksum = md5::compute(rom.get(data_start ..).unwrap_or(&[])).0;
+
+        let cartridge = ines::load_file_into_memory(&mut &rom[..])?;
+
+        // Battery saves live next to the ROM, like every other emulator keeps
+        // them, so that they can be moved between emulators.
+        let battery_path = if cartridge.battery_backed {
+            Some(full_path.with_extension("sav"))
+        } else {
+            None
+        };
+
+        let mut mem = NESMemory::new_nes_mem(cartridge.mapper);
+        for controller in mem.controllers.iter_mut() {
+            controller.set_turbo_rate(*NES_TURBO_RATE);
+        }
+        let mut cpu = CPU::new_cpu(mem);
+
+        // Labels for the debugger, from ca65's debug info or FCEUX's .nl
+        // files next to the ROM, if it has any
+        match Symbols::load_for_rom(&full_path) {
+            Ok(symbols) => {
+                if !symbols.is_empty() {
+                    info!("loaded {} symbols", symbols.len());
+                }
+                cpu.symbols = symbols;
+            },
+            Err(e) => warn!("unable to load symbols: {}", e),
+        }
+
+        let mut console = Self {
+            cpu,
+            save_name:    save_name.clone(),
+            slot:         0,
+            rom_hash,
+            battery_path,
+            battery_ram:  Vec::new(),
+            rewind:       None,
+            rom_name:     basename_path.to_string(),
+            rom_checksum,
+            power_on:     Vec::new(),
+            movie:        MovieState::Idle,
+            movie_path:   format!("{}.movie", save_name),
+            reset_pending: false,
+            trace_path:    format!("{}.trace", save_name),
+            trace_options: TraceOptions::default(),
+            powered_up:   false,
+            quitting:     false,
+        };
+
+        console.load_battery_ram().map_err(CartridgeError::IO)?;
+
+        let mut power_on = Vec::new();
+        console.save_to(&mut power_on).expect("unable to save the power on state");
+        console.power_on = power_on;
+
+        Ok(console)
+    }
+
+    fn load_battery_ram(&mut self) -> io::Result<()> {
+        let path = match &self.battery_path {
+            Some(path) => path,
+            None       => return Ok(()),
+        };
+
+        if let Some(ram) = self.cpu.mem.mapper.prg_ram() {
+            if path.exists() {
+                info!("loading battery backed RAM: {}", path.display());
+                let saved = fs::read(path)?;
+                let n = saved.len().min(ram.len());
+                ram[.. n].copy_from_slice(&saved[.. n]);
+            }
+
+            self.battery_ram = ram.to_vec();
+        }
+
+        Ok(())
+    }
+
+    // Writes battery backed PRG-RAM to its .sav file, if it has changed since
+    // it was last loaded or written.
+    pub fn flush_battery_ram(&mut self) -> io::Result<()> {
+        let path = match &self.battery_path {
+            Some(path) => path,
+            None       => return Ok(()),
+        };
+
+        if let Some(ram) = self.cpu.mem.mapper.prg_ram() {
+            if *ram != *self.battery_ram {
+                info!("saving battery backed RAM: {}", path.display());
+                fs::write(path, &ram)?;
+                self.battery_ram = ram.to_vec();
+            }
+        }
+
+        Ok(())
     }
 
     // Dump the current CHR contents to disk, in a file named tileset.chr, but
     // be warned, because if this file already exists, it will be overwritten.
     //
     // Only runnable if NES_PPU_DEBUG is non-zero.
-    fn dump_chr(&mut self) {
+    pub fn dump_chr(&mut self) {
         if !*NES_PPU_DEBUG {
             println!("Sorry! This can only be done in PPU debug mode.");
             return;
@@ -100,7 +238,7 @@ impl Console {
             let mut chr = [0; 0x2000];
 
             for x in 0 ..= 0x1fff {
-                let b = self.cartridge.borrow_mut().read(x);
+                let b = self.cpu.mem.mapper.read(x);
                 chr[x as usize] = b;
             }
 
@@ -117,7 +255,7 @@ impl Console {
         let mut rv = String::new();
 
         loop {
-            let b = self.cpu.borrow_mut().read(addr);
+            let b = self.cpu.peek(addr);
 
             if b == 0 {
                 break;
@@ -131,279 +269,1085 @@ impl Console {
         rv
     }
 
-    // Detects if we're running a instr_test-v5 rom, and if so, it will output
-    // the test results.
-    fn debug_tests(&mut self) {
-        let a = self.cpu.borrow_mut().read(0x6001);
-        let b = self.cpu.borrow_mut().read(0x6002);
-        let c = self.cpu.borrow_mut().read(0x6003);
+    // Detects if we're running a instr_test-v5 rom, and if so, returns the
+    // final status code and the text output by the test once it has finished.
+    pub fn test_result(&mut self) -> Option<(u8, String)> {
+        let a = self.cpu.peek(0x6001);
+        let b = self.cpu.peek(0x6002);
+        let c = self.cpu.peek(0x6003);
 
         if a == 0xDE && b == 0xB0 && c == 0x61 {
-            let result = self.cpu.borrow_mut().read(0x6000);
+            let result = self.cpu.peek(0x6000);
 
             if result <= 0x7F {
-                let result_string = self.read_string(0x6004);
-                println!("{}", result_string);
-
-                println!("Emulator test complete, final status: 0x{:02X}", result);
-                process::exit(0);
+                return Some((result, self.read_string(0x6004)));
             }
         }
+
+        None
     }
 
-    fn save(&mut self) {
-        let mut fh = File::create(&self.save_path).unwrap();
-        self.cpu.borrow().save(&mut fh).expect("unable to save CPU state");
-        self.ppu.borrow().save(&mut fh).expect("unable to save PPU state");
-        self.apu.borrow().save(&mut fh).expect("unable to save APU state");
-        println!("saved state to {}", self.save_path);
+    // The path on disk of a save state slot
+    pub fn slot_path(&self, slot: u8) -> String {
+        format!("{}.{}.data", self.save_name, slot)
     }
 
-    fn load(&mut self) {
-        if let Ok(mut fh) = File::open(&self.save_path) {
-            self.cpu.borrow_mut().load(&mut fh).expect("unable to load CPU state");
-            self.ppu.borrow_mut().load(&mut fh).expect("unable to load PPU state");
-            //self.apu.borrow_mut().reset();
-            //self.apu.borrow_mut().load(&mut fh).expect("unable to laod APU state");
-            println!("loaded state from {}", self.save_path);
-        }
+    pub fn slot(&self) -> u8 {
+        self.slot
+    }
+
+    pub fn select_slot(&mut self, slot: u8) {
+        assert!(slot < SAVE_SLOTS, "bad save state slot: {}", slot);
+        self.slot = slot;
+    }
+
+    // When the state in a slot was saved, or None if the slot is empty, or
+    // holds a state that can't be loaded.
+    pub fn slot_timestamp(&self, slot: u8) -> Option<SystemTime> {
+        let mut fh = File::open(self.slot_path(slot)).ok()?;
+        let mut reader = Reader::new(&mut fh, &self.rom_hash).ok()?;
+
+        let mut secs = 0;
+        reader.section(&savestate::TIMESTAMP, |fh, _| {
+            secs = serde::decode_u64(fh)?;
+            Ok(())
+        }).ok()?;
+
+        Some(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    // Saves to the selected slot
+    pub fn save(&mut self) -> Result<(), SaveStateError> {
+        let mut fh = File::create(self.slot_path(self.slot)).map_err(SaveStateError::IO)?;
+        self.save_to(&mut fh)
+    }
+
+    // Loads from the selected slot
+    pub fn load(&mut self) -> Result<(), SaveStateError> {
+        let mut fh = File::open(self.slot_path(self.slot)).map_err(SaveStateError::IO)?;
+        self.load_from(&mut fh)
+    }
+
+    // Writes a save state to anything, such as a buffer in memory, rather than
+    // the usual file on disk.
+    pub fn save_to(&mut self, output: &mut dyn Write) -> Result<(), SaveStateError> {
+        let mut writer = Writer::new(output, &self.rom_hash)?;
+
+        let cpu = &self.cpu;
+        let mem = &cpu.mem;
+        writer.section(&savestate::CPU, |fh| cpu.save(fh))?;
+        writer.section(&savestate::RAM, |fh| mem.save(fh))?;
+        writer.section(&savestate::PPU, |fh| mem.ppu.save(fh))?;
+        writer.section(&savestate::APU, |fh| mem.apu.save(fh))?;
+        writer.section(&savestate::MAPPER, |fh| mem.mapper.save(fh))?;
+        writer.section(&savestate::CONTROLLERS, |fh| {
+            mem.controllers.iter().try_for_each(|controller| controller.save(fh))?;
+            mem.four_score.save(fh)?;
+            input::save_device(&mem.device, fh)
+        })?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        writer.section(&savestate::TIMESTAMP, |fh| serde::encode_u64(fh, now))?;
+
+        Ok(())
+    }
+
+    pub fn load_from(&mut self, input: &mut dyn Read) -> Result<(), SaveStateError> {
+        let mut reader = Reader::new(input, &self.rom_hash)?;
+
+        let cpu = &mut self.cpu;
+        reader.section(&savestate::CPU, |fh, version| cpu.load_version(fh, version))?;
+
+        let mem = &mut cpu.mem;
+        reader.section(&savestate::RAM, |fh, version| mem.load_version(fh, version))?;
+        reader.section(&savestate::PPU, |fh, version| mem.ppu.load_version(fh, version))?;
+        reader.section(&savestate::APU, |fh, version| mem.apu.load_version(fh, version))?;
+        reader.section(&savestate::MAPPER, |fh, version| mem.mapper.load_version(fh, version))?;
+        reader.section(&savestate::CONTROLLERS, |fh, version| {
+            mem.controllers.iter_mut().try_for_each(|controller| controller.load_version(fh, version))?;
+            mem.four_score.load_version(fh, version)?;
+            input::load_device(&mut mem.device, fh)
+        })?;
+
+        self.powered_up = true;
+        Ok(())
     }
 
     pub fn power_up(&mut self) {
         info!("powering up");
+        self.cpu.reset();
+        self.powered_up = true;
+    }
 
-        let sdl_context = sdl2::init().unwrap();
-        let video_subsystem = sdl_context.video().unwrap();
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.cpu.mem.apu.reset();
 
-        let mut width = 256 * 3;
-        let height = 240 * 3;
+        if let MovieState::Recording(_) = self.movie {
+            self.reset_pending = true;
+        }
+    }
 
-        if *NES_PPU_DEBUG {
-            // Make room for the two pattern tables, side by side
-            width += 2 * 144 + 20;
+    // Goes back to the state the console was in when the cartridge was loaded,
+    // and powers up from there.
+    fn power_cycle(&mut self) -> Result<(), SaveStateError> {
+        let power_on = std::mem::take(&mut self.power_on);
+        let result = self.load_from(&mut &power_on[..]);
+        self.power_on = power_on;
+
+        result?;
+        self.power_up();
+        Ok(())
+    }
+
+    // Executes a single CPU instruction. The bus keeps the rest of the system
+    // in step with the CPU as the instruction runs.
+    pub fn step(&mut self) -> StepResult {
+        let cycles = self.cpu.step();
+
+        if self.cpu.debugger.as_ref().map_or(false, |debugger| debugger.is_stopped()) {
+            self.debug_prompt();
         }
 
-        let window = video_subsystem.window("nes", width, height)
-            .position_centered()
-            .build()
-            .unwrap();
+        StepResult {
+            cycles,
+            frame_finished: self.cpu.mem.take_frame_finished(),
+        }
+    }
+
+    // Runs the system until the PPU has finished rendering a frame.
+    pub fn step_frame(&mut self) {
+        for controller in self.cpu.mem.controllers.iter_mut() {
+            controller.next_frame();
+        }
 
-        let mut canvas = window.into_canvas()
-            .target_texture()
-            .build()
-            .unwrap();
-        debug!("canvas: {}", canvas.info().name);
-        let texture_creator = canvas.texture_creator();
-        let mut texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, width, height)
-            .unwrap();
+        self.movie_frame();
+        while !self.step().frame_finished && !self.quitting { }
+        self.record_rewind();
+    }
 
-        for _ in 0 .. 2 {
-            canvas.clear();
-            canvas.set_draw_color(Color::RGB(0, 0, 0));
-            canvas.fill_rect(Rect::new(0, 0, width, height)).unwrap();
-            canvas.present();
+    // Records the controllers for the frame that's about to run, or plays back
+    // what was recorded for it.
+    fn movie_frame(&mut self) {
+        let frame = match &mut self.movie {
+            MovieState::Idle => return,
+
+            MovieState::Recording(movie) => {
+                movie.frames.push(Frame {
+                    buttons: [
+                        self.cpu.mem.controllers[0].buttons(),
+                        self.cpu.mem.controllers[1].buttons(),
+                        self.cpu.mem.controllers[2].buttons(),
+                        self.cpu.mem.controllers[3].buttons(),
+                    ],
+                    reset:   self.reset_pending,
+                    power:   false,
+                });
+                self.reset_pending = false;
+                return;
+            },
+
+            MovieState::Playing(movie, next) => {
+                match movie.frames.get(*next) {
+                    Some(frame) => {
+                        *next += 1;
+                        *frame
+                    },
+                    None => {
+                        info!("movie finished");
+                        self.movie = MovieState::Idle;
+                        return;
+                    },
+                }
+            },
+        };
+
+        if frame.power {
+            if let Err(e) = self.power_cycle() {
+                warn!("unable to power cycle: {}", e);
+            }
+        }
+        if frame.reset {
+            self.reset();
         }
 
-        let audio_subsystem = sdl_context.audio().unwrap();
-        debug!("audio driver: {}", audio_subsystem.current_audio_driver());
+        for (controller, &buttons) in self.cpu.mem.controllers.iter_mut().zip(&frame.buttons) {
+            controller.set_buttons(buttons);
+        }
+    }
 
-        let desired_spec = AudioSpecDesired {
-            freq:     Some(44_100),
-            channels: Some(2),
-            samples:  Some(1024),
+    // Starts recording a movie, either from power on, or from the current
+    // state, which is saved into the movie. Whatever movie was being recorded
+    // or played is stopped.
+    pub fn record_movie(&mut self, start: MovieStart) -> Result<(), SaveStateError> {
+        self.stop_movie();
+
+        let start = match start {
+            MovieStart::PowerOn => {
+                self.power_cycle()?;
+                None
+            },
+            MovieStart::SaveState => {
+                let mut state = Vec::new();
+                self.save_to(&mut state)?;
+                Some(state)
+            },
         };
-        let audio_device = audio_subsystem.open_queue(None, &desired_spec).unwrap();
-        audio_device.resume();
-        let mut samples = Vec::new();
-        let mut audio_sampling = true;
 
-        self.cpu.borrow_mut().reset();
+        self.reset_pending = false;
+        self.movie = MovieState::Recording(Movie {
+            rom_checksum: Some(self.rom_checksum),
+            rom_name:     self.rom_name.clone(),
+            start,
+            multitap:     self.cpu.mem.multitap,
+            frames:       Vec::new(),
+        });
 
-        let mut event_pump = sdl_context.event_pump().unwrap();
-        let mut fps_start = Instant::now();
-        let mut paused = false;
+        Ok(())
+    }
 
-        'running: loop {
-            let mut poll_keyboard = false;
-            self.debug_tests();
+    // Plays a movie back from the start, and hands control of the controllers
+    // over to it until it finishes.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), MovieError> {
+        if let Some(checksum) = movie.rom_checksum {
+            if checksum != self.rom_checksum {
+                return Err(MovieError::ROMMismatch);
+            }
+        }
 
-            if paused {
-                poll_keyboard = true;
-                thread::sleep(Duration::from_millis(200));
-            } else {
-                let cpu_cycles = self.cpu.borrow_mut().step();
-                let ppu_cycles = cpu_cycles * 3;
-                let apu_cycles = cpu_cycles;
+        self.stop_movie();
+        self.set_multitap(movie.multitap);
 
-                self.cartridge.borrow_mut()
-                    .notify(MapperEvent::CPUTick(cpu_cycles));
+        match &movie.start {
+            Some(state) => self.load_from(&mut &state[..]),
+            None        => self.power_cycle(),
+        }.map_err(MovieError::SaveState)?;
 
-                let mut frame_finished = false;
-                for _ in 0 .. ppu_cycles {
-                    let res = self.ppu.borrow_mut().step();
+        self.movie = MovieState::Playing(movie, 0);
+        Ok(())
+    }
 
-                    if self.cartridge.borrow().irq_flag() {
-                        self.cpu.borrow_mut().trigger_irq();
-                    }
+    // Stops recording or playing, and returns the movie that was being
+    // recorded, if there was one.
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        match std::mem::replace(&mut self.movie, MovieState::Idle) {
+            MovieState::Recording(movie) => Some(movie),
+            _                            => None,
+        }
+    }
 
-                    if res.trigger_nmi {
-                        self.cpu.borrow_mut().trigger_nmi();
-                    }
+    pub fn is_recording_movie(&self) -> bool {
+        matches!(self.movie, MovieState::Recording(_))
+    }
 
-                    if res.frame_finished {
-                        frame_finished = true;
-                    }
+    pub fn is_playing_movie(&self) -> bool {
+        matches!(self.movie, MovieState::Playing(..))
+    }
+
+    // Where movies are saved to and played from. Paths ending in .fm2 use
+    // FCEUX's format.
+    pub fn movie_path(&self) -> &str {
+        &self.movie_path
+    }
+
+    pub fn set_movie_path(&mut self, path: &str) {
+        self.movie_path = path.to_string();
+    }
+
+    fn movie_is_fm2(&self) -> bool {
+        self.movie_path.to_lowercase().ends_with(".fm2")
+    }
+
+    // Stops recording, and writes the movie to the movie path.
+    pub fn save_movie(&mut self) -> Result<(), MovieError> {
+        let movie = match self.stop_movie() {
+            Some(movie) => movie,
+            None        => return Ok(()),
+        };
+
+        let mut fh = File::create(&self.movie_path).map_err(MovieError::IO)?;
+        if self.movie_is_fm2() {
+            movie.write_fm2(&mut fh)
+        } else {
+            movie.write_to(&mut fh).map_err(MovieError::IO)
+        }
+    }
+
+    fn finish_recording(&mut self) {
+        match self.save_movie() {
+            Ok(()) => info!("saved movie to {}", self.movie_path),
+            Err(e) => warn!("unable to save movie: {}", e),
+        }
+    }
+
+    // Plays the movie at the movie path.
+    pub fn load_movie(&mut self) -> Result<(), MovieError> {
+        let mut fh = File::open(&self.movie_path).map_err(MovieError::IO)?;
+        let movie = if self.movie_is_fm2() {
+            Movie::read_fm2(&mut fh)?
+        } else {
+            Movie::read_from(&mut fh)?
+        };
+
+        self.play_movie(movie)
+    }
+
+    // Enables rewinding with the given settings, or disables it. Either way,
+    // any states that were recorded before are thrown away.
+    pub fn set_rewind(&mut self, settings: Option<RewindSettings>) {
+        self.rewind = settings.map(Rewind::new);
+    }
+
+    fn record_rewind(&mut self) {
+        let due = match &mut self.rewind {
+            Some(rewind) => rewind.tick(),
+            None         => false,
+        };
+
+        if due {
+            let mut state = Vec::new();
+            match self.save_to(&mut state) {
+                Ok(()) => self.rewind.as_mut().unwrap().push(state),
+                Err(e) => warn!("unable to record state for rewinding: {}", e),
+            }
+        }
+    }
+
+    // Goes back to the most recently recorded state before the current frame,
+    // and forgets it, so that calling this repeatedly plays the game
+    // backwards. Returns false once there's nothing left to go back to, or
+    // while a movie is recording or playing, since rewinding would throw it
+    // out of sync.
+    pub fn rewind(&mut self) -> Result<bool, SaveStateError> {
+        if !matches!(self.movie, MovieState::Idle) {
+            return Ok(false);
+        }
+
+        let rewind = match self.rewind.as_mut() {
+            Some(rewind) => rewind,
+            None         => return Ok(false),
+        };
+
+        if rewind.is_current() {
+            rewind.pop();
+        }
+
+        let state = match rewind.pop() {
+            Some(state) => state,
+            None        => return Ok(false),
+        };
+
+        self.load_from(&mut &state[..])?;
+        Ok(true)
+    }
+
+    // The most recently rendered frame, as 240 rows of 256 pixels
+    pub fn framebuffer(&self) -> &[Vec<Color>] {
+        self.cpu.mem.ppu.get_pixels()
+    }
+
+    // Removes and returns the mono audio samples, at 44.1kHz, that have been
+    // produced since the last time this was called.
+    pub fn drain_samples(&mut self) -> Drain<'_, f32> {
+        self.cpu.mem.drain_samples()
+    }
+
+    // Switches between catching the rest of the system up after every
+    // instruction, and running it alongside every bus access the CPU makes.
+    pub fn set_cycle_accurate(&mut self, cycle_accurate: bool) {
+        self.cpu.cycle_accurate = cycle_accurate;
+    }
+
+    // Plugs in a four player adapter, or unplugs it. Controllers 2 and 3
+    // (players 3 and 4) can only be read while one is plugged in.
+    pub fn set_multitap(&mut self, multitap: Multitap) {
+        self.cpu.mem.multitap = multitap;
+    }
+
+    pub fn multitap(&self) -> Multitap {
+        self.cpu.mem.multitap
+    }
+
+    // Plugs in a Zapper, an Arkanoid controller, a Power Pad or the Family
+    // BASIC keyboard, or unplugs it to leave just the standard controllers.
+    pub fn set_input_device(&mut self, device: InputDevice) {
+        self.cpu.mem.device = device.new_device();
+    }
+
+    pub fn input_device(&self) -> InputDevice {
+        self.cpu.mem.device.as_ref().map_or(InputDevice::None, |device| device.kind())
+    }
+
+    // Passes mouse, Power Pad and keyboard events along to the input device,
+    // if there is one
+    pub fn device_input(&mut self, event: InputEvent) {
+        if let Some(device) = &mut self.cpu.mem.device {
+            device.input(&event);
+        }
+    }
+
+    pub fn set_trace_path(&mut self, path: &str) {
+        self.trace_path = path.to_string();
+    }
+
+    pub fn set_trace_options(&mut self, options: TraceOptions) {
+        self.trace_options = options;
+    }
+
+    // Starts logging every instruction to the trace file, replacing whatever
+    // was there
+    pub fn start_trace(&mut self) -> io::Result<()> {
+        let tracer = Tracer::new_tracer(&self.trace_path, self.trace_options)?;
+        self.cpu.tracer = Some(Box::new(tracer));
+        Ok(())
+    }
+
+    pub fn stop_trace(&mut self) -> io::Result<()> {
+        match self.cpu.tracer.take() {
+            Some(mut tracer) => tracer.flush(),
+            None             => Ok(()),
+        }
+    }
+
+    // Pauses or carries on with the trace log, starting a new one if there
+    // isn't one, and returns whether instructions are being logged now
+    pub fn toggle_trace(&mut self) -> io::Result<bool> {
+        match &mut self.cpu.tracer {
+            Some(tracer) => {
+                tracer.enabled = !tracer.enabled;
+                if !tracer.enabled {
+                    tracer.flush()?;
                 }
+                Ok(tracer.enabled)
+            },
+            None => {
+                self.start_trace()?;
+                Ok(true)
+            },
+        }
+    }
 
-                for _ in 0 .. apu_cycles {
-                    let res = self.apu.borrow_mut().step();
+    pub fn is_tracing(&self) -> bool {
+        self.cpu.tracer.as_ref().map_or(false, |tracer| tracer.enabled)
+    }
 
-                    if res.trigger_irq {
-                        self.cpu.borrow_mut().trigger_irq();
-                    }
+    // Attaches the debugger, if it isn't already, and stops the CPU before the
+    // next instruction to give it a command prompt in the terminal
+    pub fn debug(&mut self) {
+        self.cpu.debugger
+            .get_or_insert_with(|| Box::new(Debugger::new_debugger()))
+            .break_in();
+    }
 
-                    if let Some(signal) = res.signal {
-                        if audio_sampling {
-                            samples.push(signal);
-                            samples.push(signal);
-                        }
+    // Reads debugger commands from stdin until one of them lets the CPU carry
+    // on. The end of stdin detaches the debugger.
+    fn debug_prompt(&mut self) {
+        let mut debugger = match self.cpu.debugger.take() {
+            Some(debugger) => debugger,
+            None           => return,
+        };
+
+        let mut stdout = io::stdout();
+        let _ = debugger.show_stop(&mut self.cpu, &mut stdout);
+
+        let prompt = loop {
+            print!("(debug) ");
+            let _ = stdout.flush();
+
+            let mut line = String::new();
+            match io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => break Prompt::Detach,
+                Ok(_)          => { },
+            }
+
+            match debugger.command(&mut self.cpu, &line, &mut stdout) {
+                Ok(Prompt::Stay) => { },
+                Ok(prompt)       => break prompt,
+                Err(_)           => break Prompt::Detach,
+            }
+        };
+
+        match prompt {
+            Prompt::Detach => println!("debugger detached"),
+            Prompt::Quit   => self.quitting = true,
+            _              => self.cpu.debugger = Some(debugger),
+        }
+    }
+
+    // Presses or releases a button on controller 0 to 3, for players 1 to 4
+    pub fn set_button(&mut self, port: usize, button: Button, pressed: bool) {
+        self.cpu.mem.controllers[port].set_button(button, pressed);
+    }
+
+    // Holds or lets go of the turbo version of a button, which presses and
+    // releases it every few frames.
+    pub fn set_turbo(&mut self, port: usize, button: Button, held: bool) {
+        self.cpu.mem.controllers[port].set_turbo(button, held);
+    }
+
+    pub fn set_turbo_rate(&mut self, frames: u32) {
+        for controller in self.cpu.mem.controllers.iter_mut() {
+            controller.set_turbo_rate(frames);
+        }
+    }
+
+    // For debugging purposes. Captures the palettes, pattern tables and
+    // nametables.
+    pub fn debug_view(&mut self) -> DebugView {
+        let mem = &mut self.cpu.mem;
+        mem.ppu.debug_view(&mut *mem.mapper)
+    }
+
+    // Runs the emulator until the input source asks to quit, presenting every
+    // frame and its audio to the given frontend.
+    pub fn run(&mut self,
+               video: &mut dyn VideoSink,
+               audio: &mut dyn AudioSink,
+               input: &mut dyn InputSource)
+    {
+        if !self.powered_up {
+            self.power_up();
+        }
+
+        let mut paused = false;
+        let mut rewinding = false;
+        let mut samples = Vec::new();
+        let mut frames = 0;
+
+        'running: loop {
+            if self.quitting {
+                break 'running;
+            }
+
+            if let Some((status, output)) = self.test_result() {
+                println!("{}", output);
+                println!("Emulator test complete, final status: 0x{:02X}", status);
+                break 'running;
+            }
+
+            if paused {
+                thread::sleep(Duration::from_millis(200));
+            } else if rewinding {
+                if let Err(e) = self.rewind() {
+                    warn!("unable to rewind: {}", e);
+                }
+
+                video.present(self.framebuffer());
+            } else {
+                self.step_frame();
+
+                frames += 1;
+                if frames % BATTERY_FLUSH_FRAMES == 0 {
+                    if let Err(e) = self.flush_battery_ram() {
+                        warn!("unable to save battery backed RAM: {}", e);
                     }
                 }
 
-                // Super basic dynamic sampling implementation.
-                //
-                // If the number of samples is too low, we'll end up with
-                // crackling and popping because the audio backend is consuming
-                // the samples faster than we can produce them, but if we have
-                // too many samples, the audio will get more and more out of
-                // sync with the video.
-                //
-                // We want to keep the audio queue full of samples, and we want
-                // to maintain at roughly AUDIO_QUEUE_HIGH_WATER_MARK samples.
-                // So if we've got more than that many in the queue, we stop
-                // sampling, and if we drop below, we start sampling again.
-                //
-                // This is much better than past attempts, and only
-                // occasionally results in some cracking and popping. I can
-                // live with this for now :)
-                if audio_sampling && audio_device.size() > AUDIO_QUEUE_HIGH_WATER_MARK {
-                    audio_sampling = false;
+                samples.clear();
+                samples.extend(self.drain_samples());
+                audio.queue(&samples);
+
+                if *NES_PPU_DEBUG {
+                    video.present_debug(&self.debug_view());
                 }
 
-                if !audio_sampling && audio_device.size() < AUDIO_QUEUE_HIGH_WATER_MARK {
-                    audio_sampling = true;
+                video.present(self.framebuffer());
+            }
+
+            for event in input.poll() {
+                match event {
+                    InputEvent::Quit => { break 'running },
+
+                    InputEvent::Button(port, button, pressed) => {
+                        self.set_button(port, button, pressed);
+                    },
+                    InputEvent::Turbo(port, button, held) => {
+                        self.set_turbo(port, button, held);
+                    },
+
+                    InputEvent::Rewind(held) => { rewinding = held },
+
+                    InputEvent::Mouse(_)
+                    | InputEvent::MouseButton(_)
+                    | InputEvent::PowerPad(..)
+                    | InputEvent::Key(..) => { self.device_input(event) },
+
+                    InputEvent::Hotkey(Hotkey::Pause)     => { paused = ! paused },
+                    InputEvent::Hotkey(Hotkey::SaveState) => {
+                        match self.save() {
+                            Ok(()) => info!("saved state to {}", self.slot_path(self.slot)),
+                            Err(e) => warn!("unable to save state: {}", e),
+                        }
+                    },
+                    InputEvent::Hotkey(Hotkey::LoadState) => {
+                        match self.load() {
+                            Ok(()) => info!("loaded state from {}", self.slot_path(self.slot)),
+                            Err(e) => warn!("unable to load state: {}", e),
+                        }
+                    },
+                    InputEvent::Hotkey(Hotkey::DumpCHR)   => { self.dump_chr() },
+                    InputEvent::Hotkey(Hotkey::SelectSlot(slot)) => {
+                        self.select_slot(slot);
+
+                        match self.slot_timestamp(slot) {
+                            Some(time) => {
+                                let age = time.elapsed().unwrap_or_default();
+                                info!("selected save slot {}, saved {} ago", slot, describe_age(age));
+                            },
+                            None => info!("selected save slot {}, which is empty", slot),
+                        }
+                    },
+                    InputEvent::Hotkey(Hotkey::Reset)     => { self.reset() },
+                    InputEvent::Hotkey(Hotkey::Debug)     => { self.debug() },
+                    InputEvent::Hotkey(Hotkey::Trace)     => {
+                        match self.toggle_trace() {
+                            Ok(true)  => info!("tracing to {}", self.trace_path),
+                            Ok(false) => info!("paused tracing"),
+                            Err(e)    => warn!("unable to trace: {}", e),
+                        }
+                    },
+
+                    InputEvent::Hotkey(Hotkey::RecordMovie(start)) => {
+                        if self.is_recording_movie() {
+                            self.finish_recording();
+                        } else {
+                            match self.record_movie(start) {
+                                Ok(()) => info!("recording movie"),
+                                Err(e) => warn!("unable to record movie: {}", e),
+                            }
+                        }
+                    },
+                    InputEvent::Hotkey(Hotkey::PlayMovie) => {
+                        if self.is_playing_movie() {
+                            self.stop_movie();
+                            info!("stopped playing movie");
+                        } else {
+                            match self.load_movie() {
+                                Ok(()) => info!("playing movie from {}", self.movie_path),
+                                Err(e) => warn!("unable to play movie: {}", e),
+                            }
+                        }
+                    },
                 }
+            }
+        }
 
-                if frame_finished {
-                    audio_device.queue(&samples);
-                    samples.clear();
-                    audio_sampling = true;
+        if self.is_recording_movie() {
+            self.finish_recording();
+        }
 
-                    let mut ppu = self.ppu.borrow_mut();
-                    let pixels  = ppu.get_pixels();
+        if let Err(e) = self.stop_trace() {
+            warn!("unable to finish the trace log: {}", e);
+        }
 
-                    texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
-                        for y in 0 .. 240 {
-                            for x in 0 .. 256 {
-                                let color  = pixels[y][x];
-                                let offset = 3*(y*pitch) + 3*(x*3);
+        if let Err(e) = self.flush_battery_ram() {
+            warn!("unable to save battery backed RAM: {}", e);
+        }
 
-                                for y2 in 0 .. 3 {
-                                    let offset = offset + (y2 * pitch);
+        info!("powering down");
+    }
+}
 
-                                    for x2 in 0 .. 3 {
-                                        let offset = offset + (x2 * 3);
+// Roughly how long ago something happened, for humans
+fn describe_age(age: Duration) -> String {
+    let secs = age.as_secs();
 
-                                        buffer[offset]   = color.r;
-                                        buffer[offset+1] = color.g;
-                                        buffer[offset+2] = color.b;
-                                    }
-                                }
-                            }
-                        }
-                    }).unwrap();
+    match secs {
+        0 ..= 59         => format!("{} seconds", secs),
+        60 ..= 3599      => format!("{} minutes", secs / 60),
+        3600 ..= 86399   => format!("{} hours", secs / 3600),
+        _                => format!("{} days", secs / 86400),
+    }
+}
 
-                    canvas.clear();
-                    canvas.copy(&texture, None, None).unwrap();
+impl Drop for Console {
+    // For anything driving the console without `run'
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_battery_ram() {
+            warn!("unable to save battery backed RAM: {}", e);
+        }
+    }
+}
 
-                    if *NES_PPU_DEBUG {
-                        ppu.render_tile_data(&mut canvas);
-                        ppu.render_tile_borders(&mut canvas);
-                    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
 
-                    canvas.present();
-                    if let Some(delay) = FRAME_DURATION.checked_sub(fps_start.elapsed()) {
-                        thread::sleep(delay);
-                    }
+    fn assert_send<T: Send>() {}
 
-                    fps_start = Instant::now();
+    #[test]
+    fn test_console_is_send() {
+        assert_send::<Console>();
+    }
 
-                    // Polling for events once per loop slows the emulator
-                    // right the fuck down, so I've moved to when a frame has
-                    // finished instead.
-                    poll_keyboard = true;
-                }
+    // Builds an NROM cartridge, whose program keeps the PPU, APU, OAM DMA and
+    // controller busy, so that as much state as possible changes every frame.
+    fn write_test_rom(name: &str, battery_backed: bool) -> PathBuf {
+        let mut prg = vec![0xea; 0x8000];
+
+        let reset = [
+            0x78,                   // SEI
+            0xd8,                   // CLD
+            0xa2, 0xff,             // LDX #$FF
+            0x9a,                   // TXS
+            0xa9, 0x80,             // LDA #$80
+            0x8d, 0x00, 0x20,       // STA $2000
+            0xa9, 0x1e,             // LDA #$1E
+            0x8d, 0x01, 0x20,       // STA $2001
+            0xa9, 0x1f,             // LDA #$1F
+            0x8d, 0x15, 0x40,       // STA $4015
+            0xa9, 0xbf,             // LDA #$BF
+            0x8d, 0x00, 0x40,       // STA $4000
+            0x8d, 0x0c, 0x40,       // STA $400C
+            0x8d, 0x08, 0x40,       // STA $4008
+            0xa9, 0x8f,             // LDA #$8F
+            0x8d, 0x10, 0x40,       // STA $4010
+            0xa9, 0xff,             // LDA #$FF
+            0x8d, 0x13, 0x40,       // STA $4013
+        ];
+
+        let main_loop = [
+            0xe6, 0x10,             // INC $10
+            0xa5, 0x10,             // LDA $10
+            0x8d, 0x02, 0x40,       // STA $4002
+            0x8d, 0x0a, 0x40,       // STA $400A
+            0x8d, 0x0e, 0x40,       // STA $400E
+            0xa9, 0x01,             // LDA #$01
+            0x8d, 0x03, 0x40,       // STA $4003
+            0x8d, 0x0b, 0x40,       // STA $400B
+            0x8d, 0x0f, 0x40,       // STA $400F
+            0x8d, 0x16, 0x40,       // STA $4016
+            0xa9, 0x00,             // LDA #$00
+            0x8d, 0x16, 0x40,       // STA $4016
+            0xad, 0x16, 0x40,       // LDA $4016
+            0x65, 0x11,             // ADC $11
+            0x85, 0x11,             // STA $11
+            0xa6, 0x10,             // LDX $10
+            0x9d, 0x00, 0x02,       // STA $0200,X
+            0x8a,                   // TXA
+            0x9d, 0x00, 0x60,       // STA $6000,X
+        ];
+
+        let nmi = [
+            0x48,                   // PHA
+            0xad, 0x02, 0x20,       // LDA $2002
+            0xe6, 0x12,             // INC $12
+            0xa9, 0x3f,             // LDA #$3F
+            0x8d, 0x06, 0x20,       // STA $2006
+            0xa9, 0x00,             // LDA #$00
+            0x8d, 0x06, 0x20,       // STA $2006
+            0xa5, 0x12,             // LDA $12
+            0x29, 0x3f,             // AND #$3F
+            0x8d, 0x07, 0x20,       // STA $2007
+            0xa9, 0x21,             // LDA #$21
+            0x8d, 0x06, 0x20,       // STA $2006
+            0xa5, 0x12,             // LDA $12
+            0x8d, 0x06, 0x20,       // STA $2006
+            0xa5, 0x11,             // LDA $11
+            0x8d, 0x07, 0x20,       // STA $2007
+            0xa9, 0x00,             // LDA #$00
+            0x8d, 0x05, 0x20,       // STA $2005
+            0x8d, 0x05, 0x20,       // STA $2005
+            0xa9, 0x02,             // LDA #$02
+            0x8d, 0x14, 0x40,       // STA $4014
+            0xa9, 0x80,             // LDA #$80
+            0x8d, 0x00, 0x20,       // STA $2000
+            0x68,                   // PLA
+            0x40,                   // RTI
+        ];
+
+        let loop_addr = 0x8000 + reset.len() as u16;
+        let jmp_loop = [0x4c, loop_addr as u8, (loop_addr >> 8) as u8];
+
+        let mut code = Vec::new();
+        code.extend_from_slice(&reset);
+        code.extend_from_slice(&main_loop);
+        code.extend_from_slice(&jmp_loop);
+        prg[.. code.len()].copy_from_slice(&code);
+
+        // NMI handler at $9000, and an IRQ handler at $9100 that just returns
+        prg[0x1000 .. 0x1000 + nmi.len()].copy_from_slice(&nmi);
+        prg[0x1100] = 0x40;
+
+        prg[0x7ffa .. 0x8000].copy_from_slice(&[0x00, 0x90, 0x00, 0x80, 0x00, 0x91]);
+
+        let chr = (0 .. 0x2000).map(|i| (i * 7 + i / 16) as u8).collect::<Vec<_>>();
+
+        let flags6 = if battery_backed { 0x02 } else { 0x00 };
+        let mut rom = vec![0x4e, 0x45, 0x53, 0x1a, 2, 1, flags6, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend(prg);
+        rom.extend(chr);
+
+        let path = env::temp_dir().join(name);
+        fs::write(&path, rom).unwrap();
+        path
+    }
 
-            }
+    fn ram(console: &mut Console) -> Vec<u8> {
+        (0 .. 0x800).map(|addr| console.cpu.peek(addr)).collect()
+    }
 
-            if poll_keyboard {
-                for event in event_pump.poll_iter() {
-                    match event {
-                        Event::Quit { .. } => { break 'running },
+    // Runs frames, keeping everything that they produced
+    fn run_frames(console: &mut Console, n: usize)
+        -> Vec<(Vec<Vec<Color>>, Vec<u8>, Vec<f32>)>
+    {
+        (0 .. n).map(|_| {
+            console.step_frame();
+            let samples = console.drain_samples().collect();
+            (console.framebuffer().to_vec(), ram(console), samples)
+        }).collect()
+    }
 
-                        Event::KeyDown { keycode: Some(key), .. } => {
-                            match key {
-                                Keycode::W => { self.controller.borrow_mut().up(true) },
-                                Keycode::A => { self.controller.borrow_mut().left(true) },
-                                Keycode::S => { self.controller.borrow_mut().down(true) },
-                                Keycode::D => { self.controller.borrow_mut().right(true) },
+    fn check_round_trip(name: &str, cycle_accurate: bool) {
+        let rom = write_test_rom(&format!("{}.nes", name), false);
+        let mut state = Vec::new();
+
+        let mut console = Console::new_nes_console(rom.to_str().unwrap()).unwrap();
+        console.set_cycle_accurate(cycle_accurate);
+        console.power_up();
+        run_frames(&mut console, 20);
+
+        console.save_to(&mut state).unwrap();
+        let expected = run_frames(&mut console, 30);
+
+        // Load into a console that has got itself into a different state
+        let mut other = Console::new_nes_console(rom.to_str().unwrap()).unwrap();
+        other.set_cycle_accurate(cycle_accurate);
+        other.power_up();
+        other.set_button(0, Button::A, true);
+        run_frames(&mut other, 7);
+
+        other.load_from(&mut &state[..]).unwrap();
+        other.set_button(0, Button::A, false);
+        let actual = run_frames(&mut other, 30);
+
+        for (i, (expected, actual)) in expected.iter().zip(actual.iter()).enumerate() {
+            assert!(expected.0 == actual.0, "framebuffer differs on frame {}", i);
+            assert!(expected.1 == actual.1, "RAM differs on frame {}", i);
+            assert!(expected.2 == actual.2, "audio differs on frame {}", i);
+        }
 
-                                Keycode::Return => { self.controller.borrow_mut().start(true) },
-                                Keycode::Space  => { self.controller.borrow_mut().select(true) },
+        // Make sure that the program really did keep things changing
+        assert!(expected[0].0 != expected[29].0);
+        assert!(expected[0].1 != expected[29].1);
+    }
 
-                                Keycode::N => { self.controller.borrow_mut().a(true) },
-                                Keycode::M => { self.controller.borrow_mut().b(true) },
+    #[test]
+    fn test_save_state_round_trip() {
+        check_round_trip("nes-console-round-trip", false);
+    }
 
-                                Keycode::P => { paused = ! paused },
+    #[test]
+    fn test_save_state_round_trip_cycle_accurate() {
+        check_round_trip("nes-console-round-trip-cycle-accurate", true);
+    }
 
-                                Keycode::F2 => { self.save() },
-                                Keycode::F3 => { self.load() },
+    #[test]
+    fn test_battery_ram() {
+        let rom = write_test_rom("nes-console-battery.nes", true);
+        let sav = rom.with_extension("sav");
+        let _ = fs::remove_file(&sav);
+
+        let mut console = Console::new_nes_console(rom.to_str().unwrap()).unwrap();
+        console.power_up();
+        run_frames(&mut console, 5);
+        console.flush_battery_ram().unwrap();
+
+        let saved = fs::read(&sav).unwrap();
+        assert_eq!(saved.len(), 0x2000);
+        assert!(saved.iter().any(|&b| b != 0));
+
+        // Nothing has run since, so there's nothing new to write
+        fs::remove_file(&sav).unwrap();
+        console.flush_battery_ram().unwrap();
+        assert!(!sav.exists());
+        fs::write(&sav, &saved).unwrap();
+
+        let mut console = Console::new_nes_console(rom.to_str().unwrap()).unwrap();
+        for (addr, &b) in saved.iter().enumerate() {
+            assert_eq!(console.cpu.peek(0x6000 + addr as u16), b);
+        }
+    }
 
-                                Keycode::F9 => { self.dump_chr() },
+    #[test]
+    fn test_save_slots() {
+        let rom = write_test_rom("nes-console-slots.nes", false);
+        let mut console = Console::new_nes_console(rom.to_str().unwrap()).unwrap();
+        console.save_name = env::temp_dir().join("nes-console-slots")
+            .to_str().unwrap().to_string();
 
-                                Keycode::F12 => {
-                                    self.cpu.borrow_mut().reset();
-                                    self.apu.borrow_mut().reset();
-                                },
+        for slot in 0 .. SAVE_SLOTS {
+            let _ = fs::remove_file(console.slot_path(slot));
+        }
 
-                                _ => {},
-                            }
-                        },
+        console.power_up();
+        run_frames(&mut console, 2);
 
-                        Event::KeyUp { keycode: Some(key), .. } => {
-                            match key {
-                                Keycode::W => { self.controller.borrow_mut().up(false) },
-                                Keycode::A => { self.controller.borrow_mut().left(false) },
-                                Keycode::S => { self.controller.borrow_mut().down(false) },
-                                Keycode::D => { self.controller.borrow_mut().right(false) },
+        console.select_slot(3);
+        console.save().unwrap();
+        let expected = run_frames(&mut console, 2);
 
-                                Keycode::Return => { self.controller.borrow_mut().start(false) },
-                                Keycode::Space  => { self.controller.borrow_mut().select(false) },
+        let saved_at = console.slot_timestamp(3).unwrap();
+        assert!(saved_at.elapsed().unwrap_or_default() < Duration::from_secs(60));
+        assert!(console.slot_timestamp(4).is_none());
 
-                                Keycode::N => { self.controller.borrow_mut().a(false) },
-                                Keycode::M => { self.controller.borrow_mut().b(false) },
+        console.select_slot(4);
+        assert!(console.load().is_err());
 
-                                _ => {},
-                            }
-                        },
+        console.select_slot(3);
+        console.load().unwrap();
+        assert!(run_frames(&mut console, 2) == expected);
+    }
 
-                        _ => {},
-                    }
-                }
+    #[test]
+    fn test_trace_toggle() {
+        let rom = write_test_rom("nes-console-trace.nes", false);
+        let mut console = Console::new_nes_console(rom.to_str().unwrap()).unwrap();
+        let path = env::temp_dir().join(format!("nes-console-{}.trace", std::process::id()));
+        console.set_trace_path(path.to_str().unwrap());
+        console.power_up();
+
+        assert!(console.toggle_trace().unwrap());
+        run_frames(&mut console, 1);
+        assert!(!console.toggle_trace().unwrap());
+        let paused = fs::read_to_string(&path).unwrap().len();
+        assert!(paused > 0);
+
+        // Carrying on adds to the same log, rather than starting it again
+        run_frames(&mut console, 1);
+        assert!(!console.is_tracing());
+        assert!(console.toggle_trace().unwrap());
+        run_frames(&mut console, 1);
+        console.stop_trace().unwrap();
+        assert!(fs::read_to_string(&path).unwrap().len() > paused);
+
+        let _ = fs::remove_file(&path);
+    }
 
+    #[test]
+    fn test_rewind() {
+        let rom = write_test_rom("nes-console-rewind.nes", false);
+        let mut console = Console::new_nes_console(rom.to_str().unwrap()).unwrap();
+        console.set_rewind(Some(RewindSettings {
+            interval:          1,
+            capacity:          4,
+            delta_compression: true,
+        }));
+
+        console.power_up();
+        let frames = run_frames(&mut console, 6);
+
+        // The snapshot of the current frame is skipped, so the first rewind
+        // goes back to the one before it
+        assert!(console.rewind().unwrap());
+        assert!(console.framebuffer() == &frames[4].0[..]);
+        assert!(ram(&mut console) == frames[4].1);
+        assert!(run_frames(&mut console, 1)[0] == frames[5]);
+
+        // Only four states were kept, two of which have been rewound past, and
+        // the last frame was recorded again, which is skipped again
+        assert!(console.rewind().unwrap());
+        assert!(console.rewind().unwrap());
+        assert!(!console.rewind().unwrap());
+        assert!(ram(&mut console) == frames[2].1);
+    }
+
+    #[test]
+    fn test_movie() {
+        let rom = write_test_rom("nes-console-movie.nes", false);
+        let mut console = Console::new_nes_console(rom.to_str().unwrap()).unwrap();
+
+        console.power_up();
+        run_frames(&mut console, 3);
+
+        // Record from power on, pressing A on some frames, with a reset
+        console.record_movie(MovieStart::PowerOn).unwrap();
+        let mut expected = Vec::new();
+        for i in 0 .. 12 {
+            console.set_button(0, Button::A, i % 3 == 0);
+            if i == 7 {
+                console.reset();
             }
+            expected.extend(run_frames(&mut console, 1));
         }
+        let movie = console.stop_movie().unwrap();
+        assert_eq!(movie.frames.len(), 12);
+        assert!(movie.frames[7].reset);
 
-        info!("powering down");
+        // Pressing A makes a difference to the test program
+        assert!(expected[0].1 != expected[1].1);
+
+        // Through FM2 and back
+        let mut fm2 = Vec::new();
+        movie.write_fm2(&mut fm2).unwrap();
+        let movie = Movie::read_fm2(&mut &fm2[..]).unwrap();
+
+        let mut console = Console::new_nes_console(rom.to_str().unwrap()).unwrap();
+        console.power_up();
+        run_frames(&mut console, 5);
+        console.set_button(0, Button::A, true);
+
+        console.play_movie(movie).unwrap();
+        assert!(console.is_playing_movie());
+        assert!(run_frames(&mut console, 12) == expected);
+
+        run_frames(&mut console, 1);
+        assert!(!console.is_playing_movie());
+    }
+
+    #[test]
+    fn test_movie_from_save_state() {
+        let rom = write_test_rom("nes-console-movie-state.nes", false);
+        let mut console = Console::new_nes_console(rom.to_str().unwrap()).unwrap();
+
+        console.power_up();
+        run_frames(&mut console, 4);
+
+        console.record_movie(MovieStart::SaveState).unwrap();
+        console.set_button(0, Button::A, true);
+        let expected = run_frames(&mut console, 6);
+        let movie = console.stop_movie().unwrap();
+        assert!(movie.start.is_some());
+
+        let mut data = Vec::new();
+        movie.write_to(&mut data).unwrap();
+        let movie = Movie::read_from(&mut &data[..]).unwrap();
+
+        console.set_button(0, Button::A, false);
+        console.play_movie(movie).unwrap();
+        assert!(run_frames(&mut console, 6) == expected);
+
+        let mut other = Movie::default();
+        other.rom_checksum = Some([0; 16]);
+        match console.play_movie(other) {
+            Err(MovieError::ROMMismatch) => { },
+            _ => panic!("expected a ROM mismatch"),
+        }
+    }
+
+    #[test]
+    fn test_turbo_movie() {
+        let rom = write_test_rom("nes-console-turbo.nes", false);
+        let mut console = Console::new_nes_console(rom.to_str().unwrap()).unwrap();
+        console.set_turbo_rate(2);
+
+        console.power_up();
+        run_frames(&mut console, 2);
+
+        console.record_movie(MovieStart::SaveState).unwrap();
+        console.set_turbo(1, Button::B, true);
+        let expected = run_frames(&mut console, 6);
+        let movie = console.stop_movie().unwrap();
+
+        // Turbo is recorded as the button going up and down
+        let buttons: Vec<u8> = movie.frames.iter().map(|frame| frame.buttons[1]).collect();
+        assert_eq!(buttons, [0x02, 0x02, 0x00, 0x00, 0x02, 0x02]);
+
+        console.set_turbo(1, Button::B, false);
+        console.play_movie(movie).unwrap();
+        assert!(run_frames(&mut console, 6) == expected);
     }
 }
@@ -7,11 +7,42 @@
 //
 // bit    |   7   |   6   |   5   |  4   |   3   |   2    |   1   |   0   |
 // button | right | left  | down  |  up  | start | select |   b   |   a   |
+//
+// Turbo buttons aren't part of the controller as far as the NES is concerned,
+// they just press and release a button over and over. That only happens on
+// frame boundaries, so what the game sees during a frame is fixed, and can be
+// recorded in a movie like any other input.
+
+use std::io::{Read, Write};
+use std::io;
 
 use crate::mem::Memory;
+use crate::serde;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Button {
+    A      = 0,
+    B      = 1,
+    Select = 2,
+    Start  = 3,
+    Up     = 4,
+    Down   = 5,
+    Left   = 6,
+    Right  = 7,
+}
 
 pub struct Controller {
+    // What the game sees, which is what's pressed, plus any turbo buttons that
+    // are held and currently switched on
     buttons: [bool; 8],
+    pressed: [bool; 8],
+    turbo: [bool; 8],
+
+    // How many frames turbo buttons stay pressed and released for, and how
+    // many frames they've been held for
+    turbo_rate: u32,
+    turbo_frames: u32,
+
     index: usize,
     strobe: u8,
 }
@@ -39,23 +70,112 @@ impl Memory for Controller {
             self.index = 0;
         }
     }
+
+    // The buttons belong to whoever is holding the controller, so only the
+    // shift register state is saved.
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
+        serde::encode_usize(output, self.index)?;
+        serde::encode_u8(output, self.strobe)?;
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.index = serde::decode_usize(input)?;
+        self.strobe = serde::decode_u8(input)?;
+        Ok(())
+    }
 }
 
 impl Controller {
     pub fn new_controller() -> Self {
         Self {
             buttons: [false; 8],
+            pressed: [false; 8],
+            turbo: [false; 8],
+            turbo_rate: 2,
+            turbo_frames: 0,
             index: 0,
             strobe: 0,
         }
     }
 
-    pub fn a(&mut self, v: bool) { self.buttons[0] = v; }
-    pub fn b(&mut self, v: bool) { self.buttons[1] = v; }
-    pub fn select(&mut self, v: bool) { self.buttons[2] = v; }
-    pub fn start(&mut self, v: bool) { self.buttons[3] = v; }
-    pub fn up(&mut self, v: bool) { self.buttons[4] = v; }
-    pub fn down(&mut self, v: bool) { self.buttons[5] = v; }
-    pub fn left(&mut self, v: bool) { self.buttons[6] = v; }
-    pub fn right(&mut self, v: bool) { self.buttons[7] = v; }
+    pub fn set_button(&mut self, button: Button, v: bool) {
+        self.pressed[button as usize] = v;
+        self.update_buttons();
+    }
+
+    // Holds or lets go of the turbo version of a button. The button is pressed
+    // straight away, and then toggles every `turbo_rate' frames.
+    pub fn set_turbo(&mut self, button: Button, held: bool) {
+        if held && !self.turbo.iter().any(|&held| held) {
+            self.turbo_frames = 0;
+        }
+
+        self.turbo[button as usize] = held;
+        self.update_buttons();
+    }
+
+    pub fn set_turbo_rate(&mut self, frames: u32) {
+        self.turbo_rate = frames.max(1);
+    }
+
+    // Called at the start of every frame
+    pub fn next_frame(&mut self) {
+        if self.turbo.iter().any(|&held| held) {
+            self.update_buttons();
+            self.turbo_frames += 1;
+        }
+    }
+
+    fn update_buttons(&mut self) {
+        let turbo_on = (self.turbo_frames / self.turbo_rate) % 2 == 0;
+
+        for i in 0 .. 8 {
+            self.buttons[i] = self.pressed[i] || (self.turbo[i] && turbo_on);
+        }
+    }
+
+    // All of the buttons at once, one bit each, in the order they're read out
+    pub fn buttons(&self) -> u8 {
+        self.buttons.iter().rev().fold(0, |bits, &pressed| bits << 1 | pressed as u8)
+    }
+
+    // Overrides what the game sees, until the next time a button is pressed
+    // or released
+    pub fn set_buttons(&mut self, bits: u8) {
+        for (i, pressed) in self.buttons.iter_mut().enumerate() {
+            *pressed = bits & (1 << i) != 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(controller: &mut Controller, n: usize) -> Vec<u8> {
+        (0 .. n).map(|_| {
+            controller.next_frame();
+            controller.buttons()
+        }).collect()
+    }
+
+    #[test]
+    fn test_turbo() {
+        let mut controller = Controller::new_controller();
+        controller.set_turbo(Button::A, true);
+        assert_eq!(controller.buttons(), 0x01);
+
+        assert_eq!(frames(&mut controller, 6), [0x01, 0x01, 0x00, 0x00, 0x01, 0x01]);
+
+        // Pressing the button itself wins over turbo
+        controller.set_button(Button::A, true);
+        assert_eq!(frames(&mut controller, 2), [0x01, 0x01]);
+        controller.set_button(Button::A, false);
+
+        controller.set_turbo(Button::A, false);
+        controller.set_turbo_rate(1);
+        controller.set_turbo(Button::B, true);
+        assert_eq!(frames(&mut controller, 4), [0x02, 0x00, 0x02, 0x00]);
+    }
 }
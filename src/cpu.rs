@@ -1,16 +1,23 @@
 mod addr;
+mod disasm;
 mod inst;
 mod opcode;
+mod trace;
+
+pub use crate::cpu::disasm::disassemble_rom_bank;
+pub use crate::cpu::trace::{TraceFormat, TraceOptions, Tracer};
 
 use std::env;
 use std::process;
+use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
 
 use crate::cpu::addr::AddressingMode;
 use crate::cpu::opcode::{Opcode, OPCODES};
-use crate::mem::Memory;
+use crate::debugger::{Access, Debugger, Space};
+use crate::mem::{Memory, NESMemory};
 use crate::serde;
+use crate::symbols::Symbols;
 
 const STACK_INIT: u8 = 0xfd;
 const PPU_DOTS_PER_SCANLINE: u64 = 341;
@@ -25,6 +32,11 @@ lazy_static!{
         Ok(val) => val != "" && val != "0",
         Err(_)  => false,
     };
+
+    static ref NES_CPU_CYCLE_ACCURATE: bool = match env::var("NES_CPU_CYCLE_ACCURATE") {
+        Ok(val) => val != "" && val != "0",
+        Err(_)  => false,
+    };
 }
 
 enum Interrupt {
@@ -33,7 +45,8 @@ enum Interrupt {
 }
 
 pub struct CPU {
-    mem: Box<dyn Memory>,
+    // The system bus, which owns everything else
+    pub mem: NESMemory,
 
     // Main registers
     pub a: u8,  // Accumulator
@@ -54,7 +67,7 @@ pub struct CPU {
     pub pc: u16,
 
     // Stack pointer
-    sp: u8,
+    pub sp: u8,
 
     // Interrupt to execute on the next CPU step
     interrupt: Option<Interrupt>,
@@ -64,14 +77,51 @@ pub struct CPU {
 
     // Total number of cycles executed
     cycles: u64,
+
+    // When set, every bus access runs the rest of the system for a cycle as it
+    // happens, and the dummy reads and writes that the real 6502 performs are
+    // carried out too. Otherwise the rest of the system is caught up once the
+    // whole instruction has executed.
+    pub cycle_accurate: bool,
+
+    // The number of cycles that have already been run on the bus during the
+    // current step
+    ticks: u64,
+
+    // When attached, the debugger is told about every bus access, and can
+    // stop the CPU before any instruction
+    pub debugger: Option<Box<Debugger>>,
+
+    // When attached, every instruction is logged to a file
+    pub tracer: Option<Box<Tracer>>,
+
+    // Labels for the debugger, disassembly and traces to show
+    pub symbols: Symbols,
 }
 
 impl Memory for CPU {
     fn read(&mut self, addr: u16) -> u8 {
-        self.mem.read(addr)
+        self.tick();
+
+        if self.debugger.is_none() {
+            return self.mem.read(addr);
+        }
+
+        // Reading PPUDATA moves the VRAM address on, so it's taken first
+        let vram_addr = self.mem.ppu.vram_address();
+        let val = self.mem.read(addr);
+        self.watch(addr, vram_addr, val, Access::Read);
+        val
     }
 
     fn write(&mut self, addr: u16, val: u8) {
+        self.tick();
+
+        if self.debugger.is_some() {
+            let vram_addr = self.mem.ppu.vram_address();
+            self.watch(addr, vram_addr, val, Access::Write);
+        }
+
         if addr == 0x4014 {
             self.dma(val);
         } else {
@@ -79,7 +129,7 @@ impl Memory for CPU {
         }
     }
 
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         serde::encode_u8(output, self.a)?;
         serde::encode_u8(output, self.x)?;
         serde::encode_u8(output, self.y)?;
@@ -102,10 +152,12 @@ impl Memory for CPU {
             None        => { serde::encode_u64(output, 0)? }
         };
 
-        self.mem.save(output)
+        serde::encode_u64(output, self.cycles)?;
+
+        Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.a = serde::decode_u8(input)?;
         self.x = serde::decode_u8(input)?;
         self.y = serde::decode_u8(input)?;
@@ -124,14 +176,16 @@ impl Memory for CPU {
             i => Some(i),
         };
 
-        self.mem.load(input)
+        self.cycles = serde::decode_u64(input)?;
+
+        Ok(())
     }
 }
 
 impl CPU {
-    pub fn new_cpu(mem: Box<dyn Memory>) -> Self {
+    pub fn new_cpu(mem: NESMemory) -> Self {
         Self {
-            mem: mem,
+            mem,
 
             a: 0,
             x: 0,
@@ -154,12 +208,19 @@ impl CPU {
 
             stall: None,
             cycles: 0,
+
+            cycle_accurate: *NES_CPU_CYCLE_ACCURATE,
+            ticks: 0,
+
+            debugger: None,
+            tracer: None,
+            symbols: Symbols::new_symbols(),
         }
     }
 
     pub fn reset(&mut self) {
-        let lo = self.read(0xFFFC) as u16;
-        let hi = self.read(0xFFFD) as u16;
+        let lo = self.peek(0xFFFC) as u16;
+        let hi = self.peek(0xFFFD) as u16;
         let addr = (hi << 8) | lo;
         self.pc = if *NES_CPU_NESTEST { 0xc000 } else { addr };
         debug!("PC: 0x{:04X}", self.pc);
@@ -174,25 +235,102 @@ impl CPU {
         self.interrupt = None;
         self.stall = None;
         self.cycles = 0;
+        self.ticks = 0;
+    }
+
+    // Reads from the bus without running the rest of the system, or any of the
+    // side effects of reading I/O registers, for things outside of the CPU,
+    // like debugging output and test ROM detection.
+    pub fn peek(&mut self, addr: u16) -> u8 {
+        self.mem.peek(addr)
+    }
+
+    // In cycle accurate mode, runs the rest of the system for the cycle that a
+    // bus access is about to take.
+    fn tick(&mut self) {
+        if self.cycle_accurate {
+            self.mem.tick();
+            self.ticks += 1;
+        }
+    }
+
+    // The 6502 accesses the bus on every cycle, even when it has nothing
+    // useful to do, and those accesses can have side effects, like clearing
+    // flags on PPU registers or clocking mapper shift registers.
+    fn dummy_read(&mut self, addr: u16) {
+        if self.cycle_accurate {
+            let _ = self.read(addr);
+        }
+    }
+
+    fn dummy_write(&mut self, addr: u16, val: u8) {
+        if self.cycle_accurate {
+            self.write(addr, val);
+        }
+    }
+
+    // Tells the debugger about a bus access, and about the access to the PPU's
+    // address space that it makes if it's to PPUDATA
+    fn watch(&mut self, addr: u16, vram_addr: u16, val: u8, access: Access) {
+        if let Some(debugger) = &mut self.debugger {
+            debugger.access(Space::CPU, addr, val, access);
+
+            if (0x2000 ..= 0x3fff).contains(&addr) && addr & 0x07 == 0x07 {
+                debugger.access(Space::PPU, vram_addr, val, access);
+            }
+        }
+    }
+
+    // Tells the debugger that a subroutine has been called, or an interrupt
+    // taken, from `from', with the stack where it was before, so that it can
+    // keep track of the call stack
+    fn debug_call(&mut self, from: u16, sp: u8, interrupt: Option<&'static str>) {
+        if let Some(debugger) = &mut self.debugger {
+            let from_offset = self.mem.mapper.prg_rom_offset(from);
+            let to_offset = self.mem.mapper.prg_rom_offset(self.pc);
+            debugger.call((from, from_offset), (self.pc, to_offset), sp, interrupt);
+        }
+    }
+
+    fn debug_return(&mut self) {
+        if let Some(debugger) = &mut self.debugger {
+            debugger.ret(self.sp);
+        }
     }
 
     fn dma(&mut self, val: u8) {
         let addr_base = (val as u16) << 8;
 
+        let stall = if self.cycles % 2 == 1 { 514 } else { 513 };
+
+        if self.cycle_accurate {
+            // The CPU is halted for a cycle or two while the DMA unit gets
+            // itself aligned, and then every byte takes a read and a write.
+            for _ in 0 .. stall - 512 {
+                self.tick();
+            }
+
+            for lo_nyb in 0x00 ..= 0xff {
+                let addr = addr_base | lo_nyb;
+                let val = self.read(addr);
+                self.tick();
+                self.mem.write(0x2004, val);
+            }
+
+            self.cycles += stall;
+            return;
+        }
+
         for lo_nyb in 0x00 ..= 0xff {
             let addr = addr_base | lo_nyb;
             let val = self.read(addr);
             self.mem.write(0x2004, val);
         }
 
-        if self.cycles % 2 == 1 {
-            self.stall(514);
-        } else {
-            self.stall(513);
-        }
+        self.stall(stall);
     }
 
-    fn flags(&self) -> u8 {
+    pub fn flags(&self) -> u8 {
            (self.c as u8)
         | ((self.z as u8) << 1)
         | ((self.i as u8) << 2)
@@ -203,7 +341,7 @@ impl CPU {
         | ((self.s as u8) << 7)
     }
 
-    fn set_flags(&mut self, val: u8) {
+    pub fn set_flags(&mut self, val: u8) {
         self.c = val & 0x01 == 1;
         self.z = (val >> 1 & 0x01) == 1;
         self.i = (val >> 2 & 0x01) == 1;
@@ -214,8 +352,11 @@ impl CPU {
         self.s = (val >> 7 & 0x01) == 1;
     }
 
-    fn debug(&mut self, op: &Opcode) {
-        let Opcode(ref inst, ref addr_mode, _, _) = *op;
+    // The instruction at PC and the state of the registers, in the same format
+    // as nestest's log
+    pub fn trace_line(&mut self) -> String {
+        let opcode = self.peek(self.pc);
+        let Opcode(ref inst, ref addr_mode, _, _) = OPCODES[opcode as usize];
 
         let raw_bytes = addr_mode.get_bytes(self);
 
@@ -226,16 +367,16 @@ impl CPU {
 
         let ppu_dots = self.cycles * 3 % PPU_DOTS_PER_SCANLINE;
 
-        println!("{:04X}  {:8}  {:32?} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{:-3}",
-                 self.pc,
-                 bytes,
-                 inst,
-                 self.a,
-                 self.x,
-                 self.y,
-                 self.flags(),
-                 self.sp,
-                 ppu_dots);
+        format!("{:04X}  {:8}  {:32?} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{:-3}",
+                self.pc,
+                bytes,
+                inst,
+                self.a,
+                self.x,
+                self.y,
+                self.flags(),
+                self.sp,
+                ppu_dots)
     }
 
     pub fn trigger_nmi(&mut self) {
@@ -250,6 +391,8 @@ impl CPU {
 
     fn nmi(&mut self) {
         let pc = self.pc;
+        self.dummy_read(pc);
+        self.dummy_read(pc);
         self.stack_push16(pc);
         self.php();
 
@@ -265,6 +408,8 @@ impl CPU {
 
     fn irq(&mut self) {
         let pc = self.pc;
+        self.dummy_read(pc);
+        self.dummy_read(pc);
         self.stack_push16(pc);
         self.php();
 
@@ -319,13 +464,22 @@ impl CPU {
 
     fn add_branch_cycles(&mut self, pc: u16, addr: u16) {
         self.cycles += 1;
+        self.dummy_read(pc);
 
         // It costs an extra cycle to branch to a different page.
         if (pc & 0xff00) != (addr & 0xff00) {
             self.cycles += 1;
+            self.dummy_read((pc & 0xff00) | (addr & 0x00ff));
         }
     }
 
+    // The dummy read the 6502 performs while it increments the stack pointer
+    // before pulling from the stack.
+    fn stack_dummy_read(&mut self) {
+        let addr = 0x0100 | (self.sp as u16);
+        self.dummy_read(addr);
+    }
+
     pub fn stall(&mut self, extra_steps: u64) {
         if let Some(stall_cycles) = self.stall {
             self.stall = Some(stall_cycles + extra_steps);
@@ -334,7 +488,35 @@ impl CPU {
         }
     }
 
+    // Executes a single instruction, and then runs the rest of the system for
+    // the number of cycles that the instruction took. In cycle accurate mode,
+    // most of those cycles will already have been run by the bus accesses.
     pub fn step(&mut self) -> u64 {
+        let cycles = self.execute();
+
+        for _ in self.ticks .. cycles {
+            self.mem.tick();
+        }
+        self.ticks = 0;
+
+        let stall = self.mem.take_stall();
+        if stall > 0 {
+            self.stall(stall);
+        }
+
+        // An NMI takes priority over an IRQ, so it's checked last
+        if self.mem.take_irq() {
+            self.trigger_irq();
+        }
+
+        if self.mem.take_nmi() {
+            self.trigger_nmi();
+        }
+
+        cycles
+    }
+
+    fn execute(&mut self) -> u64 {
         // If a DMA was executed before this step, we need to stall a bunch of
         // cycles before we can do anything else, because DMA costs cycles.
         if let Some(stall) = self.stall {
@@ -349,28 +531,51 @@ impl CPU {
         let start_cycles = self.cycles;
 
         // Process pending interrupts.
-        match self.interrupt {
-            Some(Interrupt::NMI) => { self.nmi() },
-            Some(Interrupt::IRQ) => { self.irq() },
-            None => { }
+        if let Some(interrupt) = self.interrupt.take() {
+            let (pc, sp) = (self.pc, self.sp);
+
+            let name = match interrupt {
+                Interrupt::NMI => { self.nmi(); "NMI" },
+                Interrupt::IRQ => { self.irq(); "IRQ" },
+            };
+
+            self.trace_interrupt(&interrupt);
+            self.debug_call(pc, sp, Some(name));
         }
-        self.interrupt = None;
 
-        let opcode = self.read(self.pc);
+        // The debugger can stop the CPU before the instruction, in which case
+        // it doesn't go any further until the debugger lets it. It can also
+        // start and stop tracing.
+        if let Some(debugger) = &mut self.debugger {
+            let rom_offset = self.mem.mapper.prg_rom_offset(self.pc);
+            if debugger.check(self.pc, rom_offset, self.sp, self.mem.ppu.position().0) {
+                return self.cycles - start_cycles;
+            }
 
-        let op = &OPCODES[opcode as usize];
+            if let (Some(on), Some(tracer)) = (debugger.take_trace_toggle(), &mut self.tracer) {
+                tracer.enabled = on;
+            }
+        }
+
+        self.trace_instruction();
 
         if *NES_CPU_DEBUG {
-            self.debug(&op);
+            println!("{}", self.trace_line());
         }
 
+        let opcode = self.read(self.pc);
+
+        let op = &OPCODES[opcode as usize];
+
         let &Opcode(ref inst, ref addr_mode, cycles, extra_cycles) = op;
 
         let bytes = addr_mode.n_bytes();
         self.pc += bytes as u16;
         self.cycles += cycles as u64;
 
-        let (addr, page_crossed) = addr_mode.get_data(self);
+        // Instructions that aren't penalised for crossing a page are the ones
+        // that write, and they always take the extra cycle.
+        let (addr, page_crossed) = addr_mode.get_data(self, extra_cycles == 0);
         inst.run(self, addr, addr_mode);
 
         if page_crossed {
@@ -420,7 +625,10 @@ impl CPU {
 
         match *addr_mode {
             AddressingMode::Accumulator => { self.a = n; },
-            _ => { self.write(addr, n); }
+            _ => {
+                self.dummy_write(addr, val);
+                self.write(addr, n);
+            }
         };
 
         self.update_sz(n);
@@ -483,6 +691,7 @@ impl CPU {
     }
 
     pub fn brk(&mut self) {
+        let (from, sp) = (self.pc - 1, self.sp);
         let pc = self.pc + 1;
         self.stack_push16(pc);
 
@@ -497,6 +706,8 @@ impl CPU {
         let hi = self.read(0xFFFF) as u16;
         let pc = (hi << 8) | lo;
         self.pc = pc;
+
+        self.debug_call(from, sp, Some("BRK"));
     }
 
     pub fn bvc(&mut self, addr: u16) {
@@ -556,6 +767,7 @@ impl CPU {
         let val = self.read(addr);
         let n = val.wrapping_sub(1);
         self.update_sz(n);
+        self.dummy_write(addr, val);
         self.write(addr, n);
     }
 
@@ -581,6 +793,7 @@ impl CPU {
     pub fn inc(&mut self, addr: u16) {
         let val = self.read(addr);
         let n = val.wrapping_add(1);
+        self.dummy_write(addr, val);
         self.write(addr, n);
         self.update_sz(n);
     }
@@ -602,9 +815,13 @@ impl CPU {
     }
 
     pub fn jsr(&mut self, addr: u16) {
+        let sp = self.sp;
         let retaddr = self.pc - 1;
+        self.stack_dummy_read();
         self.stack_push16(retaddr);
         self.pc = addr;
+
+        self.debug_call(retaddr - 2, sp, None);
     }
 
     pub fn lda(&mut self, addr: u16) {
@@ -637,11 +854,20 @@ impl CPU {
 
         match *addr_mode {
             AddressingMode::Accumulator => { self.a = n; },
-            _ => { self.write(addr, n); }
+            _ => {
+                self.dummy_write(addr, val);
+                self.write(addr, n);
+            }
         };
     }
 
-    pub fn nop(&self) { }
+    pub fn nop(&mut self, addr: u16, addr_mode: &AddressingMode) {
+        // The unofficial NOPs with operands still read them
+        match *addr_mode {
+            AddressingMode::Implied => { },
+            _ => { self.dummy_read(addr) },
+        }
+    }
 
     pub fn ora(&mut self, addr: u16) {
         let val = self.read(addr);
@@ -665,12 +891,14 @@ impl CPU {
     }
 
     pub fn pla(&mut self) {
+        self.stack_dummy_read();
         let rv = self.stack_pop8();
         self.a = rv;
         self.update_sz(rv);
     }
 
     pub fn plp(&mut self) {
+        self.stack_dummy_read();
         let p = self.stack_pop8() & 0xef | 0x20;
         self.set_flags(p);
     }
@@ -687,7 +915,10 @@ impl CPU {
 
         match *addr_mode {
             AddressingMode::Accumulator => { self.a = n; },
-            _ => { self.write(addr, n); }
+            _ => {
+                self.dummy_write(addr, val);
+                self.write(addr, n);
+            }
         };
     }
 
@@ -703,21 +934,31 @@ impl CPU {
 
         match *addr_mode {
             AddressingMode::Accumulator => { self.a = n; },
-            _ => { self.write(addr, n); }
+            _ => {
+                self.dummy_write(addr, val);
+                self.write(addr, n);
+            }
         };
     }
 
     pub fn rti(&mut self) {
+        self.stack_dummy_read();
         let flags = self.stack_pop8() & 0xef | 0x20;
         self.set_flags(flags);
 
         let retaddr = self.stack_pop16();
         self.pc = retaddr;
+
+        self.debug_return();
     }
 
     pub fn rts(&mut self) {
+        self.stack_dummy_read();
         let retaddr = self.stack_pop16();
+        self.dummy_read(retaddr);
         self.pc = retaddr + 1;
+
+        self.debug_return();
     }
 
     pub fn sbc(&mut self, addr: u16) {
@@ -828,6 +1069,7 @@ impl CPU {
         let val = self.read(addr);
         let n = val.wrapping_sub(1);
         self.update_sz(n);
+        self.dummy_write(addr, val);
         self.write(addr, n);
 
         // Copied from cmp
@@ -840,6 +1082,7 @@ impl CPU {
         // Copied from inc
         let val = self.read(addr);
         let n = val.wrapping_add(1);
+        self.dummy_write(addr, val);
         self.write(addr, n);
         self.update_sz(n);
 
@@ -864,7 +1107,10 @@ impl CPU {
 
         match *addr_mode {
             AddressingMode::Accumulator => { self.a = n; },
-            _ => { self.write(addr, n); }
+            _ => {
+                self.dummy_write(addr, val);
+                self.write(addr, n);
+            }
         };
 
         self.update_sz(n);
@@ -886,7 +1132,10 @@ impl CPU {
 
         match *addr_mode {
             AddressingMode::Accumulator => { self.a = n; },
-            _ => { self.write(addr, n); }
+            _ => {
+                self.dummy_write(addr, val);
+                self.write(addr, n);
+            }
         };
 
         // Copied from and
@@ -905,7 +1154,10 @@ impl CPU {
 
         match *addr_mode {
             AddressingMode::Accumulator => { self.a = n; },
-            _ => { self.write(addr, n); }
+            _ => {
+                self.dummy_write(addr, val);
+                self.write(addr, n);
+            }
         };
 
         // Copied from eor
@@ -925,7 +1177,10 @@ impl CPU {
 
         match *addr_mode {
             AddressingMode::Accumulator => { self.a = n; },
-            _ => { self.write(addr, n); }
+            _ => {
+                self.dummy_write(addr, val);
+                self.write(addr, n);
+            }
         };
 
         // Copied from adc
@@ -943,18 +1198,36 @@ impl CPU {
     }
 }
 
+// A CPU for tests, reset to run `program' from $8000, in 32KB of PRG-ROM that's
+// otherwise all NOPs
+#[cfg(test)]
+pub fn new_test_cpu(program: &[u8]) -> CPU {
+    let mut rom = vec![0xea; 0x8000];
+    rom[.. program.len()].copy_from_slice(program);
+    rom[0x7ffc] = 0x00;
+    rom[0x7ffd] = 0x80;
+
+    let mut cpu = new_test_cpu_with_rom(rom);
+    cpu.reset();
+    cpu
+}
+
+// A CPU for tests with the whole of PRG-ROM given, which isn't reset
+#[cfg(test)]
+pub fn new_test_cpu_with_rom(rom: Vec<u8>) -> CPU {
+    let mapper = crate::mapper::Mapper0::new_mapper(rom, vec![0; 0x2000], 0);
+    CPU::new_cpu(NESMemory::new_nes_mem(Box::new(mapper)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::ppu::PPU;
-    use crate::controller::Controller;
+    use crate::cpu::inst::Instruction;
+    use crate::mapper::Mapper1;
 
     #[test]
     fn test_stack_pop_empty() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mem = NESMemory::new_nes_mem(ppu, ctrl);
-        let mut cpu = CPU::new_cpu(mem);
+        let mut cpu = new_test_cpu_with_rom(vec![0; 0x8000]);
         let _ = cpu.stack_pop8();
         assert_eq!(cpu.sp, STACK_INIT + 1);
 
@@ -968,10 +1241,7 @@ mod tests {
 
     #[test]
     fn test_stack_push_full() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mem = NESMemory::new_nes_mem(ppu, ctrl);
-        let mut cpu = CPU::new_cpu(mem);
+        let mut cpu = new_test_cpu_with_rom(vec![0; 0x8000]);
 
         for _ in 0 .. STACK_INIT {
             cpu.stack_push8(0xff);
@@ -986,19 +1256,16 @@ mod tests {
 
     #[test]
     fn test_stack() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mem = NESMemory::new_nes_mem(ppu, ctrl);
-        let mut cpu = CPU::new_cpu(mem);
+        let mut cpu = new_test_cpu_with_rom(vec![0; 0x8000]);
 
         cpu.stack_push8(0xff);
         assert_eq!(cpu.sp, 0xfc);
-        assert_eq!(cpu.mem.ram[0x0100 + (cpu.sp as usize) + 1], 0xff);
+        assert_eq!(cpu.mem.read(0x0100 + (cpu.sp as u16) + 1), 0xff);
 
         cpu.stack_push16(0xdead);
         assert_eq!(cpu.sp, 0xfa);
-        assert_eq!(cpu.mem.ram[0x100 + (cpu.sp as usize) + 1], 0xad);
-        assert_eq!(cpu.mem.ram[0x100 + (cpu.sp as usize) + 2], 0xde);
+        assert_eq!(cpu.mem.read(0x100 + (cpu.sp as u16) + 1), 0xad);
+        assert_eq!(cpu.mem.read(0x100 + (cpu.sp as u16) + 2), 0xde);
 
         let rv = cpu.stack_pop16();
         assert_eq!(cpu.sp, 0xfc);
@@ -1011,10 +1278,7 @@ mod tests {
 
     #[test]
     fn test_flags() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mem = NESMemory::new_nes_mem(ppu, ctrl);
-        let mut cpu = CPU::new_cpu(mem);
+        let mut cpu = new_test_cpu_with_rom(vec![0; 0x8000]);
 
         assert_eq!(cpu.flags(), 0x00);
 
@@ -1030,17 +1294,109 @@ mod tests {
 
     #[test]
     fn test_nmi() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mem = NESMemory::new_nes_mem(ppu, ctrl);
-        let mut cpu = CPU::new_cpu(mem);
-
-        let mut rom = vec![0; 0xffff];
-        rom[0xfffa] = 0xad;
-        rom[0xfffb] = 0xde;
-        cpu.mem.load_rom(&rom);
+        let mut rom = vec![0; 0x8000];
+        rom[0x7ffa] = 0xad;
+        rom[0x7ffb] = 0xde;
+        let mut cpu = new_test_cpu_with_rom(rom);
+
         cpu.nmi();
         assert_eq!(cpu.pc, 0xdead);
         assert!(cpu.i);
     }
+
+    #[test]
+    fn test_cycle_accurate_bus_accesses() {
+        // Every cycle of every instruction should be a bus access, both with
+        // and without page crossings.
+        for &(index, operand) in &[(0x00, 0x00), (0xff, 0xff)] {
+            for opcode in 0 ..= 0xff {
+                // Skip the opcodes that aren't implemented, or halt the CPU
+                let Opcode(ref inst, ref addr_mode, _, _) = OPCODES[opcode];
+                match (inst, addr_mode) {
+                    (Instruction::JAM, _) | (_, AddressingMode::None) => continue,
+                    _ => { },
+                }
+
+                let mut rom = vec![operand; 0x8000];
+                rom[0] = opcode as u8;
+                rom[0x7ffe] = 0x00;
+                rom[0x7fff] = 0x80;
+
+                let mut cpu = new_test_cpu_with_rom(rom);
+                cpu.cycle_accurate = true;
+                cpu.pc = 0x8000;
+                cpu.x = index;
+                cpu.y = index;
+
+                let cycles = cpu.execute();
+                assert_eq!(cpu.ticks, cycles, "opcode 0x{:02X}", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn test_cycle_accurate_rmw_double_write() {
+        // INC $0010
+        let mut rom = vec![0; 0x8000];
+        rom[0] = 0xee;
+        rom[1] = 0x10;
+        rom[2] = 0x00;
+
+        let mut cpu = new_test_cpu_with_rom(rom);
+        cpu.cycle_accurate = true;
+        cpu.pc = 0x8000;
+        cpu.mem.write(0x0010, 0x41);
+
+        assert_eq!(cpu.step(), 6);
+        assert_eq!(cpu.peek(0x0010), 0x42);
+    }
+
+    #[test]
+    fn test_cycle_accurate_mmc1_double_write() {
+        //   C000  INC $E000
+        //   C003  LDA #$01
+        //   C005  STA $E000
+        //   ...
+        //
+        // The first byte of each 16KB bank is its number
+        let mut rom = vec![0xea; 8 * 0x4000];
+        for bank in 0 .. 8 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom[0x1c000 .. 0x1c011].copy_from_slice(&[
+            0xee, 0x00, 0xe0, 0xa9, 0x01,
+            0x8d, 0x00, 0xe0, 0x8d, 0x00, 0xe0, 0x8d, 0x00, 0xe0, 0x8d, 0x00, 0xe0,
+        ]);
+        rom[0x1e000] = 0x00;
+
+        let mapper = Mapper1::new_mapper(rom, vec![0; 0x2000], 0);
+        let mut cpu = CPU::new_cpu(NESMemory::new_nes_mem(Box::new(mapper)));
+        cpu.cycle_accurate = true;
+        cpu.pc = 0xc000;
+
+        // INC writes $00 and then $01 on consecutive cycles, and MMC1 only
+        // takes the first, so three more writes don't fill the shift register
+        for _ in 0 .. 5 {
+            cpu.step();
+        }
+        assert_eq!(cpu.peek(0x8000), 0);
+
+        // The fifth write loads the PRG bank with %11110, which is bank 6 of 8
+        cpu.step();
+        assert_eq!(cpu.peek(0x8000), 6);
+    }
+
+    #[test]
+    fn test_peek_registers() {
+        let mut cpu = new_test_cpu(&[]);
+        while cpu.mem.ppu.position().0 != 242 {
+            cpu.step();
+        }
+
+        // Peeking PPUSTATUS sees vblank, but doesn't clear it
+        assert_eq!(cpu.peek(0x2002) & 0x80, 0x80);
+        assert_eq!(cpu.peek(0x2002) & 0x80, 0x80);
+        assert_eq!(cpu.mem.read(0x2002) & 0x80, 0x80);
+        assert_eq!(cpu.mem.read(0x2002) & 0x80, 0);
+    }
 }
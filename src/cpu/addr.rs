@@ -23,6 +23,14 @@ fn pages_differ(addr_a: u16, addr_b: u16) -> bool {
     (addr_a & 0xff00) != (addr_b & 0xff00)
 }
 
+// Indexed addressing adds the index to the low byte first, and reads from that
+// address while the high byte is fixed up, if it needs to be.
+fn fix_up(cpu: &mut CPU, addr: u16, n_addr: u16, always: bool) {
+    if always || pages_differ(addr, n_addr) {
+        cpu.dummy_read((addr & 0xff00) | (n_addr & 0x00ff));
+    }
+}
+
 impl AddressingMode {
     pub fn n_bytes(&self) -> usize {
         match *self {
@@ -48,10 +56,12 @@ impl AddressingMode {
 
     pub fn get_bytes(&self, cpu: &mut CPU) -> Vec<u8> {
         let n_bytes = self.n_bytes() as u16;
-        (0 .. n_bytes).map(|n| cpu.read(cpu.pc + n)).collect::<Vec<_>>()
+        (0 .. n_bytes).map(|n| cpu.peek(cpu.pc + n)).collect::<Vec<_>>()
     }
 
-    pub fn get_data(&self, cpu: &mut CPU) -> (u16, bool) {
+    // When `always_fix_up' is set, the dummy read from the partially
+    // calculated address of indexed modes happens even if no page is crossed.
+    pub fn get_data(&self, cpu: &mut CPU, always_fix_up: bool) -> (u16, bool) {
         // At this point, cpu.pc points to the next instruction.
         let pc = cpu.pc - self.n_bytes() as u16;
 
@@ -66,8 +76,12 @@ impl AddressingMode {
                 let addr = (hi << 8) | lo;
                 (addr, false)
             },
-            AddressingMode::Implied => (0, false),
-            AddressingMode::Accumulator => (0, false),
+            AddressingMode::Implied
+            | AddressingMode::Accumulator => {
+                // The byte after the opcode is read and thrown away
+                cpu.dummy_read(cpu.pc);
+                (0, false)
+            },
             AddressingMode::ZeroPageIndexed => {
                 let addr = cpu.read(pc + 1) as u16;
                 (addr, false)
@@ -87,6 +101,7 @@ impl AddressingMode {
                 let hi = cpu.read(pc + 2) as u16;
                 let addr = (hi << 8) | lo;
                 let n_addr = addr.wrapping_add(cpu.x as u16);
+                fix_up(cpu, addr, n_addr, always_fix_up);
                 (n_addr, pages_differ(addr, n_addr))
             },
             AddressingMode::AbsoluteY => {
@@ -94,6 +109,7 @@ impl AddressingMode {
                 let hi = cpu.read(pc + 2) as u16;
                 let addr = (hi << 8) | lo;
                 let n_addr = addr.wrapping_add(cpu.y as u16);
+                fix_up(cpu, addr, n_addr, always_fix_up);
                 (n_addr, pages_differ(addr, n_addr))
             },
             AddressingMode::Indirect => {
@@ -114,15 +130,20 @@ impl AddressingMode {
                 (addr, false)
             }
             AddressingMode::ZeroPageX => {
-                let addr = cpu.read(pc + 1).wrapping_add(cpu.x) as u16;
+                let base = cpu.read(pc + 1);
+                cpu.dummy_read(base as u16);
+                let addr = base.wrapping_add(cpu.x) as u16;
                 (addr, false)
             },
             AddressingMode::ZeroPageY => {
-                let addr = cpu.read(pc + 1).wrapping_add(cpu.y) as u16;
+                let base = cpu.read(pc + 1);
+                cpu.dummy_read(base as u16);
+                let addr = base.wrapping_add(cpu.y) as u16;
                 (addr, false)
             },
             AddressingMode::IndexedIndirect => {
                 let lo = cpu.read(pc + 1);
+                cpu.dummy_read(lo as u16);
                 let addr = lo.wrapping_add(cpu.x) as u16;
 
                 let lo = cpu.read(addr) as u16;
@@ -149,6 +170,7 @@ impl AddressingMode {
 
                 let addr = (hi << 8) | lo;
                 let n_addr = addr.wrapping_add(cpu.y as u16);
+                fix_up(cpu, addr, n_addr, always_fix_up);
 
                 (n_addr, pages_differ(addr, n_addr))
             },
@@ -0,0 +1,299 @@
+// 6502 disassembler
+//
+// Instructions are decoded with the same opcode table that the CPU executes
+// from, so everything that the CPU can run, including the unofficial opcodes,
+// can be disassembled. Bytes that aren't an instruction at all are shown as
+// data. Operands that are addresses are shown as labels, when there are any.
+
+use std::fmt;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::io;
+
+use crate::cpu::CPU;
+use crate::cpu::addr::AddressingMode;
+use crate::cpu::inst::Instruction;
+use crate::cpu::opcode::{Opcode, OPCODES};
+use crate::symbols::Symbols;
+
+const INES_MAGIC: [u8; 4] = [0x4e, 0x45, 0x53, 0x1a];
+const PRG_BANK_SIZE: usize = 0x4000;
+
+pub struct Disassembly {
+    pub address: u16,
+
+    // Where in PRG-ROM the instruction is, if it's in PRG-ROM, and its label
+    pub rom_offset: Option<usize>,
+    pub label: Option<String>,
+
+    pub bytes: Vec<u8>,
+    pub text: String,
+}
+
+// In the same layout as a nestest log line, without the registers
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes = self.bytes.iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        write!(f, "{:04X}  {:8}  {}", self.address, bytes, self.text)
+    }
+}
+
+// Decodes a single instruction at `address', reading its bytes with `read',
+// and looking up labels for its operand with `label'
+pub fn disassemble_one(read: &mut dyn FnMut(u16) -> u8,
+                       label: &dyn Fn(u16) -> Option<String>,
+                       address: u16) -> Disassembly
+{
+    let opcode = read(address);
+    let Opcode(ref inst, ref addr_mode, _, _) = OPCODES[opcode as usize];
+
+    if let (Instruction::None, _) | (_, AddressingMode::None) = (inst, addr_mode) {
+        return Disassembly {
+            address,
+            rom_offset: None,
+            label: None,
+            bytes: vec![opcode],
+            text: format!(".db ${:02X}", opcode),
+        };
+    }
+
+    let bytes: Vec<u8> = (0 .. addr_mode.n_bytes() as u16)
+        .map(|n| if n == 0 { opcode } else { read(address.wrapping_add(n)) })
+        .collect();
+
+    let byte = bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or(0)]);
+
+    let zero_page = label(byte as u16).unwrap_or_else(|| format!("${:02X}", byte));
+    let absolute = label(word).unwrap_or_else(|| format!("${:04X}", word));
+
+    let operand = match *addr_mode {
+        AddressingMode::Implied         => String::new(),
+        AddressingMode::Accumulator     => "A".to_string(),
+        AddressingMode::Immediate       => format!("#${:02X}", byte),
+        AddressingMode::ZeroPageIndexed => zero_page,
+        AddressingMode::ZeroPageX       => format!("{},X", zero_page),
+        AddressingMode::ZeroPageY       => format!("{},Y", zero_page),
+        AddressingMode::Absolute        => absolute,
+        AddressingMode::AbsoluteX       => format!("{},X", absolute),
+        AddressingMode::AbsoluteY       => format!("{},Y", absolute),
+        AddressingMode::Indirect        => format!("({})", absolute),
+        AddressingMode::IndexedIndirect => format!("({},X)", zero_page),
+        AddressingMode::IndirectIndexed => format!("({}),Y", zero_page),
+        AddressingMode::Relative        => {
+            let target = branch_target(address, byte);
+            label(target).unwrap_or_else(|| format!("${:04X}", target))
+        },
+        AddressingMode::None            => unreachable!(),
+    };
+
+    let text = if operand.is_empty() {
+        format!("{:?}", inst)
+    } else {
+        format!("{:?} {}", inst, operand)
+    };
+
+    Disassembly {
+        address,
+        rom_offset: None,
+        label: None,
+        bytes,
+        text,
+    }
+}
+
+// Decodes every instruction that starts from `start' to `end', inclusive
+pub fn disassemble(read: &mut dyn FnMut(u16) -> u8,
+                   label: &dyn Fn(u16) -> Option<String>,
+                   start: u16,
+                   end: u16) -> Vec<Disassembly>
+{
+    let mut lines = Vec::new();
+    let mut address = start as u32;
+
+    while address <= end as u32 {
+        let mut line = disassemble_one(read, label, address as u16);
+        line.label = label(address as u16);
+        address += line.bytes.len() as u32;
+        lines.push(line);
+    }
+
+    lines
+}
+
+fn branch_target(address: u16, offset: u8) -> u16 {
+    address.wrapping_add(2).wrapping_add(offset as i8 as u16)
+}
+
+impl CPU {
+    // Disassembles whatever is mapped into the CPU's address space right now,
+    // noting which part of PRG-ROM each instruction came from.
+    pub fn disassemble(&mut self, start: u16, end: u16) -> Vec<Disassembly> {
+        // The last instruction can run up to two bytes past the end
+        let bytes: Vec<u8> = (start as u32 ..= (end as u32 + 2).min(0xffff))
+            .map(|address| self.peek(address as u16))
+            .collect();
+
+        let mapper = &self.mem.mapper;
+        let symbols = &self.symbols;
+        let label = |address: u16| {
+            symbols.label(address, mapper.prg_rom_offset(address)).map(str::to_string)
+        };
+
+        let mut read = |address: u16| {
+            bytes.get(address.wrapping_sub(start) as usize).copied().unwrap_or(0)
+        };
+
+        let mut lines = disassemble(&mut read, &label, start, end);
+        for line in &mut lines {
+            line.rom_offset = mapper.prg_rom_offset(line.address);
+        }
+
+        lines
+    }
+}
+
+// Disassembles one of the 16KB PRG-ROM banks in an iNES file. The last bank is
+// disassembled at $C000, where it's fixed by most mappers, and the others at
+// $8000. Addresses outside of the bank are only labelled if they're not in
+// PRG-ROM, or they're in the last bank.
+pub fn disassemble_rom_bank(rom_path: &str, bank: usize, symbols: &Symbols, output: &mut dyn Write)
+    -> io::Result<()>
+{
+    let rom = fs::read(rom_path)?;
+    if rom.len() < 16 || rom[0 .. 4] != INES_MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "expected an iNES formatted ROM"));
+    }
+
+    let n_banks = rom[4] as usize;
+    let data_start = if rom[6] & 0x04 != 0 { 16 + 512 } else { 16 };
+    let bank_start = data_start + bank * PRG_BANK_SIZE;
+
+    let data = match rom.get(bank_start .. bank_start + PRG_BANK_SIZE) {
+        Some(data) if bank < n_banks => data,
+        _ => {
+            let message = format!("there's no PRG-ROM bank {}, the ROM has {}", bank, n_banks);
+            return Err(io::Error::new(ErrorKind::InvalidInput, message));
+        },
+    };
+
+    let origin: u16 = if bank + 1 == n_banks { 0xc000 } else { 0x8000 };
+
+    writeln!(output, "; {}, PRG-ROM bank {} of {}", rom_path, bank, n_banks)?;
+
+    let mut read = |address: u16| {
+        data.get(address.wrapping_sub(origin) as usize).copied().unwrap_or(0)
+    };
+
+    // Only the bank itself and the fixed last bank are known to be mapped in
+    let bank_end = origin + (PRG_BANK_SIZE - 1) as u16;
+    let label = |address: u16| {
+        let rom_offset = match address {
+            _ if (origin ..= bank_end).contains(&address) => {
+                Some(bank * PRG_BANK_SIZE + (address - origin) as usize)
+            },
+            0xc000 ..= 0xffff => {
+                Some((n_banks - 1) * PRG_BANK_SIZE + (address - 0xc000) as usize)
+            },
+            0x8000 ..= 0xffff => return None,
+            _                 => None,
+        };
+
+        symbols.label(address, rom_offset).map(str::to_string)
+    };
+
+    for line in disassemble(&mut read, &label, origin, bank_end) {
+        if let Some(label) = &line.label {
+            writeln!(output, "{}:", label)?;
+        }
+
+        writeln!(output, "{}", line)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(bytes: &[u8]) -> String {
+        let mut read = |address: u16| bytes.get(address as usize - 0x8000).copied().unwrap_or(0);
+        disassemble_one(&mut read, &|_| None, 0x8000).to_string()
+    }
+
+    #[test]
+    fn test_operands() {
+        assert_eq!(text(&[0xb1, 0x20]), "8000  B1 20     LDA ($20),Y");
+        assert_eq!(text(&[0xa1, 0x20]), "8000  A1 20     LDA ($20,X)");
+        assert_eq!(text(&[0xa9, 0x01]), "8000  A9 01     LDA #$01");
+        assert_eq!(text(&[0xbd, 0x34, 0x12]), "8000  BD 34 12  LDA $1234,X");
+        assert_eq!(text(&[0xb6, 0x10]), "8000  B6 10     LDX $10,Y");
+        assert_eq!(text(&[0x6c, 0xfc, 0xff]), "8000  6C FC FF  JMP ($FFFC)");
+        assert_eq!(text(&[0x0a]), "8000  0A        ASL A");
+        assert_eq!(text(&[0x60]), "8000  60        RTS");
+
+        // Branches are shown with where they go to
+        assert_eq!(text(&[0xd0, 0xfe]), "8000  D0 FE     BNE $8000");
+        assert_eq!(text(&[0x10, 0x10]), "8000  10 10     BPL $8012");
+    }
+
+    #[test]
+    fn test_range() {
+        let bytes = [0xa9, 0x01, 0x8d, 0x00, 0x02, 0xe8, 0x60];
+        let mut read = |address: u16| bytes.get(address as usize - 0x8000).copied().unwrap_or(0);
+
+        let lines = disassemble(&mut read, &|_| None, 0x8000, 0x8005);
+        let addresses: Vec<u16> = lines.iter().map(|line| line.address).collect();
+        assert_eq!(addresses, [0x8000, 0x8002, 0x8005]);
+        assert_eq!(lines[1].text, "STA $0200");
+    }
+
+    #[test]
+    fn test_rom_bank_labels() {
+        // Three banks, where the first calls into the last, fixed bank
+        let mut rom = vec![0x4e, 0x45, 0x53, 0x1a, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        rom.extend(vec![0xea; 3 * PRG_BANK_SIZE]);
+        rom[16 .. 19].copy_from_slice(&[0x20, 0x10, 0xc0]);
+
+        let path = std::env::temp_dir().join(format!("nes_disasm_{}.nes", std::process::id()));
+        fs::write(&path, &rom).unwrap();
+
+        let mut symbols = Symbols::new_symbols();
+        symbols.add("switched", 0x8010, Some(PRG_BANK_SIZE + 0x10));
+        symbols.add("fixed", 0xc010, Some(2 * PRG_BANK_SIZE + 0x10));
+
+        let mut output = Vec::new();
+        let result = disassemble_rom_bank(path.to_str().unwrap(), 0, &symbols, &mut output);
+        let _ = fs::remove_file(&path);
+        result.unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().nth(1), Some("8000  20 10 C0  JSR fixed"));
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut symbols = Symbols::new_symbols();
+        symbols.add("buffer", 0x0200, None);
+        symbols.add("frame", 0x0010, None);
+        symbols.add("loop", 0x8005, Some(0x0005));
+
+        let label = |address: u16| {
+            let rom_offset = if address >= 0x8000 { Some(address as usize - 0x8000) } else { None };
+            symbols.label(address, rom_offset).map(str::to_string)
+        };
+
+        let bytes = [0xa9, 0x01, 0x8d, 0x00, 0x02, 0xb1, 0x10, 0xd0, 0xfc];
+        let mut read = |address: u16| bytes.get(address as usize - 0x8000).copied().unwrap_or(0);
+
+        let lines = disassemble(&mut read, &label, 0x8000, 0x8007);
+        let text: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(text, ["LDA #$01", "STA buffer", "LDA (frame),Y", "BNE loop"]);
+        assert_eq!(lines[2].label.as_deref(), Some("loop"));
+    }
+}
@@ -113,7 +113,7 @@ impl Instruction {
             Instruction::LDX => cpu.ldx(addr),
             Instruction::LDY => cpu.ldy(addr),
             Instruction::LSR => cpu.lsr(addr, addr_mode),
-            Instruction::NOP => cpu.nop(),
+            Instruction::NOP => cpu.nop(addr, addr_mode),
             Instruction::ORA => cpu.ora(addr),
             Instruction::PHA => cpu.pha(),
             Instruction::PHP => cpu.php(),
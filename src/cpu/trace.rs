@@ -0,0 +1,321 @@
+// Trace logger
+//
+// Writes a line to a file for every instruction the CPU executes, in the
+// layout of nestest's log, FCEUX's trace logger or Mesen's trace logger, so
+// that traces can be compared against other emulators with the usual diff
+// tools. Tracing can be paused and carried on without closing the file.
+//
+// Optionally, each line can also have the PPU's scanline and dot, and the
+// 8KB PRG-ROM bank that the instruction is in, and interrupts can be logged
+// as they're taken, on lines of their own starting with a `;'.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::io;
+
+use crate::cpu::{Interrupt, CPU};
+use crate::cpu::addr::AddressingMode;
+use crate::cpu::inst::Instruction;
+use crate::cpu::opcode::{Opcode, OPCODES};
+
+// The size of the banks that the PRG-ROM bank numbers count in
+const BANK_SIZE: usize = 0x2000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceFormat {
+    Nestest,
+    FCEUX,
+    Mesen,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceOptions {
+    pub format: TraceFormat,
+
+    // Whether to log the PPU's scanline and dot, which nestest's format
+    // always has
+    pub ppu: bool,
+
+    // Whether to log which PRG-ROM bank each instruction is in
+    pub banks: bool,
+
+    // Whether to log NMIs and IRQs as they're taken
+    pub interrupts: bool,
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        Self {
+            format: TraceFormat::Nestest,
+            ppu: false,
+            banks: false,
+            interrupts: false,
+        }
+    }
+}
+
+impl TraceOptions {
+    // Parses a comma separated list of a format and the things to log, like
+    // "mesen,ppu,interrupts"
+    pub fn parse(options: &str) -> Result<Self, String> {
+        let mut result = Self::default();
+
+        for option in options.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            match option.to_lowercase().as_str() {
+                "nestest"    => result.format = TraceFormat::Nestest,
+                "fceux"      => result.format = TraceFormat::FCEUX,
+                "mesen"      => result.format = TraceFormat::Mesen,
+                "ppu"        => result.ppu = true,
+                "banks"      => result.banks = true,
+                "interrupts" => result.interrupts = true,
+                _            => return Err(format!("unknown trace option {}", option)),
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+pub struct Tracer {
+    output: BufWriter<File>,
+    options: TraceOptions,
+
+    // Whether instructions are being logged right now
+    pub enabled: bool,
+}
+
+impl Tracer {
+    // Starts tracing to a new file at `path'
+    pub fn new_tracer(path: &str, options: TraceOptions) -> io::Result<Self> {
+        Ok(Self {
+            output: BufWriter::new(File::create(path)?),
+            options,
+            enabled: true,
+        })
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+impl CPU {
+    // Logs the instruction at PC, before it's executed
+    pub(super) fn trace_instruction(&mut self) {
+        let options = match &self.tracer {
+            Some(tracer) if tracer.enabled => tracer.options,
+            _                              => return,
+        };
+
+        let line = self.disassemble(self.pc, self.pc).remove(0);
+        let (scanline, dot) = self.mem.ppu.position();
+
+        let pc = match line.rom_offset {
+            Some(offset) if options.banks => format!("{:02X}:{:04X}", offset / BANK_SIZE, self.pc),
+            _                             => format!("{:04X}", self.pc),
+        };
+
+        let mut text = match options.format {
+            TraceFormat::Nestest => {
+                let bytes = hex_bytes(&line.bytes, "");
+                let marker = if is_unofficial(line.bytes[0]) { '*' } else { ' ' };
+                let text = format!("{}{}", line.text, self.operand_values(&line.bytes));
+                format!("{}  {:8} {}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:3},{:3} CYC:{}",
+                        pc, bytes, marker, text, self.a, self.x, self.y, self.flags(), self.sp,
+                        scanline, dot, self.cycles)
+            },
+            TraceFormat::FCEUX => {
+                let bytes = hex_bytes(&line.bytes, "");
+                format!("A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{}  ${}:{:8}  {}",
+                        self.a, self.x, self.y, self.sp, flag_letters(self.flags()), pc, bytes, line.text)
+            },
+            TraceFormat::Mesen => {
+                let bytes = hex_bytes(&line.bytes, "$");
+                format!("{}  {:11}  {:<28}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+                        pc, bytes, line.text, self.a, self.x, self.y, self.flags(), self.sp)
+            },
+        };
+
+        if options.ppu && options.format != TraceFormat::Nestest {
+            text.push_str(&format!(" CYC:{:<3} SL:{:<3}", dot, scanline));
+        }
+
+        if options.format == TraceFormat::Mesen {
+            text.push_str(&format!(" CPU Cycle:{}", self.cycles));
+        }
+
+        self.write_trace(&text);
+    }
+
+    // What an instruction's operand points to, the way nestest.log shows it,
+    // like ` = 0300 @ 0300 = 89' for `LDA ($89),Y'. Memory is read before the
+    // instruction runs.
+    fn operand_values(&mut self, bytes: &[u8]) -> String {
+        let Opcode(ref inst, ref addr_mode, _, _) = OPCODES[bytes[0] as usize];
+        if let (Instruction::None, _) | (_, AddressingMode::None) = (inst, addr_mode) {
+            return String::new();
+        }
+
+        let byte = bytes.get(1).copied().unwrap_or(0);
+        let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or(0)]);
+
+        // Pointers wrap around within the page they're in
+        let pointer = |cpu: &mut Self, addr: u16| {
+            let hi_addr = (addr & 0xff00) | (addr.wrapping_add(1) & 0x00ff);
+            u16::from_le_bytes([cpu.peek(addr), cpu.peek(hi_addr)])
+        };
+
+        match *addr_mode {
+            AddressingMode::ZeroPageIndexed => {
+                format!(" = {:02X}", self.peek(byte as u16))
+            },
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+                let index = if let AddressingMode::ZeroPageX = addr_mode { self.x } else { self.y };
+                let addr = byte.wrapping_add(index) as u16;
+                format!(" @ {:02X} = {:02X}", addr, self.peek(addr))
+            },
+            AddressingMode::Absolute => match inst {
+                Instruction::JMP | Instruction::JSR => String::new(),
+                _ => format!(" = {:02X}", self.peek(word)),
+            },
+            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+                let index = if let AddressingMode::AbsoluteX = addr_mode { self.x } else { self.y };
+                let addr = word.wrapping_add(index as u16);
+                format!(" @ {:04X} = {:02X}", addr, self.peek(addr))
+            },
+            AddressingMode::Indirect => format!(" = {:04X}", pointer(self, word)),
+            AddressingMode::IndexedIndirect => {
+                let zero_page = byte.wrapping_add(self.x) as u16;
+                let addr = pointer(self, zero_page);
+                format!(" @ {:02X} = {:04X} = {:02X}", zero_page, addr, self.peek(addr))
+            },
+            AddressingMode::IndirectIndexed => {
+                let base = pointer(self, byte as u16);
+                let addr = base.wrapping_add(self.y as u16);
+                format!(" = {:04X} @ {:04X} = {:02X}", base, addr, self.peek(addr))
+            },
+            _ => String::new(),
+        }
+    }
+
+    // Logs an interrupt that's just been taken, if interrupts are being logged
+    pub(super) fn trace_interrupt(&mut self, interrupt: &Interrupt) {
+        match &self.tracer {
+            Some(tracer) if tracer.enabled && tracer.options.interrupts => { },
+            _ => return,
+        }
+
+        let name = match interrupt {
+            Interrupt::NMI => "NMI",
+            Interrupt::IRQ => "IRQ",
+        };
+
+        let (scanline, dot) = self.mem.ppu.position();
+        let text = format!("; {} to ${:04X}, at scanline {}, dot {}", name, self.pc, scanline, dot);
+        self.write_trace(&text);
+    }
+
+    // If the log can't be written to, tracing stops rather than taking the
+    // emulator down with it
+    fn write_trace(&mut self, text: &str) {
+        if let Some(tracer) = &mut self.tracer {
+            if let Err(e) = writeln!(tracer.output, "{}", text) {
+                warn!("unable to write to the trace log, so tracing has stopped: {}", e);
+                self.tracer = None;
+            }
+        }
+    }
+}
+
+// Whether an opcode is one of the undocumented ones, which nestest.log marks
+// with a `*'
+fn is_unofficial(opcode: u8) -> bool {
+    let Opcode(ref inst, _, _, _) = OPCODES[opcode as usize];
+
+    match inst {
+        Instruction::NOP => opcode != 0xea,
+        Instruction::SBC => opcode == 0xeb,
+        Instruction::ANC | Instruction::DCP | Instruction::ISB | Instruction::JAM |
+        Instruction::LAX | Instruction::RLA | Instruction::RRA | Instruction::SAX |
+        Instruction::SLO | Instruction::SRE => true,
+        _ => false,
+    }
+}
+
+fn hex_bytes(bytes: &[u8], prefix: &str) -> String {
+    bytes.iter()
+        .map(|byte| format!("{}{:02X}", prefix, byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// The flags the way FCEUX shows them, with set flags in upper case
+fn flag_letters(p: u8) -> String {
+    "NVUBDIZC".chars().enumerate().map(|(i, flag)| {
+        if p & (0x80 >> i) != 0 { flag } else { flag.to_ascii_lowercase() }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use crate::cpu::new_test_cpu;
+
+    // Runs a few instructions with tracing on, and returns the log
+    fn trace(name: &str, options: &str) -> Vec<String> {
+        //   8000  LDA #$01
+        //   8002  STA $0200
+        //   8005  LDA ($20),Y
+        //   8007  NOP $20
+        //   8009  JMP $8009
+        let mut cpu = new_test_cpu(&[
+            0xa9, 0x01, 0x8d, 0x00, 0x02, 0xb1, 0x20, 0x04, 0x20, 0x4c, 0x09, 0x80,
+        ]);
+
+        let path = env::temp_dir().join(format!("nes_trace_{}_{}.log", name, std::process::id()));
+        let path = path.to_str().unwrap();
+        let options = TraceOptions::parse(options).unwrap();
+        cpu.tracer = Some(Box::new(Tracer::new_tracer(path, options).unwrap()));
+
+        for _ in 0 .. 5 {
+            cpu.step();
+        }
+
+        cpu.tracer.as_mut().unwrap().flush().unwrap();
+        let log = fs::read_to_string(path).unwrap();
+        let _ = fs::remove_file(path);
+
+        log.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_options() {
+        assert_eq!(TraceOptions::parse("").unwrap(), TraceOptions::default());
+
+        let options = TraceOptions::parse("mesen,ppu,interrupts").unwrap();
+        assert_eq!(options.format, TraceFormat::Mesen);
+        assert!(options.ppu && options.interrupts && !options.banks);
+
+        assert!(TraceOptions::parse("nestest,colours").is_err());
+    }
+
+    #[test]
+    fn test_formats() {
+        let log = trace("nestest", "nestest");
+        assert_eq!(log.len(), 5);
+        assert_eq!(log[0], "8000  A9 01     LDA #$01                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0,  0 CYC:0");
+        assert_eq!(log[1], "8002  8D 00 02  STA $0200 = 00                  A:01 X:00 Y:00 P:24 SP:FD PPU:  0,  6 CYC:2");
+        assert_eq!(log[2], "8005  B1 20     LDA ($20),Y = 0000 @ 0000 = 00  A:01 X:00 Y:00 P:24 SP:FD PPU:  0, 18 CYC:6");
+
+        // Undocumented opcodes are marked, which moves the mnemonic left
+        assert_eq!(log[3], "8007  04 20    *NOP $20 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 33 CYC:11");
+
+        let log = trace("fceux", "fceux,banks");
+        assert_eq!(log[1], "A:01 X:00 Y:00 S:FD P:nvUbdIzc  $00:8002:8D 00 02  STA $0200");
+
+        let log = trace("mesen", "mesen,ppu");
+        assert_eq!(log[1], "8002  $8D $00 $02  STA $0200                   A:01 X:00 Y:00 P:24 SP:FD CYC:6   SL:0   CPU Cycle:2");
+    }
+}
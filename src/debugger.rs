@@ -0,0 +1,825 @@
+// Interactive CPU debugger
+//
+// The debugger hangs off of the CPU, which checks it before every instruction
+// and tells it about every bus access. When a breakpoint or watchpoint is hit,
+// or a step finishes, the CPU stops before the next instruction, and the
+// console hands over to the debugger's command prompt in the terminal until
+// it's told to carry on.
+//
+// Watchpoints on the PPU's address space are hit by accesses through PPUDATA
+// ($2007), at whatever VRAM address the PPU is pointing at. Watchpoints on the
+// CPU's address space are hit by every access, including instruction fetches.
+
+use std::io::Write;
+use std::io;
+
+use crate::cpu::{TraceOptions, Tracer, CPU};
+use crate::symbols::Symbols;
+
+const HELP: &str = "\
+c, continue               carry on until something stops the CPU
+s, step [N]               execute one instruction, or N of them
+n, next                   step, but over subroutine calls
+o, out                    carry on until the current subroutine returns
+scanline N                carry on until the PPU gets to scanline N
+b, break ADDR             stop before executing the instruction at ADDR
+watch ADDR[-END] [r|w|rw] stop after the CPU reads or writes ADDR to END
+pwatch ADDR[-END] [r|w|rw] the same, for the PPU's address space
+l, list                   list the breakpoints and watchpoints
+d, delete [N]             delete breakpoint N, or all of them
+bt, backtrace             show the subroutine calls and interrupts so far
+r, regs                   show the registers and flags
+set REG VALUE             set a, x, y, p, sp, pc, or a flag (n v b d i z c)
+trace FILE [OPTIONS]      trace to FILE, with options like mesen,ppu,banks
+trace on|off [ADDR]       start or stop tracing, now or when at ADDR
+m, mem ADDR [LEN]         show LEN bytes of memory from ADDR
+u, dis [ADDR[-END]]       disassemble from ADDR, or the PC, to END
+detach                    leave the debugger, and keep running
+q, quit                   quit the emulator
+
+Addresses and register values are in hex, with or without a $ or 0x.
+Addresses can also be labels from the ROM's symbol files, which only match
+while the PRG-ROM bank that they're in is mapped in.
+";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Space {
+    CPU,
+    PPU,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Breakpoint {
+    // Stops before the instruction at an address is executed, but only while
+    // it's mapped to the same place in PRG-ROM, if there is one
+    Exec { address: u16, rom_offset: Option<usize> },
+
+    // Stops after an instruction that reads or writes anywhere from `start'
+    // to `end'
+    Watch { space: Space, start: u16, end: u16, read: bool, write: bool },
+
+    // Starts or stops tracing before the instruction at an address, without
+    // stopping
+    Trace { address: u16, rom_offset: Option<usize>, on: bool },
+}
+
+// What to do after a command
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Prompt {
+    // Wait for another command
+    Stay,
+
+    // Let the CPU carry on
+    Resume,
+
+    // Leave the debugger and let the CPU carry on without it
+    Detach,
+
+    // Quit the emulator
+    Quit,
+}
+
+// How the CPU runs until it next stops, apart from breakpoints
+enum Run {
+    Continue,
+
+    // The number of instructions left to step through
+    Step(usize),
+
+    // Until the instruction after a subroutine call, with the stack where it
+    // was when the call was made
+    StepOver { pc: u16, sp: u8 },
+
+    // Until the stack has unwound past where it was
+    StepOut { sp: u8 },
+
+    // Until the PPU gets to a scanline, having been on another one first
+    Scanline { scanline: u16, left: bool },
+}
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    run: Run,
+
+    // Whether the CPU has stopped, and why
+    stopped: bool,
+    reason: Option<String>,
+
+    // A watchpoint that was hit during the last instruction
+    hit: Option<String>,
+
+    // The instruction the CPU stopped at, which it doesn't stop at again
+    // straight away when it carries on
+    resume_pc: Option<u16>,
+
+    // Whether a tracepoint wants tracing started or stopped
+    trace_toggle: Option<bool>,
+
+    // The subroutines and interrupts that haven't returned yet, innermost
+    // last
+    calls: Vec<Call>,
+}
+
+// Where a subroutine was called or an interrupt taken from, and where to, with
+// the stack as it was before
+struct Call {
+    from: (u16, Option<usize>),
+    to: (u16, Option<usize>),
+    sp: u8,
+    interrupt: Option<&'static str>,
+}
+
+// The most calls that are kept track of, in case a game never returns from
+// its subroutines or interrupts, and uses the stack in other ways
+const MAX_CALLS: usize = 256;
+
+impl Debugger {
+    pub fn new_debugger() -> Self {
+        Self {
+            breakpoints: Vec::new(),
+            run: Run::Continue,
+            stopped: false,
+            reason: None,
+            hit: None,
+            resume_pc: None,
+            trace_toggle: None,
+            calls: Vec::new(),
+        }
+    }
+
+    // Stops the CPU before the next instruction
+    pub fn break_in(&mut self) {
+        self.run = Run::Step(1);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // Called by the CPU before every instruction, once any interrupt has been
+    // taken, with where the instruction is in PRG-ROM. Returns whether the
+    // CPU should stop.
+    pub fn check(&mut self, pc: u16, rom_offset: Option<usize>, sp: u8, scanline: u16) -> bool {
+        if self.stopped {
+            return true;
+        }
+
+        let resuming = self.resume_pc.take() == Some(pc);
+        let matches = |address: u16, offset: Option<usize>| {
+            address == pc && (offset.is_none() || offset == rom_offset)
+        };
+
+        for breakpoint in &self.breakpoints {
+            if let Breakpoint::Trace { address, rom_offset: offset, on } = *breakpoint {
+                if matches(address, offset) {
+                    self.trace_toggle = Some(on);
+                }
+            }
+        }
+
+        let breakpoint = self.breakpoints.iter().any(|breakpoint| match *breakpoint {
+            Breakpoint::Exec { address, rom_offset: offset } => matches(address, offset),
+            _                                                => false,
+        });
+
+        if let Run::Scanline { scanline: target, left } = &mut self.run {
+            if scanline != *target {
+                *left = true;
+            }
+        }
+
+        let reason = if let Some(hit) = self.hit.take() {
+            Some(Some(hit))
+        } else if resuming {
+            None
+        } else if breakpoint {
+            Some(Some(format!("breakpoint at ${:04X}", pc)))
+        } else {
+            match &mut self.run {
+                Run::Continue => None,
+                Run::Step(n) => {
+                    *n = n.saturating_sub(1);
+                    if *n == 0 { Some(None) } else { None }
+                },
+                Run::StepOver { pc: target, sp: call_sp } => {
+                    if pc == *target && sp >= *call_sp { Some(None) } else { None }
+                },
+                Run::StepOut { sp: start } => {
+                    if sp as u16 >= *start as u16 + 2 { Some(None) } else { None }
+                },
+                Run::Scanline { scanline: target, left } => {
+                    if *left && scanline == *target {
+                        Some(Some(format!("reached scanline {}", scanline)))
+                    } else {
+                        None
+                    }
+                },
+            }
+        };
+
+        match reason {
+            Some(reason) => {
+                self.stopped = true;
+                self.reason = reason;
+                self.run = Run::Continue;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Called by the CPU when a subroutine is called or an interrupt is taken
+    pub fn call(&mut self,
+                from: (u16, Option<usize>),
+                to: (u16, Option<usize>),
+                sp: u8,
+                interrupt: Option<&'static str>)
+    {
+        if self.calls.len() == MAX_CALLS {
+            self.calls.remove(0);
+        }
+
+        self.calls.push(Call { from, to, sp, interrupt });
+    }
+
+    // Called by the CPU when a subroutine or interrupt returns, which unwinds
+    // every call that the stack has been unwound past
+    pub fn ret(&mut self, sp: u8) {
+        while let Some(call) = self.calls.last() {
+            if call.sp > sp {
+                break;
+            }
+            self.calls.pop();
+        }
+    }
+
+    // Whether a tracepoint was hit, and if so, whether it starts tracing
+    pub fn take_trace_toggle(&mut self) -> Option<bool> {
+        self.trace_toggle.take()
+    }
+
+    // Called by the CPU for every bus access, and every access to the PPU's
+    // address space through PPUDATA
+    pub fn access(&mut self, space: Space, address: u16, val: u8, access: Access) {
+        if self.hit.is_some() {
+            return;
+        }
+
+        let watched = self.breakpoints.iter().any(|breakpoint| match *breakpoint {
+            Breakpoint::Watch { space: s, start, end, read, write } => {
+                s == space && (start ..= end).contains(&address) && match access {
+                    Access::Read  => read,
+                    Access::Write => write,
+                }
+            },
+            _ => false,
+        });
+
+        if watched {
+            let space = match space {
+                Space::CPU => "",
+                Space::PPU => "PPU ",
+            };
+
+            self.hit = Some(match access {
+                Access::Read  => format!("read ${:02X} from {}${:04X}", val, space, address),
+                Access::Write => format!("wrote ${:02X} to {}${:04X}", val, space, address),
+            });
+        }
+    }
+
+    // Says why the CPU stopped, and what it's about to execute
+    pub fn show_stop(&self, cpu: &mut CPU, output: &mut dyn Write) -> io::Result<()> {
+        if let Some(reason) = &self.reason {
+            writeln!(output, "stopped: {}", reason)?;
+        }
+
+        let rom_offset = cpu.mem.mapper.prg_rom_offset(cpu.pc);
+        if let Some(label) = cpu.symbols.label(cpu.pc, rom_offset) {
+            writeln!(output, "{}:", label)?;
+        }
+
+        writeln!(output, "{}", cpu.trace_line())
+    }
+
+    // Runs a command from the prompt
+    pub fn command(&mut self, cpu: &mut CPU, line: &str, output: &mut dyn Write) -> io::Result<Prompt> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None               => return Ok(Prompt::Stay),
+        };
+
+        let result = match name {
+            "c" | "continue" => Ok(self.resume(cpu, Run::Continue)),
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(n) => n.parse().map_err(|_| format!("invalid count {}", n)),
+                    None    => Ok(1),
+                };
+                count.map(|count| self.resume(cpu, Run::Step(count)))
+            },
+            "n" | "next" => {
+                // JSR is the only instruction that calls a subroutine
+                let run = if cpu.peek(cpu.pc) == 0x20 {
+                    Run::StepOver { pc: cpu.pc.wrapping_add(3), sp: cpu.sp }
+                } else {
+                    Run::Step(1)
+                };
+                Ok(self.resume(cpu, run))
+            },
+            "o" | "out" => Ok(self.resume(cpu, Run::StepOut { sp: cpu.sp })),
+            "scanline" => {
+                match args.first().and_then(|n| n.parse::<u16>().ok()) {
+                    Some(scanline) if scanline <= 261 => {
+                        Ok(self.resume(cpu, Run::Scanline { scanline, left: false }))
+                    },
+                    _ => Err("expected a scanline from 0 to 261".to_string()),
+                }
+            },
+            "b" | "break" => {
+                match args.first().and_then(|a| parse_address(&cpu.symbols, a)) {
+                    Some((address, rom_offset)) => {
+                        let breakpoint = Breakpoint::Exec { address, rom_offset };
+                        self.breakpoints.push(breakpoint);
+                        writeln!(output, "{}: {}", self.breakpoints.len() - 1, describe(&breakpoint, &cpu.symbols))?;
+                        Ok(Prompt::Stay)
+                    },
+                    None => Err("expected an address or a label".to_string()),
+                }
+            },
+            "watch" | "pwatch" => {
+                let space = if name == "watch" { Space::CPU } else { Space::PPU };
+                match parse_watch(&cpu.symbols, space, args) {
+                    Some(watch) => {
+                        self.breakpoints.push(watch);
+                        writeln!(output, "{}: {}", self.breakpoints.len() - 1, describe(&watch, &cpu.symbols))?;
+                        Ok(Prompt::Stay)
+                    },
+                    None => Err("expected ADDR[-END] [r|w|rw]".to_string()),
+                }
+            },
+            "trace" => self.trace(cpu, args, output),
+            "l" | "list" => {
+                if self.breakpoints.is_empty() {
+                    writeln!(output, "no breakpoints")?;
+                }
+                for (i, breakpoint) in self.breakpoints.iter().enumerate() {
+                    writeln!(output, "{}: {}", i, describe(breakpoint, &cpu.symbols))?;
+                }
+                Ok(Prompt::Stay)
+            },
+            "d" | "delete" => {
+                match args.first() {
+                    None => {
+                        self.breakpoints.clear();
+                        Ok(Prompt::Stay)
+                    },
+                    Some(n) => match n.parse::<usize>() {
+                        Ok(i) if i < self.breakpoints.len() => {
+                            self.breakpoints.remove(i);
+                            Ok(Prompt::Stay)
+                        },
+                        _ => Err(format!("no breakpoint {}", n)),
+                    },
+                }
+            },
+            "bt" | "backtrace" => {
+                let rom_offset = cpu.mem.mapper.prg_rom_offset(cpu.pc);
+                writeln!(output, "#0  {}", cpu.symbols.describe(cpu.pc, rom_offset))?;
+
+                for (i, call) in self.calls.iter().rev().enumerate() {
+                    let (from, from_offset) = call.from;
+                    let (to, to_offset) = call.to;
+                    let from = cpu.symbols.describe(from, from_offset);
+                    let to = cpu.symbols.describe(to, to_offset);
+
+                    match call.interrupt {
+                        Some(interrupt) => writeln!(output, "#{}  {} to {}, from {}", i + 1, interrupt, to, from)?,
+                        None            => writeln!(output, "#{}  JSR {}, from {}", i + 1, to, from)?,
+                    }
+                }
+                Ok(Prompt::Stay)
+            },
+            "r" | "regs" => {
+                writeln!(output, "{}", registers(cpu))?;
+                Ok(Prompt::Stay)
+            },
+            "set" => {
+                match args {
+                    [register, value] => set_register(cpu, register, value).map(|_| Prompt::Stay),
+                    _                 => Err("expected set REG VALUE".to_string()),
+                }
+            },
+            "m" | "mem" => {
+                let address = args.first()
+                    .and_then(|a| parse_address(&cpu.symbols, a))
+                    .map(|(address, _)| address);
+                let len = match args.get(1) {
+                    Some(len) => parse_hex(len),
+                    None      => Some(0x40),
+                };
+
+                match (address, len) {
+                    (Some(address), Some(len)) => {
+                        dump_memory(cpu, address, len, output)?;
+                        Ok(Prompt::Stay)
+                    },
+                    _ => Err("expected an address, and optionally a length".to_string()),
+                }
+            },
+            "u" | "dis" => {
+                let range = match args.first() {
+                    Some(range) => parse_range(&cpu.symbols, range),
+                    None        => Some((cpu.pc, cpu.pc.saturating_add(0x1f))),
+                };
+
+                match range {
+                    Some((start, end)) => {
+                        for line in cpu.disassemble(start, end) {
+                            if let Some(label) = &line.label {
+                                writeln!(output, "{}:", label)?;
+                            }
+
+                            match line.rom_offset {
+                                Some(offset) => writeln!(output, "{:<40} ; PRG-ROM ${:05X}", line.to_string(), offset)?,
+                                None         => writeln!(output, "{}", line)?,
+                            }
+                        }
+                        Ok(Prompt::Stay)
+                    },
+                    None => Err("expected ADDR[-END]".to_string()),
+                }
+            },
+            "detach" => {
+                self.stopped = false;
+                Ok(Prompt::Detach)
+            },
+            "q" | "quit" => Ok(Prompt::Quit),
+            "h" | "help" | "?" => {
+                write!(output, "{}", HELP)?;
+                Ok(Prompt::Stay)
+            },
+            _ => Err(format!("unknown command {}, try help", name)),
+        };
+
+        match result {
+            Ok(prompt) => Ok(prompt),
+            Err(message) => {
+                writeln!(output, "{}", message)?;
+                Ok(Prompt::Stay)
+            },
+        }
+    }
+
+    fn trace(&mut self, cpu: &mut CPU, args: &[&str], output: &mut dyn Write) -> Result<Prompt, String> {
+        let on = match args.first().copied() {
+            Some("on")  => true,
+            Some("off") => false,
+            Some(path)  => {
+                let options = TraceOptions::parse(args.get(1).copied().unwrap_or(""))?;
+                let tracer = Tracer::new_tracer(path, options)
+                    .map_err(|e| format!("unable to trace to {}: {}", path, e))?;
+
+                cpu.tracer = Some(Box::new(tracer));
+                return Ok(Prompt::Stay);
+            },
+            None => return Err("expected a file, or on or off".to_string()),
+        };
+
+        match args.get(1) {
+            Some(address) => {
+                let (address, rom_offset) = parse_address(&cpu.symbols, address)
+                    .ok_or_else(|| format!("invalid address {}", address))?;
+                let tracepoint = Breakpoint::Trace { address, rom_offset, on };
+                self.breakpoints.push(tracepoint);
+                writeln!(output, "{}: {}", self.breakpoints.len() - 1, describe(&tracepoint, &cpu.symbols))
+                    .map_err(|e| e.to_string())?;
+            },
+            None => {
+                match &mut cpu.tracer {
+                    Some(tracer) => tracer.enabled = on,
+                    None         => return Err("not tracing to a file".to_string()),
+                }
+            },
+        }
+
+        Ok(Prompt::Stay)
+    }
+
+    fn resume(&mut self, cpu: &CPU, run: Run) -> Prompt {
+        self.run = run;
+        self.stopped = false;
+        self.reason = None;
+        self.resume_pc = Some(cpu.pc);
+        Prompt::Resume
+    }
+}
+
+fn describe(breakpoint: &Breakpoint, symbols: &Symbols) -> String {
+    // Labels are only shown for breakpoints that were set on them, since
+    // they're the only ones that are tied to a bank
+    let at = |address: u16, rom_offset: Option<usize>| {
+        match rom_offset.and_then(|offset| symbols.label(address, Some(offset))) {
+            Some(label) => format!("{} (${:04X})", label, address),
+            None        => format!("${:04X}", address),
+        }
+    };
+
+    match *breakpoint {
+        Breakpoint::Exec { address, rom_offset } => format!("break at {}", at(address, rom_offset)),
+        Breakpoint::Trace { address, rom_offset, on } => {
+            format!("trace {} at {}", if on { "on" } else { "off" }, at(address, rom_offset))
+        },
+        Breakpoint::Watch { space, start, end, read, write } => {
+            let access = match (read, write) {
+                (true, true) => "rw",
+                (true, _)    => "r",
+                _            => "w",
+            };
+
+            let space = match space {
+                Space::CPU => "watch",
+                Space::PPU => "pwatch",
+            };
+
+            if start == end {
+                format!("{} ${:04X} {}", space, start, access)
+            } else {
+                format!("{} ${:04X}-${:04X} {}", space, start, end, access)
+            }
+        },
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u16> {
+    let digits = s.strip_prefix('$')
+        .or_else(|| s.strip_prefix("0x"))
+        .unwrap_or(s);
+
+    u16::from_str_radix(digits, 16).ok()
+}
+
+// An address in hex, or a label, along with where the label is in PRG-ROM.
+// Hex with a $ or 0x can't be a label, and labels take priority over bare hex.
+fn parse_address(symbols: &Symbols, s: &str) -> Option<(u16, Option<usize>)> {
+    if s.starts_with('$') || s.starts_with("0x") {
+        return parse_hex(s).map(|address| (address, None));
+    }
+
+    symbols.lookup(s).or_else(|| parse_hex(s).map(|address| (address, None)))
+}
+
+// A single address, or START-END
+fn parse_range(symbols: &Symbols, range: &str) -> Option<(u16, u16)> {
+    let address = |s: &str| parse_address(symbols, s).map(|(address, _)| address);
+
+    let (start, end) = match range.find('-') {
+        Some(dash) => (address(&range[.. dash])?, address(&range[dash + 1 ..])?),
+        None       => {
+            let address = address(range)?;
+            (address, address)
+        },
+    };
+
+    if end < start {
+        return None;
+    }
+
+    Some((start, end))
+}
+
+fn parse_watch(symbols: &Symbols, space: Space, args: &[&str]) -> Option<Breakpoint> {
+    let (start, end) = parse_range(symbols, args.first()?)?;
+
+    let (read, write) = match args.get(1).copied() {
+        Some("r")         => (true, false),
+        Some("w")         => (false, true),
+        Some("rw") | None => (true, true),
+        _                 => return None,
+    };
+
+    Some(Breakpoint::Watch { space, start, end, read, write })
+}
+
+fn registers(cpu: &CPU) -> String {
+    let p = cpu.flags();
+    let flags: String = "NV-BDIZC".chars().enumerate().map(|(i, flag)| {
+        if p & (0x80 >> i) != 0 { flag } else { flag.to_ascii_lowercase() }
+    }).collect();
+
+    format!("A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PC:{:04X}  {}",
+            cpu.a, cpu.x, cpu.y, p, cpu.sp, cpu.pc, flags)
+}
+
+fn set_register(cpu: &mut CPU, register: &str, value: &str) -> Result<(), String> {
+    let value = parse_hex(value).ok_or_else(|| format!("invalid value {}", value))?;
+
+    let flag = |cpu: &mut CPU, bit: u8| {
+        let p = cpu.flags();
+        cpu.set_flags(if value != 0 { p | bit } else { p & !bit });
+    };
+
+    match register.to_lowercase().as_str() {
+        "pc" => cpu.pc = value,
+        _ if value > 0xff => return Err(format!("{} is only 8 bits", register)),
+        "a"  => cpu.a = value as u8,
+        "x"  => cpu.x = value as u8,
+        "y"  => cpu.y = value as u8,
+        "p"  => cpu.set_flags(value as u8),
+        "sp" => cpu.sp = value as u8,
+        "n"  => flag(cpu, 0x80),
+        "v"  => flag(cpu, 0x40),
+        "b"  => flag(cpu, 0x10),
+        "d"  => flag(cpu, 0x08),
+        "i"  => flag(cpu, 0x04),
+        "z"  => flag(cpu, 0x02),
+        "c"  => flag(cpu, 0x01),
+        _    => return Err(format!("unknown register {}", register)),
+    }
+
+    Ok(())
+}
+
+fn dump_memory(cpu: &mut CPU, address: u16, len: u16, output: &mut dyn Write) -> io::Result<()> {
+    let end = address as u32 + len as u32;
+
+    for row in (address as u32 .. end).step_by(16) {
+        write!(output, "{:04X}:", row)?;
+
+        for a in row .. (row + 16).min(end) {
+            write!(output, " {:02X}", cpu.peek(a as u16))?;
+        }
+
+        writeln!(output)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu;
+
+    //   8000  LDA #$01
+    //   8002  JSR $8010
+    //   8005  STA $0200
+    //   8008  JMP $8008
+    //   8010  INX
+    //   8011  STA $0300
+    //   8014  RTS
+    fn new_test_cpu() -> CPU {
+        let mut cpu = cpu::new_test_cpu(&[
+            0xa9, 0x01, 0x20, 0x10, 0x80, 0x8d, 0x00, 0x02, 0x4c, 0x08, 0x80,
+            0xea, 0xea, 0xea, 0xea, 0xea,
+            0xe8, 0x8d, 0x00, 0x03, 0x60,
+        ]);
+
+        let mut debugger = Debugger::new_debugger();
+        debugger.break_in();
+        cpu.debugger = Some(Box::new(debugger));
+        cpu
+    }
+
+    fn run(cpu: &mut CPU) {
+        for _ in 0 .. 100000 {
+            cpu.step();
+            if cpu.debugger.as_ref().unwrap().is_stopped() {
+                return;
+            }
+        }
+
+        panic!("the CPU didn't stop");
+    }
+
+    fn command(cpu: &mut CPU, line: &str) -> (Prompt, String) {
+        let mut debugger = cpu.debugger.take().unwrap();
+        let mut output = Vec::new();
+        let prompt = debugger.command(cpu, line, &mut output).unwrap();
+        cpu.debugger = Some(debugger);
+
+        (prompt, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_stepping() {
+        let mut cpu = new_test_cpu();
+        run(&mut cpu);
+        assert_eq!(cpu.pc, 0x8000);
+
+        assert_eq!(command(&mut cpu, "step").0, Prompt::Resume);
+        run(&mut cpu);
+        assert_eq!(cpu.pc, 0x8002);
+
+        // Stepping over the subroutine still runs it
+        command(&mut cpu, "next");
+        run(&mut cpu);
+        assert_eq!(cpu.pc, 0x8005);
+        assert_eq!(cpu.x, 1);
+
+        command(&mut cpu, "set pc 8000");
+        command(&mut cpu, "break $8010");
+        command(&mut cpu, "continue");
+        run(&mut cpu);
+        assert_eq!(cpu.pc, 0x8010);
+
+        command(&mut cpu, "out");
+        run(&mut cpu);
+        assert_eq!(cpu.pc, 0x8005);
+        assert_eq!(cpu.x, 2);
+
+        command(&mut cpu, "step 2");
+        run(&mut cpu);
+        assert_eq!(cpu.pc, 0x8008);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut cpu = new_test_cpu();
+        run(&mut cpu);
+
+        let (_, output) = command(&mut cpu, "watch 0200-02ff w");
+        assert_eq!(output, "0: watch $0200-$02FF w\n");
+
+        // The subroutine's write to $0300 isn't watched
+        command(&mut cpu, "c");
+        run(&mut cpu);
+        assert_eq!(cpu.pc, 0x8008);
+
+        let debugger = cpu.debugger.take().unwrap();
+        let mut output = Vec::new();
+        debugger.show_stop(&mut cpu, &mut output).unwrap();
+        cpu.debugger = Some(debugger);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("stopped: wrote $01 to $0200\n8008"), "{}", output);
+
+        // Tracepoints don't stop the CPU
+        assert_eq!(command(&mut cpu, "trace on 8010").1, "1: trace on at $8010\n");
+        assert_eq!(command(&mut cpu, "trace on").1, "not tracing to a file\n");
+
+        command(&mut cpu, "delete 0");
+        command(&mut cpu, "delete 0");
+        assert_eq!(command(&mut cpu, "list").1, "no breakpoints\n");
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut cpu = new_test_cpu();
+        run(&mut cpu);
+
+        command(&mut cpu, "set a 42");
+        command(&mut cpu, "set c 1");
+        command(&mut cpu, "set sp $f0");
+        assert_eq!(command(&mut cpu, "set a 100").1, "a is only 8 bits\n");
+        assert_eq!(command(&mut cpu, "regs").1, "A:42 X:00 Y:00 P:25 SP:F0 PC:8000  nv-bdIzC\n");
+
+        assert_eq!(command(&mut cpu, "mem 8000 3").1, "8000: A9 01 20\n");
+        assert_eq!(command(&mut cpu, "dis 8000-8002").1,
+                   "8000  A9 01     LDA #$01                 ; PRG-ROM $00000\n\
+                    8002  20 10 80  JSR $8010                ; PRG-ROM $00002\n");
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut cpu = new_test_cpu();
+        cpu.symbols.add("update", 0x8010, Some(0x0010));
+        cpu.symbols.add("buffer", 0x0200, None);
+        run(&mut cpu);
+
+        assert_eq!(command(&mut cpu, "break update").1, "0: break at update ($8010)\n");
+        assert_eq!(command(&mut cpu, "break nowhere").1, "expected an address or a label\n");
+        assert_eq!(command(&mut cpu, "mem buffer 1").1, "0200: 00\n");
+        assert_eq!(command(&mut cpu, "dis 8002-8002").1,
+                   "8002  20 10 80  JSR update               ; PRG-ROM $00002\n");
+
+        command(&mut cpu, "continue");
+        run(&mut cpu);
+        assert_eq!(cpu.pc, 0x8010);
+
+        command(&mut cpu, "step");
+        run(&mut cpu);
+        assert_eq!(command(&mut cpu, "bt").1, "#0  update+1\n#1  JSR update, from $8002\n");
+
+        // The call's gone once the subroutine returns
+        command(&mut cpu, "out");
+        run(&mut cpu);
+        assert_eq!(command(&mut cpu, "backtrace").1, "#0  $8005\n");
+    }
+
+    #[test]
+    fn test_scanline() {
+        let mut cpu = new_test_cpu();
+        run(&mut cpu);
+
+        command(&mut cpu, "scanline 100");
+        run(&mut cpu);
+        assert_eq!(cpu.mem.ppu.position().0, 100);
+    }
+}
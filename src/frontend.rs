@@ -0,0 +1,103 @@
+// Frontend abstractions
+//
+// The emulator core doesn't know anything about windows, sound cards or
+// keyboards. Instead, the run loop in the console talks to a video sink, an
+// audio sink and an input source, and any frontend (SDL, a null frontend for
+// headless runs, a file dumper, a terminal renderer, etc.) can implement these
+// traits and plug into the same loop.
+
+use crate::controller::Button;
+use crate::keyboard::Key;
+use crate::movie::MovieStart;
+use crate::palette::Color;
+use crate::ppu::DebugView;
+
+// Hotkeys that control the emulator itself, rather than the game
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hotkey {
+    Pause,
+    SaveState,
+    LoadState,
+    Reset,
+    DumpCHR,
+
+    // Stops the CPU and opens the debugger's command prompt in the terminal
+    Debug,
+
+    // Starts logging every instruction to a file, or stops
+    Trace,
+
+    // Selects which of the save state slots, 0 to 9, saving and loading use
+    SelectSlot(u8),
+
+    // Start recording a movie, or stop and save the one being recorded
+    RecordMovie(MovieStart),
+
+    // Play the saved movie back, or stop the one that's playing
+    PlayMovie,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum InputEvent {
+    // A button on controller 0 to 3 was pressed or released
+    Button(usize, Button, bool),
+
+    // The turbo version of a button was held or let go of
+    Turbo(usize, Button, bool),
+    Hotkey(Hotkey),
+
+    // Rewinding carries on for as long as its key is held down
+    Rewind(bool),
+
+    // A button on the Power Pad, from 0 to 11, was stepped on or off of
+    PowerPad(usize, bool),
+
+    // A key on the Family BASIC keyboard was pressed or released
+    Key(Key, bool),
+
+    // The mouse moved to a pixel on the NES screen, or away from it, and its
+    // button was pressed or released. The Zapper and the Arkanoid controller
+    // follow the mouse.
+    Mouse(Option<(u8, u8)>),
+    MouseButton(bool),
+
+    Quit,
+}
+
+pub trait VideoSink {
+    // Called every time the PPU has finished rendering a frame, with 240 rows
+    // of 256 pixels.
+    fn present(&mut self, frame: &[Vec<Color>]);
+
+    // Only called in PPU debug mode, with the palettes and pattern tables, and
+    // before the frame is presented.
+    fn present_debug(&mut self, _debug: &DebugView) { }
+}
+
+pub trait AudioSink {
+    // Called once per frame with the mono samples, at 44.1kHz, that were
+    // produced during that frame.
+    fn queue(&mut self, samples: &[f32]);
+}
+
+pub trait InputSource {
+    // Called once per frame (and periodically while paused) to collect any
+    // input that has happened since the last poll.
+    fn poll(&mut self) -> Vec<InputEvent>;
+}
+
+// A frontend that throws away all output and never produces any input. Useful
+// for running the emulator headless.
+pub struct Null;
+
+impl VideoSink for Null {
+    fn present(&mut self, _frame: &[Vec<Color>]) { }
+}
+
+impl AudioSink for Null {
+    fn queue(&mut self, _samples: &[f32]) { }
+}
+
+impl InputSource for Null {
+    fn poll(&mut self) -> Vec<InputEvent> { Vec::new() }
+}
@@ -12,11 +12,8 @@ use crate::mapper::Mapper69;
 
 use crate::mapper::MirrorMode;
 
-use std::cell::RefCell;
-use std::fs::File;
 use std::io::Read;
 use std::io;
-use std::rc::Rc;
 
 const INES_MAGIC: [u8; 4] = [0x4e, 0x45, 0x53, 0x1a];
 
@@ -29,8 +26,16 @@ pub enum CartridgeError {
     UnsupportedMapper(u8),
 }
 
-pub fn load_file_into_memory(fh: &mut File)
-    -> Result<Rc<RefCell<Box<dyn Mapper>>>, CartridgeError>
+pub struct Cartridge {
+    pub mapper: Box<dyn Mapper>,
+
+    // Whether the cartridge's PRG-RAM is kept alive by a battery, and so
+    // should be persisted between runs
+    pub battery_backed: bool,
+}
+
+pub fn load_file_into_memory(fh: &mut dyn Read)
+    -> Result<Cartridge, CartridgeError>
 {
     let mut header = [0; 16];
     let _ = fh.read(&mut header).map_err(CartridgeError::IO)?;
@@ -57,7 +62,8 @@ pub fn load_file_into_memory(fh: &mut File)
         mirror_mode = MirrorMode::Four as u8;
     }
 
-    let battery_backed = (header[7] & 0x02) != 0;
+    // Flags 6, bit 1 (byte 7 holds the VS/PlayChoice bits instead)
+    let battery_backed = (header[6] & 0x02) != 0;
     debug!("battery backed PRG-RAM: {}", if battery_backed { "yes" } else { "no" });
 
     // Get the mapper
@@ -122,17 +128,22 @@ pub fn load_file_into_memory(fh: &mut File)
         debug!("making 8KB of CHR-RAM");
     }
 
-    match mapper {
-        0 => Ok(Rc::new(RefCell::new(Box::new(Mapper0::new_mapper(rom, vrom, mirror_mode))))),
-        1 => Ok(Rc::new(RefCell::new(Box::new(Mapper1::new_mapper(rom, vrom, mirror_mode))))),
-        2 => Ok(Rc::new(RefCell::new(Box::new(Mapper2::new_mapper(rom, vrom, mirror_mode))))),
-        3 => Ok(Rc::new(RefCell::new(Box::new(Mapper3::new_mapper(rom, vrom, mirror_mode))))),
-        4 => Ok(Rc::new(RefCell::new(Box::new(Mapper4::new_mapper(rom, vrom, mirror_mode))))),
-        7 => Ok(Rc::new(RefCell::new(Box::new(Mapper7::new_mapper(rom, vrom, mirror_mode))))),
-        34 => Ok(Rc::new(RefCell::new(Box::new(Mapper34::new_mapper(rom, vrom, mirror_mode))))),
-        66 => Ok(Rc::new(RefCell::new(Box::new(Mapper66::new_mapper(rom, vrom, mirror_mode))))),
-        68 => Ok(Rc::new(RefCell::new(Box::new(Mapper68::new_mapper(rom, vrom, mirror_mode))))),
-        69 => Ok(Rc::new(RefCell::new(Box::new(Mapper69::new_mapper(rom, vrom, mirror_mode))))),
-        _ => Err(CartridgeError::UnsupportedMapper(mapper)),
-    }
+    let mapper: Box<dyn Mapper> = match mapper {
+        0 => Box::new(Mapper0::new_mapper(rom, vrom, mirror_mode)),
+        1 => Box::new(Mapper1::new_mapper(rom, vrom, mirror_mode)),
+        2 => Box::new(Mapper2::new_mapper(rom, vrom, mirror_mode)),
+        3 => Box::new(Mapper3::new_mapper(rom, vrom, mirror_mode)),
+        4 => Box::new(Mapper4::new_mapper(rom, vrom, mirror_mode)),
+        7 => Box::new(Mapper7::new_mapper(rom, vrom, mirror_mode)),
+        34 => Box::new(Mapper34::new_mapper(rom, vrom, mirror_mode)),
+        66 => Box::new(Mapper66::new_mapper(rom, vrom, mirror_mode)),
+        68 => Box::new(Mapper68::new_mapper(rom, vrom, mirror_mode)),
+        69 => Box::new(Mapper69::new_mapper(rom, vrom, mirror_mode)),
+        _ => return Err(CartridgeError::UnsupportedMapper(mapper)),
+    };
+
+    Ok(Cartridge {
+        mapper,
+        battery_backed,
+    })
 }
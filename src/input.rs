@@ -0,0 +1,111 @@
+// Input devices
+//
+// The standard controllers are always plugged in, but another device can be
+// plugged in as well, either in place of the second controller or into the
+// Famicom's expansion port, where it's read alongside the controllers.
+//
+// Every device sees the same writes to $4016 as the controllers do, which
+// usually strobe or clock it, and gets its input straight from the frontend's
+// input events.
+
+use std::io::{Read, Write};
+use std::io;
+
+use crate::frontend::InputEvent;
+use crate::keyboard::Keyboard;
+use crate::mem::Memory;
+use crate::power_pad::PowerPad;
+use crate::ppu::PPU;
+use crate::serde;
+use crate::vaus::Vaus;
+use crate::zapper::Zapper;
+
+// Which device is plugged in. The numbers are what's stored in save states.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum InputDevice {
+    // Just the standard controllers
+    #[default]
+    None        = 0,
+
+    // A Zapper in place of the second controller
+    Zapper      = 1,
+
+    // An Arkanoid controller, either the NES one in place of the second
+    // controller, or the Famicom one in the expansion port
+    Vaus        = 2,
+    FamicomVaus = 3,
+
+    // A Power Pad in place of the second controller
+    PowerPad    = 4,
+
+    // The Family BASIC keyboard, in the expansion port
+    Keyboard    = 5,
+}
+
+impl InputDevice {
+    pub fn new_device(self) -> Option<Box<dyn Device>> {
+        match self {
+            InputDevice::None        => None,
+            InputDevice::Zapper      => Some(Box::new(Zapper::new_zapper())),
+            InputDevice::Vaus        => Some(Box::new(Vaus::new_vaus())),
+            InputDevice::FamicomVaus => Some(Box::new(Vaus::new_famicom_vaus())),
+            InputDevice::PowerPad    => Some(Box::new(PowerPad::new_power_pad())),
+            InputDevice::Keyboard    => Some(Box::new(Keyboard::new_keyboard())),
+        }
+    }
+}
+
+// Where a device plugs in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slot {
+    // In place of the second controller, which can't be read any more
+    Port2,
+
+    // Into the Famicom's expansion port, next to both controllers
+    Expansion,
+}
+
+pub trait Device: Memory + Send {
+    fn kind(&self) -> InputDevice;
+    fn slot(&self) -> Slot;
+
+    // Reads the device's bits of $4016 (port 0) or $4017 (port 1). The PPU is
+    // there for light guns, which see what's on the screen.
+    fn read_port(&mut self, port: usize, ppu: &PPU) -> u8;
+
+    // Devices pick out the input events that are meant for them, and ignore
+    // the rest
+    fn input(&mut self, _event: &InputEvent) { }
+}
+
+// Saves whichever device is plugged in, along with what it is, so that a
+// state with a different device can be told apart.
+pub fn save_device(device: &Option<Box<dyn Device>>, output: &mut dyn Write) -> io::Result<()> {
+    let mut data = Vec::new();
+    let kind = match device {
+        Some(device) => {
+            device.save(&mut data)?;
+            device.kind()
+        },
+        None => InputDevice::None,
+    };
+
+    serde::encode_u8(output, kind as u8)?;
+    serde::encode_vec(output, &data)?;
+    Ok(())
+}
+
+// What's plugged in belongs to the player rather than the state, so a saved
+// device is only loaded if the same kind of device is plugged in now.
+pub fn load_device(device: &mut Option<Box<dyn Device>>, input: &mut dyn Read) -> io::Result<()> {
+    let kind = serde::decode_u8(input)?;
+    let data = serde::decode_vec(input)?;
+
+    if let Some(device) = device {
+        if device.kind() as u8 == kind {
+            device.load(&mut &data[..])?;
+        }
+    }
+
+    Ok(())
+}
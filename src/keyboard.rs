@@ -0,0 +1,225 @@
+// Family BASIC keyboard
+//
+// http://wiki.nesdev.com/w/index.php/Family_BASIC_Keyboard
+//
+// The keyboard plugs into the Famicom's expansion port. Its keys are wired up
+// in a matrix of 9 rows and 2 columns, with 4 keys in each, which the game
+// scans by writing to $4016 and reading $4017.
+//
+// bit    | 7 - 3 |   2    |   1    |   0   |
+// $4016  |       | enable | column | reset |
+//
+// Setting the reset bit goes back to the first row, the column bit picks the
+// column, and moving from column 1 back to column 0 moves on to the next row.
+// The selected keys are then read back from $4017, with a 0 for each key that
+// is pressed.
+//
+// bit    | 7 - 5 | 4 - 1 | 0 |
+// $4017  |       | keys  |   |
+//
+//        | column 0                        | column 1
+//        | 4     3      2       1          | 4       3       2      1
+// row 0  | ]     [      Return  F8         | Stop    Yen     RShift Kana
+// row 1  | ;     :      @       F7         | ^       -       /      _
+// row 2  | K     L      O       F6         | 0       P       ,      .
+// row 3  | J     U      I       F5         | 8       9       N      M
+// row 4  | H     G      Y       F4         | 6       7       V      B
+// row 5  | D     R      T       F3         | 4       5       C      F
+// row 6  | A     S      W       F2         | 3       E       Z      X
+// row 7  | Ctr   Q      Esc     F1         | 2       1       Grph   LShift
+// row 8  | Left  Right  Up      Clr Home   | Ins     Del     Space  Down
+
+use std::io::{Read, Write};
+use std::io;
+
+use crate::frontend::InputEvent;
+use crate::input::{Device, InputDevice, Slot};
+use crate::mem::Memory;
+use crate::ppu::PPU;
+use crate::serde;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Key {
+    A, B, C, D, E, F, G, H, I, J, K, L, M,
+    N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    Num0, Num1, Num2, Num3, Num4, Num5, Num6, Num7, Num8, Num9,
+    F1, F2, F3, F4, F5, F6, F7, F8,
+
+    Minus, Caret, Yen, At, LeftBracket, RightBracket, Semicolon, Colon,
+    Comma, Period, Slash, Underscore,
+
+    Escape, Control, LeftShift, RightShift, Graph, Kana, Stop, Return, Space,
+    ClearHome, Insert, Delete, Up, Down, Left, Right,
+}
+
+const ROWS: usize = 9;
+
+// Each row's keys, for bits 4 to 1 of column 0, and then column 1
+const LAYOUT: [[[Key; 4]; 2]; ROWS] = [
+    [[Key::RightBracket, Key::LeftBracket, Key::Return, Key::F8],
+     [Key::Stop, Key::Yen, Key::RightShift, Key::Kana]],
+    [[Key::Semicolon, Key::Colon, Key::At, Key::F7],
+     [Key::Caret, Key::Minus, Key::Slash, Key::Underscore]],
+    [[Key::K, Key::L, Key::O, Key::F6],
+     [Key::Num0, Key::P, Key::Comma, Key::Period]],
+    [[Key::J, Key::U, Key::I, Key::F5],
+     [Key::Num8, Key::Num9, Key::N, Key::M]],
+    [[Key::H, Key::G, Key::Y, Key::F4],
+     [Key::Num6, Key::Num7, Key::V, Key::B]],
+    [[Key::D, Key::R, Key::T, Key::F3],
+     [Key::Num4, Key::Num5, Key::C, Key::F]],
+    [[Key::A, Key::S, Key::W, Key::F2],
+     [Key::Num3, Key::E, Key::Z, Key::X]],
+    [[Key::Control, Key::Q, Key::Escape, Key::F1],
+     [Key::Num2, Key::Num1, Key::Graph, Key::LeftShift]],
+    [[Key::Left, Key::Right, Key::Up, Key::ClearHome],
+     [Key::Insert, Key::Delete, Key::Space, Key::Down]],
+];
+
+pub struct Keyboard {
+    // The keys that are pressed in each row and column, in the bits they're
+    // read from
+    pressed: [[u8; 2]; ROWS],
+
+    row: usize,
+    column: usize,
+    enabled: bool,
+}
+
+impl Memory for Keyboard {
+    fn write(&mut self, _address: u16, val: u8) {
+        let column = (val >> 1 & 1) as usize;
+
+        if val & 0x01 != 0 {
+            self.row = 0;
+        } else if self.column == 1 && column == 0 && self.row < ROWS {
+            self.row += 1;
+        }
+
+        self.column = column;
+        self.enabled = val & 0x04 != 0;
+    }
+
+    // Like the controllers, only where the scan has got to is saved, and not
+    // the keys that are pressed
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
+        serde::encode_usize(output, self.row)?;
+        serde::encode_usize(output, self.column)?;
+        serde::encode_u8(output, self.enabled as u8)?;
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.row = serde::decode_usize(input)?.min(ROWS);
+        self.column = serde::decode_usize(input)? & 1;
+        self.enabled = serde::decode_u8(input)? != 0;
+        Ok(())
+    }
+}
+
+impl Device for Keyboard {
+    fn kind(&self) -> InputDevice {
+        InputDevice::Keyboard
+    }
+
+    fn slot(&self) -> Slot {
+        Slot::Expansion
+    }
+
+    fn read_port(&mut self, port: usize, _ppu: &PPU) -> u8 {
+        if port == 0 || !self.enabled {
+            return 0;
+        }
+
+        // Past the last row, nothing is pressed
+        match self.pressed.get(self.row) {
+            Some(row) => !row[self.column] & 0x1e,
+            None      => 0x1e,
+        }
+    }
+
+    fn input(&mut self, event: &InputEvent) {
+        if let InputEvent::Key(key, pressed) = *event {
+            self.set_key(key, pressed);
+        }
+    }
+}
+
+impl Keyboard {
+    pub fn new_keyboard() -> Self {
+        Self {
+            pressed: [[0; 2]; ROWS],
+            row: 0,
+            column: 0,
+            enabled: false,
+        }
+    }
+
+    fn set_key(&mut self, key: Key, pressed: bool) {
+        for (row, columns) in LAYOUT.iter().enumerate() {
+            for (column, keys) in columns.iter().enumerate() {
+                if let Some(i) = keys.iter().position(|&k| k == key) {
+                    let bit = 0x10 >> i;
+
+                    if pressed {
+                        self.pressed[row][column] |= bit;
+                    } else {
+                        self.pressed[row][column] &= !bit;
+                    }
+
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Scans the whole keyboard, the way Family BASIC does
+    fn scan(keyboard: &mut Keyboard, ppu: &PPU) -> Vec<u8> {
+        let mut keys = Vec::new();
+
+        keyboard.write(0x4016, 0x05);
+        for _ in 0 .. ROWS {
+            keyboard.write(0x4016, 0x04);
+            keys.push(keyboard.read_port(1, ppu));
+            keyboard.write(0x4016, 0x06);
+            keys.push(keyboard.read_port(1, ppu));
+        }
+
+        keys
+    }
+
+    #[test]
+    fn test_keyboard() {
+        let ppu = PPU::new_nes_ppu();
+        let mut keyboard = Keyboard::new_keyboard();
+
+        assert_eq!(scan(&mut keyboard, &ppu), [0x1e; 18]);
+
+        keyboard.input(&InputEvent::Key(Key::Return, true));
+        keyboard.input(&InputEvent::Key(Key::Down, true));
+        keyboard.input(&InputEvent::Key(Key::A, true));
+
+        let keys = scan(&mut keyboard, &ppu);
+        assert_eq!(keys[0], 0x1a);
+        assert_eq!(keys[12], 0x0e);
+        assert_eq!(keys[17], 0x1c);
+        assert_eq!(keys.iter().filter(|&&k| k == 0x1e).count(), 15);
+
+        // Past the last row
+        keyboard.write(0x4016, 0x04);
+        assert_eq!(keyboard.read_port(1, &ppu), 0x1e);
+
+        // Nothing can be read while the keyboard is disabled
+        keyboard.write(0x4016, 0x00);
+        assert_eq!(keyboard.read_port(1, &ppu), 0x00);
+
+        keyboard.input(&InputEvent::Key(Key::A, false));
+        let keys = scan(&mut keyboard, &ppu);
+        assert_eq!(keys[12], 0x1e);
+    }
+}
@@ -0,0 +1,40 @@
+#[macro_use] extern crate log;
+#[macro_use] extern crate lazy_static;
+
+mod apu;
+mod console;
+mod controller;
+mod cpu;
+mod debugger;
+mod frontend;
+mod input;
+mod mapper;
+mod mem;
+mod movie;
+mod multitap;
+mod ines;
+mod keyboard;
+mod ppu;
+mod palette;
+mod power_pad;
+mod rewind;
+mod savestate;
+mod serde;
+mod symbols;
+mod vaus;
+mod zapper;
+
+pub use crate::console::{Console, StepResult, NES_PPU_DEBUG, SAVE_SLOTS};
+pub use crate::controller::Button;
+pub use crate::cpu::{disassemble_rom_bank, TraceFormat, TraceOptions};
+pub use crate::frontend::{AudioSink, Hotkey, InputEvent, InputSource, Null, VideoSink};
+pub use crate::ines::CartridgeError;
+pub use crate::input::InputDevice;
+pub use crate::keyboard::Key;
+pub use crate::movie::{Frame, Movie, MovieError, MovieStart};
+pub use crate::multitap::Multitap;
+pub use crate::palette::Color;
+pub use crate::ppu::DebugView;
+pub use crate::rewind::RewindSettings;
+pub use crate::savestate::SaveStateError;
+pub use crate::symbols::{SymbolError, Symbols};
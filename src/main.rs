@@ -1,30 +1,166 @@
 #[macro_use] extern crate log;
-#[macro_use] extern crate lazy_static;
-
-mod apu;
-mod console;
-mod controller;
-mod cpu;
-mod mapper;
-mod mem;
-mod ines;
-mod ppu;
-mod palette;
-mod serde;
+
+mod bindings;
+mod sdl;
 
 use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
 use std::process;
 
-use crate::console::Console;
-use crate::ines::CartridgeError;
+use nes::{disassemble_rom_bank, CartridgeError, Console, InputDevice, MovieStart, Multitap, RewindSettings,
+          Symbols, TraceOptions};
+
+enum MovieOption {
+    Record(String),
+    Play(String),
+}
 
 fn main() {
-    env_logger::init();
+    // The console reports saving, loading and the like through the log, so
+    // its messages are shown unless RUST_LOG says otherwise
+    env_logger::init_from_env(env_logger::Env::default().filter_or("RUST_LOG", "warn,nes::console=info"));
+
+    let mut rom = None;
+    let mut movie = None;
+    let mut multitap = Multitap::None;
+    let mut device = InputDevice::None;
+    let mut debug = false;
+    let mut disassemble = None;
+    let mut trace = None;
+    let mut trace_options = TraceOptions::default();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--record-movie" | "--play-movie" => {
+                let path = match args.next() {
+                    Some(path) => path,
+                    None       => {
+                        println!("Missing required parameter: a path to a movie file after {}.", arg);
+                        process::exit(1);
+                    },
+                };
+
+                movie = Some(if arg == "--record-movie" {
+                    MovieOption::Record(path)
+                } else {
+                    MovieOption::Play(path)
+                });
+            },
+            "--disassemble" => {
+                let bank = args.next().and_then(|bank| bank.parse::<usize>().ok());
+                let path = args.next();
 
-    if let Some(rom) = env::args().skip(1).next() {
+                match (bank, path) {
+                    (Some(bank), Some(path)) => disassemble = Some((bank, path)),
+                    _ => {
+                        println!("Missing required parameters: a PRG-ROM bank number and an output path after {}.", arg);
+                        process::exit(1);
+                    },
+                }
+            },
+            "--trace" => {
+                match args.next() {
+                    Some(path) => trace = Some(path),
+                    None       => {
+                        println!("Missing required parameter: a path to a trace log after {}.", arg);
+                        process::exit(1);
+                    },
+                }
+            },
+            "--trace-options" => {
+                match TraceOptions::parse(&args.next().unwrap_or_default()) {
+                    Ok(options) => trace_options = options,
+                    Err(e)      => {
+                        println!("Invalid trace options: {}", e);
+                        process::exit(1);
+                    },
+                }
+            },
+            "--four-score"      => multitap = Multitap::FourScore,
+            "--famicom-adapter" => multitap = Multitap::Famicom,
+            "--zapper"          => device = InputDevice::Zapper,
+            "--vaus"            => device = InputDevice::Vaus,
+            "--famicom-vaus"    => device = InputDevice::FamicomVaus,
+            "--power-pad"       => device = InputDevice::PowerPad,
+            "--keyboard"        => device = InputDevice::Keyboard,
+            "--debug"           => debug = true,
+            _ => rom = Some(arg),
+        }
+    }
+
+    if let (Some(rom), Some((bank, path))) = (&rom, disassemble) {
+        let symbols = match Symbols::load_for_rom(Path::new(rom)) {
+            Ok(symbols) => symbols,
+            Err(e)      => {
+                println!("Unable to load symbols for {}: {}", rom, e);
+                process::exit(1);
+            },
+        };
+
+        let result = File::create(&path).and_then(|file| {
+            let mut output = BufWriter::new(file);
+            disassemble_rom_bank(rom, bank, &symbols, &mut output)?;
+            output.flush()
+        });
+
+        if let Err(e) = result {
+            println!("Unable to disassemble bank {} of {} to {}: {}", bank, rom, path, e);
+            process::exit(1);
+        }
+
+        return;
+    }
+
+    if let Some(rom) = rom {
         match Console::new_nes_console(&rom) {
             Ok(mut console) => {
-                console.power_up();
+                console.set_rewind(Some(RewindSettings::default()));
+                console.set_multitap(multitap);
+                console.set_input_device(device);
+
+                console.set_trace_options(trace_options);
+                if let Some(path) = trace {
+                    console.set_trace_path(&path);
+                    if let Err(e) = console.start_trace() {
+                        println!("Unable to trace to {}: {}", path, e);
+                        process::exit(1);
+                    }
+                }
+
+                if debug {
+                    console.debug();
+                }
+
+                match movie {
+                    Some(MovieOption::Record(path)) => {
+                        console.set_movie_path(&path);
+                        if let Err(e) = console.record_movie(MovieStart::PowerOn) {
+                            println!("Unable to record movie {}: {}", path, e);
+                            process::exit(1);
+                        }
+                    },
+                    Some(MovieOption::Play(path)) => {
+                        console.set_movie_path(&path);
+                        if let Err(e) = console.load_movie() {
+                            println!("Unable to play movie {}: {}", path, e);
+                            process::exit(1);
+                        }
+                    },
+                    None => { },
+                }
+
+                let (sdl_context, canvas) = sdl::init();
+                let texture_creator = canvas.texture_creator();
+
+                let mut video = sdl::Video::new(canvas, &texture_creator);
+                let mut audio = sdl::Audio::new(&sdl_context);
+                let mut input = sdl::Input::new(&sdl_context, bindings::Bindings::load());
+                input.set_keyboard(device == InputDevice::Keyboard);
+
+                console.run(&mut video, &mut audio, &mut input);
             },
             Err(CartridgeError::IO(io_e)) => {
                 println!("There was an error reading ROM data from {}: {}", rom, io_e);
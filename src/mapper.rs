@@ -10,8 +10,8 @@ mod mapper68;
 mod mapper69;
 
 use std::collections::HashSet;
+use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
 
 pub use mapper0::Mapper0;
 pub use mapper1::Mapper1;
@@ -73,7 +73,7 @@ pub enum MapperEvent {
     VRAMAddressChange(u16),
 }
 
-pub trait Mapper {
+pub trait Mapper: Send {
     // The mirroring mode to use
     fn mirror_mode(&self) -> &MirrorMode { &MirrorMode::Vertical }
 
@@ -91,7 +91,29 @@ pub trait Mapper {
     // Called on particular events, resulting in an observer-like pattern.
     fn notify(&mut self, _event: MapperEvent) { }
 
+    // The cartridge's PRG-RAM, if it has any, so that it can be persisted when
+    // it's battery backed.
+    fn prg_ram(&mut self) -> Option<&mut [u8]> { None }
+
+    // Where in PRG-ROM an address from $8000 up reads from right now
+    fn prg_rom_index(&self, address: u16) -> usize;
+
+    // Where in PRG-ROM any CPU address is mapped to right now, for debugging
+    // tools that need to tell the banks apart
+    fn prg_rom_offset(&self, address: u16) -> Option<usize> {
+        match address {
+            0x8000 ..= 0xffff => Some(self.prg_rom_index(address)),
+            _                 => None,
+        }
+    }
+
     // Serialisation and deserialisation to save states
-    fn save(&self, output: &mut File) -> io::Result<()>;
-    fn load(&mut self, input: &mut File) -> io::Result<()>;
+    fn save(&self, output: &mut dyn Write) -> io::Result<()>;
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()>;
+
+    // Loads a save state section that was saved with `version' of the layout,
+    // like `Memory::load_version'
+    fn load_version(&mut self, input: &mut dyn Read, _version: u16) -> io::Result<()> {
+        self.load(input)
+    }
 }
@@ -1,7 +1,6 @@
 use std::collections::HashSet;
 use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
 
 use crate::mapper::Mapper;
 use crate::mapper::MirrorMode;
@@ -35,7 +34,7 @@ impl Mapper for Mapper0 {
                 self.chr_rom[address as usize % len]
             },
             0x6000 ..= 0x7fff => self.prg_ram[address as usize - 0x6000],
-            0x8000 ..= 0xffff => self.prg_rom[address as usize % self.prg_rom.len()],
+            0x8000 ..= 0xffff => self.prg_rom[self.prg_rom_index(address)],
             _ => 0,
         }
     }
@@ -53,17 +52,25 @@ impl Mapper for Mapper0 {
         }
     }
 
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn prg_rom_index(&self, address: u16) -> usize {
+        address as usize % self.prg_rom.len()
+    }
+
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         serde::encode_vec(output, &self.chr_rom)?;
         serde::encode_vec(output, &self.prg_rom)?;
-        output.write(&self.prg_ram)?;
+        output.write_all(&self.prg_ram)?;
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.chr_rom = serde::decode_vec(input)?;
         self.prg_rom = serde::decode_vec(input)?;
-        input.read(&mut self.prg_ram)?;
+        input.read_exact(&mut self.prg_ram)?;
         Ok(())
     }
 }
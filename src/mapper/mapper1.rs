@@ -1,9 +1,8 @@
 use std::collections::HashSet;
 use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
 
-use crate::mapper::Mapper;
+use crate::mapper::{Mapper, MapperEvent};
 use crate::mapper::MirrorMode;
 use crate::serde;
 
@@ -27,6 +26,12 @@ pub struct Mapper1 {
     shift_register: u8,
     write_count: u8,
 
+    // The MMC1 ignores writes to its shift register on consecutive CPU
+    // cycles, which matters for the double write of read-modify-write
+    // instructions, so we keep track of when the last one happened.
+    cpu_cycles: u64,
+    last_write: Option<u64>,
+
     // The number of PRG-ROM banks in this cartridge
     n_banks: usize,
 
@@ -54,6 +59,8 @@ impl Mapper1 {
 
             shift_register: 0,
             write_count: 0,
+            cpu_cycles: 0,
+            last_write: None,
             n_banks: n_banks,
 
             mirror_mode: MirrorMode::from_hv01(mirror_mode),
@@ -62,6 +69,13 @@ impl Mapper1 {
     }
 
     fn load_register(&mut self, address: u16, val: u8) {
+        let consecutive = self.last_write == Some(self.cpu_cycles.wrapping_sub(1));
+        self.last_write = Some(self.cpu_cycles);
+
+        if consecutive {
+            return;
+        }
+
         if val & 0x80 == 0x80 {
             self.shift_register = 0;
             self.control = 3 << 2;
@@ -151,28 +165,7 @@ impl Mapper for Mapper1 {
             },
 
             // PRG-ROM
-            0x8000 ..= 0xbfff => {
-                let bank = match self.prg_mode() {
-                    0 | 1 => self.prg_bank as usize & 0xfe,
-                    2     => 0,
-                    3     => self.prg_bank as usize,
-                    _     => panic!("bad prg_mode"),
-                };
-                let index = ((PRG_BANK_SIZE * bank) | (address as usize & 0x3fff)) % self.prg_rom.len();
-
-                self.prg_rom[index]
-            },
-            0xc000 ..= 0xffff => {
-                let bank = match self.prg_mode() {
-                    0 | 1 => (self.prg_bank as usize & 0xfe) | 1,
-                    2     => self.prg_bank as usize,
-                    3     => self.n_banks - 1,
-                    _     => panic!("bad prg_mode"),
-                };
-                let index = ((PRG_BANK_SIZE * bank) | (address as usize & 0x3fff)) % self.prg_rom.len();
-
-                self.prg_rom[index]
-            },
+            0x8000 ..= 0xffff => self.prg_rom[self.prg_rom_index(address)],
 
             _ => 0,
         }
@@ -212,31 +205,62 @@ impl Mapper for Mapper1 {
         }
     }
 
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn notify(&mut self, event: MapperEvent) {
+        if let MapperEvent::CPUTick(cycles) = event {
+            self.cpu_cycles += cycles;
+        }
+    }
+
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn prg_rom_index(&self, address: u16) -> usize {
+        let bank = match (address, self.prg_mode()) {
+            (0x8000 ..= 0xbfff, 0 | 1) => self.prg_bank as usize & 0xfe,
+            (0x8000 ..= 0xbfff, 2)     => 0,
+            (0x8000 ..= 0xbfff, 3)     => self.prg_bank as usize,
+            (_, 0 | 1)                 => (self.prg_bank as usize & 0xfe) | 1,
+            (_, 2)                     => self.prg_bank as usize,
+            (_, 3)                     => self.n_banks - 1,
+            _                          => panic!("bad prg_mode"),
+        };
+
+        ((PRG_BANK_SIZE * bank) | (address as usize & 0x3fff)) % self.prg_rom.len()
+    }
+
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         serde::encode_vec(output, &self.chr_rom)?;
         serde::encode_vec(output, &self.prg_rom)?;
-        output.write(&self.prg_ram)?;
+        output.write_all(&self.prg_ram)?;
         serde::encode_u8(output, self.control)?;
         serde::encode_u8(output, self.chr_bank0)?;
         serde::encode_u8(output, self.chr_bank1)?;
         serde::encode_u8(output, self.prg_bank)?;
         serde::encode_u8(output, self.shift_register)?;
         serde::encode_u8(output, self.write_count)?;
+        serde::encode_u64(output, self.cpu_cycles)?;
+        serde::encode_u8(output, self.last_write.is_some() as u8)?;
+        serde::encode_u64(output, self.last_write.unwrap_or(0))?;
         serde::encode_usize(output, self.n_banks)?;
         serde::encode_u8(output, self.mirror_mode as u8)?;
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.chr_rom = serde::decode_vec(input)?;
         self.prg_rom = serde::decode_vec(input)?;
-        input.read(&mut self.prg_ram)?;
+        input.read_exact(&mut self.prg_ram)?;
         self.control = serde::decode_u8(input)?;
         self.chr_bank0 = serde::decode_u8(input)?;
         self.chr_bank1 = serde::decode_u8(input)?;
         self.prg_bank = serde::decode_u8(input)?;
         self.shift_register = serde::decode_u8(input)?;
         self.write_count = serde::decode_u8(input)?;
+        self.cpu_cycles = serde::decode_u64(input)?;
+        let has_last_write = serde::decode_u8(input)? != 0;
+        let last_write = serde::decode_u64(input)?;
+        self.last_write = if has_last_write { Some(last_write) } else { None };
         self.n_banks = serde::decode_usize(input)?;
         self.mirror_mode = MirrorMode::from_hv01(serde::decode_u8(input)?);
         Ok(())
@@ -1,7 +1,6 @@
 use std::collections::HashSet;
 use std::io;
 use std::io::{Read, Write};
-use std::fs::File;
 
 use crate::mapper::Mapper;
 use crate::mapper::MirrorMode;
@@ -62,16 +61,7 @@ impl Mapper for Mapper2 {
             0x6000 ..= 0x7fff => self.prg_ram[address as usize & 0x1fff],
 
             // PRG-ROM
-            0x8000 ..= 0xbfff => {
-                let bank = (self.prg_bank1 as usize) * PRG_BANK_SIZE;
-                let index = bank | (address as usize & 0x3fff);
-                self.prg_rom[index]
-            },
-            0xc000 ..= 0xffff => {
-                let bank = (self.prg_bank2 as usize) * PRG_BANK_SIZE;
-                let index = bank | (address as usize & 0x3fff);
-                self.prg_rom[index]
-            },
+            0x8000 ..= 0xffff => self.prg_rom[self.prg_rom_index(address)],
 
             _ => 0,
         }
@@ -92,20 +82,33 @@ impl Mapper for Mapper2 {
         }
     }
 
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn prg_rom_index(&self, address: u16) -> usize {
+        let bank = match address {
+            0x8000 ..= 0xbfff => self.prg_bank1,
+            _                 => self.prg_bank2,
+        };
+
+        (bank as usize * PRG_BANK_SIZE) | (address as usize & 0x3fff)
+    }
+
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         serde::encode_vec(output, &self.chr_rom)?;
         serde::encode_vec(output, &self.prg_rom)?;
-        output.write(&self.prg_ram)?;
+        output.write_all(&self.prg_ram)?;
         serde::encode_u8(output, self.prg_bank1)?;
         serde::encode_u8(output, self.prg_bank2)?;
         serde::encode_usize(output, self.n_banks)?;
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.chr_rom = serde::decode_vec(input)?;
         self.prg_rom = serde::decode_vec(input)?;
-        input.read(&mut self.prg_ram)?;
+        input.read_exact(&mut self.prg_ram)?;
         self.prg_bank1 = serde::decode_u8(input)?;
         self.prg_bank2 = serde::decode_u8(input)?;
         self.n_banks   = serde::decode_usize(input)?;
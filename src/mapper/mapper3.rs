@@ -1,6 +1,6 @@
 use std::collections::HashSet;
+use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
 
 use crate::mapper::Mapper;
 use crate::mapper::MirrorMode;
@@ -54,7 +54,7 @@ impl Mapper for Mapper3 {
             },
 
             // PRG-ROM
-            0x8000 ..= 0xffff => self.prg_rom[address as usize - 0x8000],
+            0x8000 ..= 0xffff => self.prg_rom[self.prg_rom_index(address)],
 
             _ => 0,
         }
@@ -79,14 +79,18 @@ impl Mapper for Mapper3 {
         }
     }
 
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn prg_rom_index(&self, address: u16) -> usize {
+        address as usize - 0x8000
+    }
+
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         serde::encode_vec(output, &self.chr_rom)?;
         serde::encode_vec(output, &self.prg_rom)?;
         serde::encode_u8(output, self.chr_bank)?;
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.chr_rom = serde::decode_vec(input)?;
         self.prg_rom = serde::decode_vec(input)?;
         self.chr_bank = serde::decode_u8(input)?;
@@ -1,6 +1,6 @@
 use std::collections::HashSet;
+use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
 
 use crate::mapper::Mapper;
 use crate::mapper::MirrorMode;
@@ -99,11 +99,7 @@ impl Mapper for Mapper34 {
                     0x6000 ..= 0x7fff => self.prg_ram[address as usize & 0x1fff],
 
                     // PRG-ROM
-                    0x8000 ..= 0xffff => {
-                        let base = (self.prg_bank as usize) * PRG_BANK_SIZE;
-                        let index = base | (address as usize & 0x7fff);
-                        self.prg_rom[index]
-                    },
+                    0x8000 ..= 0xffff => self.prg_rom[self.prg_rom_index(address)],
 
                     _ => 0,
                 }
@@ -115,12 +111,7 @@ impl Mapper for Mapper34 {
                     0x0000 ..= 0x1fff => self.chr_rom[address as usize],
 
                     // PRG-ROM
-                    0x8000 ..= 0xffff => {
-                        let index = (self.prg_bank as usize * PRG_BANK_SIZE)
-                            | (address as usize & 0x7fff);
-
-                        self.prg_rom[index]
-                    },
+                    0x8000 ..= 0xffff => self.prg_rom[self.prg_rom_index(address)],
 
                     _ => 0,
                 }
@@ -184,7 +175,16 @@ impl Mapper for Mapper34 {
         }
     }
 
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    // The same for both boards
+    fn prg_rom_index(&self, address: u16) -> usize {
+        (self.prg_bank as usize * PRG_BANK_SIZE) | (address as usize & 0x7fff)
+    }
+
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         serde::encode_vec(output, &self.chr_rom)?;
         serde::encode_vec(output, &self.prg_rom)?;
         serde::encode_u8(output, self.prg_bank)?;
@@ -194,7 +194,7 @@ impl Mapper for Mapper34 {
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.chr_rom = serde::decode_vec(input)?;
         self.prg_rom = serde::decode_vec(input)?;
         self.prg_bank = serde::decode_u8(input)?;
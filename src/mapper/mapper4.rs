@@ -1,7 +1,6 @@
 use std::collections::HashSet;
 use std::io;
 use std::io::{Read, Write};
-use std::fs::File;
 
 use crate::mapper::{Mapper, MapperEvent};
 use crate::mapper::MirrorMode;
@@ -123,40 +122,7 @@ impl Mapper for Mapper4 {
             0x6000 ..= 0x7fff => self.prg_ram[address as usize - 0x6000],
 
             // PRG-ROM
-            0x8000 ..= 0x9fff => {
-                let bank = if self.prg_mode {
-                    self.n_prg_banks - 2
-                } else {
-                    self.regs[6]
-                };
-
-                let offset = address as usize & 0x1fff;
-                let index = ((PRG_BANK_SIZE * bank) | offset) % self.prg_rom.len();
-                self.prg_rom[index]
-            },
-            0xa000 ..= 0xbfff => {
-                let bank = self.regs[7];
-                let offset = address as usize & 0x1fff;
-                let index = ((PRG_BANK_SIZE * bank) | offset) % self.prg_rom.len();
-                self.prg_rom[index]
-            },
-            0xc000 ..= 0xdfff => {
-                let bank = if self.prg_mode {
-                    self.regs[6]
-                } else {
-                    self.n_prg_banks - 2
-                };
-
-                let offset = address as usize & 0x1fff;
-                let index = ((PRG_BANK_SIZE * bank) | offset) % self.prg_rom.len();
-                self.prg_rom[index]
-            },
-            0xe000 ..= 0xffff => {
-                let bank = self.n_prg_banks - 1;
-                let offset = address as usize & 0x1fff;
-                let index = ((PRG_BANK_SIZE * bank) | offset) % self.prg_rom.len();
-                self.prg_rom[index]
-            },
+            0x8000 ..= 0xffff => self.prg_rom[self.prg_rom_index(address)],
 
             _ => 0,
         }
@@ -252,10 +218,28 @@ impl Mapper for Mapper4 {
         }
     }
 
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn prg_rom_index(&self, address: u16) -> usize {
+        let bank = match (address, self.prg_mode) {
+            (0x8000 ..= 0x9fff, false) => self.regs[6],
+            (0x8000 ..= 0x9fff, true)  => self.n_prg_banks - 2,
+            (0xa000 ..= 0xbfff, _)     => self.regs[7],
+            (0xc000 ..= 0xdfff, false) => self.n_prg_banks - 2,
+            (0xc000 ..= 0xdfff, true)  => self.regs[6],
+            _                          => self.n_prg_banks - 1,
+        };
+
+        let offset = address as usize & 0x1fff;
+        ((PRG_BANK_SIZE * bank) | offset) % self.prg_rom.len()
+    }
+
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         serde::encode_vec(output, &self.chr_rom)?;
         serde::encode_vec(output, &self.prg_rom)?;
-        output.write(&self.prg_ram)?;
+        output.write_all(&self.prg_ram)?;
         serde::encode_u8(output, self.mirror_mode as u8)?;
         serde::encode_usize(output, self.n_prg_banks)?;
 
@@ -270,14 +254,16 @@ impl Mapper for Mapper4 {
         serde::encode_u8(output, self.irq_period)?;
         serde::encode_u8(output, self.irq_enabled as u8)?;
         serde::encode_u8(output, self.irq_flag as u8)?;
+        serde::encode_u8(output, self.irq_reload as u8)?;
+        serde::encode_u16(output, self.last_vram_addr)?;
 
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.chr_rom = serde::decode_vec(input)?;
         self.prg_rom = serde::decode_vec(input)?;
-        input.read(&mut self.prg_ram)?;
+        input.read_exact(&mut self.prg_ram)?;
         self.mirror_mode = MirrorMode::from_hv01(serde::decode_u8(input)?);
         self.n_prg_banks = serde::decode_usize(input)?;
 
@@ -292,6 +278,8 @@ impl Mapper for Mapper4 {
         self.irq_period = serde::decode_u8(input)?;
         self.irq_enabled = serde::decode_u8(input)? != 0;
         self.irq_flag = serde::decode_u8(input)? != 0;
+        self.irq_reload = serde::decode_u8(input)? != 0;
+        self.last_vram_addr = serde::decode_u16(input)?;
 
         Ok(())
     }
@@ -1,6 +1,6 @@
 use std::collections::HashSet;
+use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
 
 use crate::mapper::Mapper;
 use crate::mapper::MirrorMode;
@@ -68,11 +68,7 @@ impl Mapper for Mapper66 {
             },
 
             // PRG-ROM
-            0x8000 ..= 0xffff => {
-                let bank = self.prg_bank as usize;
-                let index = ((PRG_BANK_SIZE * bank) | address as usize & 0x7fff) % self.prg_rom.len();
-                self.prg_rom[index]
-            },
+            0x8000 ..= 0xffff => self.prg_rom[self.prg_rom_index(address)],
 
             _ => 0,
         }
@@ -98,7 +94,12 @@ impl Mapper for Mapper66 {
         }
     }
 
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn prg_rom_index(&self, address: u16) -> usize {
+        let bank = self.prg_bank as usize;
+        ((PRG_BANK_SIZE * bank) | address as usize & 0x7fff) % self.prg_rom.len()
+    }
+
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         serde::encode_vec(output, &self.chr_rom)?;
         serde::encode_vec(output, &self.prg_rom)?;
         serde::encode_u8(output, self.chr_bank)?;
@@ -108,7 +109,7 @@ impl Mapper for Mapper66 {
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.chr_rom = serde::decode_vec(input)?;
         self.prg_rom = serde::decode_vec(input)?;
         self.chr_bank = serde::decode_u8(input)?;
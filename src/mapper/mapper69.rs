@@ -2,7 +2,6 @@ use std::collections::HashSet;
 use std::convert::From;
 use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
 
 use crate::mapper::{Mapper, MapperEvent};
 use crate::mapper::MirrorMode;
@@ -254,12 +253,7 @@ impl Mapper for Mapper69 {
             },
 
             // PRG-ROM
-            0x8000 ..= 0xffff => {
-                let reg = (address as usize - 0x8000) / PRG_BANK_SIZE;
-                let index = (self.prg_banks[reg] * PRG_BANK_SIZE)
-                    | (address as usize & 0x1fff);
-                self.prg_rom[index & (self.prg_rom.len() - 1)]
-            },
+            0x8000 ..= 0xffff => self.prg_rom[self.prg_rom_index(address)],
 
             _ => 0,
         }
@@ -304,10 +298,21 @@ impl Mapper for Mapper69 {
         }
     }
 
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn prg_ram(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.sram)
+    }
+
+    fn prg_rom_index(&self, address: u16) -> usize {
+        let reg = (address as usize - 0x8000) / PRG_BANK_SIZE;
+        let index = (self.prg_banks[reg] * PRG_BANK_SIZE)
+            | (address as usize & 0x1fff);
+        index & (self.prg_rom.len() - 1)
+    }
+
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         serde::encode_vec(output, &self.chr_rom)?;
         serde::encode_vec(output, &self.prg_rom)?;
-        output.write(&self.sram)?;
+        output.write_all(&self.sram)?;
         serde::encode_u8(output, self.mirror_mode as u8)?;
 
         match self.cmd {
@@ -343,14 +348,15 @@ impl Mapper for Mapper69 {
         serde::encode_u8(output, self.irq_enabled as u8)?;
         serde::encode_u8(output, self.irq_counter_enabled as u8)?;
         serde::encode_u16(output, self.irq_counter_value)?;
+        serde::encode_u8(output, self.irq_flag as u8)?;
 
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.chr_rom = serde::decode_vec(input)?;
         self.prg_rom = serde::decode_vec(input)?;
-        input.read(&mut self.sram)?;
+        input.read_exact(&mut self.sram)?;
         self.mirror_mode = MirrorMode::from_vh01(serde::decode_u8(input)?);
 
         let cmd = serde::decode_u8(input)?;
@@ -387,6 +393,7 @@ impl Mapper for Mapper69 {
         self.irq_enabled = serde::decode_u8(input)? != 0;
         self.irq_counter_enabled = serde::decode_u8(input)? != 0;
         self.irq_counter_value = serde::decode_u16(input)?;
+        self.irq_flag = serde::decode_u8(input)? != 0;
 
         Ok(())
     }
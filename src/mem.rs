@@ -1,25 +1,48 @@
-use std::cell::RefCell;
-use std::fs::File;
 use std::io::{Read, Write};
 use std::io;
-use std::rc::Rc;
+use std::vec::Drain;
 
 use crate::apu::APU;
 use crate::controller::Controller;
+use crate::input::{Device, Slot};
+use crate::mapper::{Mapper, MapperEvent};
+use crate::multitap::{FourScore, Multitap};
 use crate::ppu::PPU;
 
 pub trait Memory {
     fn read(&mut self, _address: u16) -> u8 { 0 }
     fn write(&mut self, _address: u16, _val: u8) { }
-    fn save(&self, _output: &mut File) -> io::Result<()> { Ok(()) }
-    fn load(&mut self, _input: &mut File) -> io::Result<()> { Ok(()) }
+    fn save(&self, _output: &mut dyn Write) -> io::Result<()> { Ok(()) }
+    fn load(&mut self, _input: &mut dyn Read) -> io::Result<()> { Ok(()) }
+
+    // Loads from a save state section that was saved with `version' of the
+    // layout. Only components whose layout has changed since the first
+    // version need to read anything but the current one.
+    fn load_version(&mut self, input: &mut dyn Read, _version: u16) -> io::Result<()> {
+        self.load(input)
+    }
 }
 
+// The system bus. This owns every component that is reachable from the CPU's
+// address space, dispatches reads and writes to them, and keeps them in step
+// with the CPU.
 pub struct NESMemory {
-    ppu:        Rc<RefCell<PPU>>,
-    apu:        Rc<RefCell<APU>>,
-    controller: Rc<RefCell<Controller>>,
-    ram:        [u8; 0x800],
+    pub ppu:        PPU,
+    pub apu:        APU,
+    pub controllers: [Controller; 4],
+    pub multitap:   Multitap,
+    pub four_score: FourScore,
+    pub device:     Option<Box<dyn Device>>,
+    pub mapper:     Box<dyn Mapper>,
+    ram:            [u8; 0x800],
+
+    // Signals raised by the components while they were being ticked, which
+    // are collected by the CPU and the console.
+    nmi:            bool,
+    irq:            bool,
+    stall:          u64,
+    frame_finished: bool,
+    samples:        Vec<f32>,
 }
 
 impl Memory for NESMemory {
@@ -31,31 +54,31 @@ impl Memory for NESMemory {
 
             // The PPU registers exist from 0x2000 to 0x2007, the rest of the
             // address space is just a mirror of these first eight bytes.
-            0x2000 ..= 0x3fff => self.ppu.borrow_mut().read(address),
+            0x2000 ..= 0x3fff => self.ppu.read_register(&mut *self.mapper, address),
 
             // APU registers
-            0x4000 ..= 0x4013 => self.apu.borrow_mut().read(address),
+            0x4000 ..= 0x4013 => self.apu.read(address),
 
             // OAM DMA
             0x4014            => 0,
 
             // APU registers
-            0x4015            => self.apu.borrow_mut().read(address),
+            0x4015            => self.apu.read(address),
 
             // Controller 1
-            0x4016            => self.controller.borrow_mut().read(address),
+            0x4016            => self.read_port(0, address),
 
             // Controller 2
-            0x4017            => 0,
+            0x4017            => self.read_port(1, address),
 
             // Expansion ROM
             0x4020 ..= 0x5fff => 0,
 
             // SRAM
-            0x6000 ..= 0x7fff => self.ppu.borrow_mut().data.mapper.borrow_mut().read(address),
+            0x6000 ..= 0x7fff => self.mapper.read(address),
 
             // PRG-ROM
-            0x8000 ..= 0xffff => self.ppu.borrow_mut().data.mapper.borrow_mut().read(address),
+            0x8000 ..= 0xffff => self.mapper.read(address),
 
             _ => unreachable!("read out of bounds 0x{:04X}", address),
         }
@@ -67,94 +90,281 @@ impl Memory for NESMemory {
             0x0000 ..= 0x1fff => { self.ram[(address as usize) % 0x800] = val; },
 
             // PPU registers
-            0x2000 ..= 0x3fff => self.ppu.borrow_mut().write(address, val),
+            0x2000 ..= 0x3fff => self.ppu.write_register(&mut *self.mapper, address, val),
 
             // APU registers
-            0x4000 ..= 0x4013 => self.apu.borrow_mut().write(address, val),
+            0x4000 ..= 0x4013 => self.apu.write(address, val),
 
             // OAM DMA
             0x4014            => unreachable!("this should've been intercepted by the CPU"),
 
             // APU registers
-            0x4015            => self.apu.borrow_mut().write(address, val),
-
-            // Controller 1
-            0x4016            => self.controller.borrow_mut().write(address, val),
-
-            // Controller 2
-            0x4017            => { },
+            0x4015            => self.apu.write(address, val),
+
+            // All of the controllers are strobed by the same write
+            0x4016            => {
+                for controller in self.controllers.iter_mut() {
+                    controller.write(address, val);
+                }
+                self.four_score.write(address, val);
+                if let Some(device) = &mut self.device {
+                    device.write(address, val);
+                }
+            },
+
+            // APU frame counter, since controller 2 is read only
+            0x4017            => self.apu.write(address, val),
 
             // Expansion ROM
             0x4020 ..= 0x5fff => { },
 
             // SRAM
-            0x6000 ..= 0x7fff => self.ppu.borrow_mut().data.mapper.borrow_mut().write(address, val),
+            0x6000 ..= 0x7fff => self.mapper.write(address, val),
 
             // PRG-ROM
-            0x8000 ..= 0xffff => self.ppu.borrow_mut().data.mapper.borrow_mut().write(address, val),
+            0x8000 ..= 0xffff => self.mapper.write(address, val),
 
             _ => unreachable!("write out of bounds 0x{:04X}", address),
         }
     }
 
-    fn save(&self, output: &mut File) -> io::Result<()> {
-        output.write(&self.ram)?;
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
+        output.write_all(&self.ram)?;
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
-        input.read(&mut self.ram)?;
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        input.read_exact(&mut self.ram)?;
         Ok(())
     }
 }
 
 impl NESMemory {
-    pub fn new_nes_mem(ppu: Rc<RefCell<PPU>>,
-                       apu: Rc<RefCell<APU>>,
-                       controller: Rc<RefCell<Controller>>)
-        -> Self
-    {
+    pub fn new_nes_mem(mapper: Box<dyn Mapper>) -> Self {
         Self {
-            ppu: ppu,
-            apu: apu,
-            controller: controller,
-            ram: [0; 0x800],
+            ppu:            PPU::new_nes_ppu(),
+            apu:            APU::new_nes_apu(),
+            controllers:    [
+                Controller::new_controller(),
+                Controller::new_controller(),
+                Controller::new_controller(),
+                Controller::new_controller(),
+            ],
+            multitap:       Multitap::None,
+            four_score:     FourScore::new_four_score(),
+            device:         None,
+            mapper,
+            ram:            [0; 0x800],
+
+            nmi:            false,
+            irq:            false,
+            stall:          0,
+            frame_finished: false,
+            samples:        Vec::new(),
+        }
+    }
+
+    // Reads from the bus without side effects. The PPU registers read as
+    // they'd be read, and the APU and controller registers as open bus.
+    pub fn peek(&mut self, address: u16) -> u8 {
+        match address {
+            0x2000 ..= 0x3fff => self.ppu.peek_register(address),
+            0x4000 ..= 0x401f => (address >> 8) as u8,
+            _                 => self.read(address),
+        }
+    }
+
+    // Reads $4016 or $4017, from the controllers and whichever other input
+    // device is plugged in.
+    fn read_port(&mut self, port: usize, address: u16) -> u8 {
+        let device = match &mut self.device {
+            Some(device) => device,
+            None         => return self.read_controller(port, address),
+        };
+
+        let value = device.read_port(port, &self.ppu);
+        if device.slot() == Slot::Port2 && port == 1 {
+            return value;
+        }
+
+        value | self.read_controller(port, address)
+    }
+
+    // Reads controller port 0 ($4016) or 1 ($4017), through whichever four
+    // player adapter is plugged in.
+    fn read_controller(&mut self, port: usize, address: u16) -> u8 {
+        match self.multitap {
+            Multitap::None      => self.controllers[port].read(address),
+            Multitap::FourScore => self.four_score.read_port(port, &self.controllers),
+            Multitap::Famicom   => {
+                self.controllers[port].read(address) | self.controllers[port + 2].read(address) << 1
+            },
+        }
+    }
+
+    // Runs the rest of the system for a single CPU cycle. That's three PPU
+    // cycles, and one APU cycle.
+    pub fn tick(&mut self) {
+        self.mapper.notify(MapperEvent::CPUTick(1));
+
+        for _ in 0 .. 3 {
+            let res = self.ppu.step(&mut *self.mapper);
+
+            if self.mapper.irq_flag() {
+                self.irq = true;
+            }
+
+            if res.trigger_nmi {
+                self.nmi = true;
+            }
+
+            if res.frame_finished {
+                self.frame_finished = true;
+            }
+        }
+
+        let res = self.apu.step(&mut *self.mapper);
+
+        if res.trigger_irq {
+            self.irq = true;
+        }
+
+        if let Some(signal) = res.signal {
+            self.samples.push(signal);
         }
+
+        self.stall += res.stall;
+    }
+
+    // Each of these return whether the signal was raised since the last time
+    // it was checked, and clear it.
+    pub fn take_nmi(&mut self) -> bool {
+        std::mem::replace(&mut self.nmi, false)
+    }
+
+    pub fn take_irq(&mut self) -> bool {
+        std::mem::replace(&mut self.irq, false)
+    }
+
+    pub fn take_frame_finished(&mut self) -> bool {
+        std::mem::replace(&mut self.frame_finished, false)
+    }
+
+    pub fn take_stall(&mut self) -> u64 {
+        std::mem::replace(&mut self.stall, 0)
+    }
+
+    pub fn drain_samples(&mut self) -> Drain<'_, f32> {
+        self.samples.drain(..)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::controller::Button;
+    use crate::frontend::InputEvent;
+    use crate::input::InputDevice;
+    use crate::mapper::Mapper0;
+
+    fn new_test_mem(rom: Vec<u8>) -> NESMemory {
+        NESMemory::new_nes_mem(Box::new(Mapper0::new_mapper(rom, vec![0; 0x2000], 0)))
+    }
 
     #[test]
     fn test_read_write() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mut mem = NESMemory::new_nes_mem(ppu, ctrl);
+        let mut mem = new_test_mem(vec![0; 0x8000]);
 
         // RAM
-        assert_eq!(mem.read(0x1000), Ok(0));
-        assert_eq!(mem.write(0x1000, 5), Ok(5));
-        assert_eq!(mem.read(0x1000), Ok(5));
+        assert_eq!(mem.read(0x1000), 0);
+        mem.write(0x1000, 5);
+        assert_eq!(mem.read(0x1000), 5);
 
         // ROM
-        mem.load_rom(&vec![0; 0x8000]);
-        assert_eq!(mem.read(0x8000), Ok(0));
-        assert_eq!(mem.read(0x8001), Ok(0));
-        assert_eq!(mem.read(0xffff), Ok(0));
+        assert_eq!(mem.read(0x8000), 0);
+        assert_eq!(mem.read(0x8001), 0);
+        assert_eq!(mem.read(0xffff), 0);
     }
 
     #[test]
     fn test_load_rom() {
-        let ppu = PPU::new_nes_ppu();
-        let ctrl = Controller::new_controller();
-        let mut mem = NESMemory::new_nes_mem(ppu, ctrl);
-        mem.load_rom(&vec![0; 0x8000]);
-        assert_eq!(mem.read(0x8000), Ok(0));
-        assert_eq!(mem.read(0xffff), Ok(0));
-        mem.load_rom(&vec![1; 0x8000]);
-        assert_eq!(mem.read(0x8000), Ok(1));
-        assert_eq!(mem.read(0xffff), Ok(1));
+        let mut mem = new_test_mem(vec![0; 0x8000]);
+        assert_eq!(mem.read(0x8000), 0);
+        assert_eq!(mem.read(0xffff), 0);
+        let mut mem = new_test_mem(vec![1; 0x8000]);
+        assert_eq!(mem.read(0x8000), 1);
+        assert_eq!(mem.read(0xffff), 1);
+    }
+
+    #[test]
+    fn test_controllers() {
+        let mut mem = new_test_mem(vec![0; 0x8000]);
+        mem.controllers[0].set_button(Button::A, true);
+        mem.controllers[1].set_button(Button::B, true);
+
+        // Strobing $4016 latches both controllers, which are then read out
+        // separately
+        mem.write(0x4016, 1);
+        mem.write(0x4016, 0);
+
+        let one: Vec<u8> = (0 .. 8).map(|_| mem.read(0x4016)).collect();
+        let two: Vec<u8> = (0 .. 8).map(|_| mem.read(0x4017)).collect();
+        assert_eq!(one, [1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(two, [0, 1, 0, 0, 0, 0, 0, 0]);
+
+        // With the Famicom adapter, controllers 3 and 4 are on bit 1
+        mem.multitap = Multitap::Famicom;
+        mem.controllers[2].set_button(Button::A, true);
+        mem.write(0x4016, 1);
+        mem.write(0x4016, 0);
+        assert_eq!(mem.read(0x4016), 3);
+        assert_eq!(mem.read(0x4017), 0);
+        assert_eq!(mem.read(0x4017), 1);
+    }
+
+    #[test]
+    fn test_zapper() {
+        let mut mem = new_test_mem(vec![0; 0x8000]);
+        mem.controllers[1].set_button(Button::A, true);
+        mem.device = InputDevice::Zapper.new_device();
+
+        // The Zapper replaces controller 2, and there's nothing on the screen
+        // for it to see
+        mem.write(0x4016, 1);
+        mem.write(0x4016, 0);
+        assert_eq!(mem.read(0x4017), 0x08);
+
+        let zapper = mem.device.as_mut().unwrap();
+        zapper.input(&InputEvent::Mouse(Some((128, 120))));
+        zapper.input(&InputEvent::MouseButton(true));
+        assert_eq!(mem.read(0x4017), 0x18);
+    }
+
+    #[test]
+    fn test_vaus() {
+        let mut mem = new_test_mem(vec![0; 0x8000]);
+        mem.controllers[0].set_button(Button::A, true);
+        mem.controllers[1].set_button(Button::A, true);
+
+        let events = [InputEvent::Mouse(Some((0, 0))), InputEvent::MouseButton(true)];
+
+        // The NES Vaus replaces controller 2. Its knob is all the way to the
+        // left, at 0x54, which is read out inverted.
+        mem.device = InputDevice::Vaus.new_device();
+        events.iter().for_each(|event| mem.device.as_mut().unwrap().input(event));
+        mem.write(0x4016, 1);
+        mem.write(0x4016, 0);
+        assert_eq!(mem.read(0x4016), 0x01);
+        assert_eq!(mem.read(0x4017), 0x18);
+        assert_eq!(mem.read(0x4017), 0x08);
+
+        // The Famicom one is read alongside both controllers
+        mem.device = InputDevice::FamicomVaus.new_device();
+        events.iter().for_each(|event| mem.device.as_mut().unwrap().input(event));
+        mem.write(0x4016, 1);
+        mem.write(0x4016, 0);
+        assert_eq!(mem.read(0x4016), 0x03);
+        assert_eq!(mem.read(0x4017), 0x03);
+        assert_eq!(mem.read(0x4017), 0x00);
     }
 }
@@ -0,0 +1,523 @@
+// Input movies
+//
+// A movie is the state of the controllers on every frame, along with where the
+// recording started from: either power on, or a save state that's embedded in
+// the movie. Since the emulator is deterministic, playing the input back from
+// the same starting point reproduces the same run exactly.
+//
+// Movies are stored in their own binary format,
+//
+//   magic     4 bytes  "NESM"
+//   version   u16      version of the movie format
+//   checksum  u8       1 if a ROM checksum follows, 0 if not
+//             16 bytes MD5 of the ROM's PRG and CHR data
+//   rom name  vec      the name of the ROM the movie was recorded with
+//   start     u8       1 if a save state follows, 0 for power on
+//             vec      the save state
+//   multitap  u8       0 for none, 1 for a Four Score, 2 for a Famicom adapter
+//   frames    u64      number of frames that follow
+//             4 bytes  each controller's buttons, as the controller sees them
+//             u8       bit 0 for a reset before the frame, and bit 1 for a
+//                      power cycle
+//
+// and can also be imported from and exported to FCEUX's FM2 text format.
+//
+// http://fceux.com/web/help/fm2.html
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{Read, Write};
+use std::io;
+
+use crate::multitap::Multitap;
+use crate::savestate::SaveStateError;
+use crate::serde;
+
+const MAGIC: [u8; 4] = [0x4e, 0x45, 0x53, 0x4d];
+const VERSION: u16 = 1;
+
+const RESET: u8 = 0x01;
+const POWER: u8 = 0x02;
+
+// Where a recording starts from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MovieStart {
+    PowerOn,
+    SaveState,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Frame {
+    // One bit per button for each controller, in the same order as the
+    // controller's shift register
+    pub buttons: [u8; 4],
+
+    // Whether the console was reset, or power cycled, before the frame ran
+    pub reset: bool,
+    pub power: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Movie {
+    // MD5 of the ROM's PRG and CHR data, without the iNES header, which is how
+    // FCEUX identifies ROMs
+    pub rom_checksum: Option<[u8; 16]>,
+    pub rom_name: String,
+
+    // The save state the movie starts from, or None to start from power on
+    pub start: Option<Vec<u8>>,
+
+    // The four player adapter that was plugged in, which is plugged back in
+    // when the movie is played
+    pub multitap: Multitap,
+
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Debug)]
+pub enum MovieError {
+    IO(io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    ROMMismatch,
+    InvalidFM2(usize, String),
+    Unsupported(String),
+    SaveState(SaveStateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::IO(e) => write!(f, "{}", e),
+            MovieError::InvalidMagic => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(v) => {
+                write!(f, "unsupported movie version {}", v)
+            },
+            MovieError::ROMMismatch => {
+                write!(f, "movie was recorded with a different ROM")
+            },
+            MovieError::InvalidFM2(line, reason) => {
+                write!(f, "invalid FM2 movie, line {}: {}", line, reason)
+            },
+            MovieError::Unsupported(what) => write!(f, "unsupported movie: {}", what),
+            MovieError::SaveState(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Movie {
+    pub fn write_to(&self, output: &mut dyn Write) -> io::Result<()> {
+        output.write_all(&MAGIC)?;
+        serde::encode_u16(output, VERSION)?;
+
+        match &self.rom_checksum {
+            Some(checksum) => {
+                serde::encode_u8(output, 1)?;
+                output.write_all(checksum)?;
+            },
+            None => serde::encode_u8(output, 0)?,
+        }
+
+        serde::encode_vec(output, self.rom_name.as_bytes())?;
+
+        match &self.start {
+            Some(state) => {
+                serde::encode_u8(output, 1)?;
+                serde::encode_vec(output, state)?;
+            },
+            None => serde::encode_u8(output, 0)?,
+        }
+
+        serde::encode_u8(output, match self.multitap {
+            Multitap::None      => 0,
+            Multitap::FourScore => 1,
+            Multitap::Famicom   => 2,
+        })?;
+
+        serde::encode_u64(output, self.frames.len() as u64)?;
+        for frame in &self.frames {
+            let mut flags = 0;
+            if frame.reset { flags |= RESET }
+            if frame.power { flags |= POWER }
+
+            output.write_all(&frame.buttons)?;
+            serde::encode_u8(output, flags)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn read_from(input: &mut dyn Read) -> Result<Self, MovieError> {
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic).map_err(MovieError::IO)?;
+        if magic != MAGIC {
+            return Err(MovieError::InvalidMagic);
+        }
+
+        let version = serde::decode_u16(input).map_err(MovieError::IO)?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+
+        Self::read_body(input).map_err(MovieError::IO)
+    }
+
+    fn read_body(input: &mut dyn Read) -> io::Result<Self> {
+        let rom_checksum = if serde::decode_u8(input)? == 1 {
+            let mut checksum = [0; 16];
+            input.read_exact(&mut checksum)?;
+            Some(checksum)
+        } else {
+            None
+        };
+
+        let rom_name = String::from_utf8_lossy(&serde::decode_vec(input)?).into_owned();
+
+        let start = if serde::decode_u8(input)? == 1 {
+            Some(serde::decode_vec(input)?)
+        } else {
+            None
+        };
+
+        let multitap = match serde::decode_u8(input)? {
+            0 => Multitap::None,
+            1 => Multitap::FourScore,
+            2 => Multitap::Famicom,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad four player adapter")),
+        };
+
+        let count = serde::decode_u64(input)?;
+        let mut frames = Vec::new();
+        for _ in 0 .. count {
+            let mut buttons = [0; 4];
+            input.read_exact(&mut buttons)?;
+            let flags = serde::decode_u8(input)?;
+
+            frames.push(Frame {
+                buttons,
+                reset:   flags & RESET != 0,
+                power:   flags & POWER != 0,
+            });
+        }
+
+        Ok(Self { rom_checksum, rom_name, start, multitap, frames })
+    }
+
+    // FM2 movies that start from a save state embed an FCEUX save state, which
+    // can't be loaded here, and the same goes the other way around. Only
+    // standard controllers are supported, either in the first two ports or in
+    // a Four Score.
+    pub fn read_fm2(input: &mut dyn Read) -> Result<Self, MovieError> {
+        let mut text = String::new();
+        input.read_to_string(&mut text).map_err(MovieError::IO)?;
+
+        let mut movie = Self::default();
+        let mut version = None;
+
+        for (n, line) in text.lines().enumerate() {
+            let n = n + 1;
+            let invalid = |reason: &str| MovieError::InvalidFM2(n, reason.to_string());
+
+            if line.starts_with('|') {
+                let ports = movie.multitap.ports();
+                let fields: Vec<&str> = line.split('|').collect();
+                if fields.len() < ports + 2 {
+                    return Err(invalid("too few fields"));
+                }
+
+                let commands: u8 = fields[1].parse()
+                    .map_err(|_| invalid("invalid commands"))?;
+
+                let mut buttons = [0; 4];
+                for (port, pad) in fields[2 .. ports + 2].iter().enumerate() {
+                    buttons[port] = match pad.len() {
+                        0 => 0,
+                        8 => parse_fm2_pad(pad),
+                        _ => return Err(invalid("controller input should be 8 characters")),
+                    };
+                }
+
+                movie.frames.push(Frame {
+                    buttons,
+                    reset:   commands & RESET != 0,
+                    power:   commands & POWER != 0,
+                });
+
+                continue;
+            }
+
+            let mut parts = line.splitn(2, ' ');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("").trim();
+
+            match key {
+                "version" => version = value.parse::<u32>().ok(),
+                "romFilename" => movie.rom_name = value.to_string(),
+                "romChecksum" => {
+                    let checksum = value.strip_prefix("base64:")
+                        .and_then(decode_base64)
+                        .and_then(|checksum| checksum.try_into().ok())
+                        .ok_or_else(|| invalid("invalid ROM checksum"))?;
+                    movie.rom_checksum = Some(checksum);
+                },
+                "savestate" if !value.is_empty() => {
+                    return Err(MovieError::Unsupported("starts from an FCEUX save state".to_string()));
+                },
+                "palFlag" if value != "0" => {
+                    return Err(MovieError::Unsupported("PAL".to_string()));
+                },
+                "fourscore" => {
+                    movie.multitap = if value == "1" { Multitap::FourScore } else { Multitap::None };
+                },
+                "port0" | "port1" if value != "0" && value != "1" => {
+                    return Err(MovieError::Unsupported(format!("{} isn't a standard controller", key)));
+                },
+                "FDS" if value != "0" => {
+                    return Err(MovieError::Unsupported("Famicom Disk System".to_string()));
+                },
+                _ => { },
+            }
+        }
+
+        match version {
+            Some(3) => Ok(movie),
+            Some(v) => Err(MovieError::UnsupportedVersion(v as u16)),
+            None    => Err(MovieError::InvalidFM2(0, "missing version".to_string())),
+        }
+    }
+
+    pub fn write_fm2(&self, output: &mut dyn Write) -> Result<(), MovieError> {
+        if self.start.is_some() {
+            return Err(MovieError::Unsupported("FM2 movies can't start from a save state".to_string()));
+        }
+
+        if self.multitap == Multitap::Famicom {
+            return Err(MovieError::Unsupported("FM2 movies can't use the Famicom four player adapter".to_string()));
+        }
+
+        self.write_fm2_text(output).map_err(MovieError::IO)
+    }
+
+    fn write_fm2_text(&self, output: &mut dyn Write) -> io::Result<()> {
+        writeln!(output, "version 3")?;
+        writeln!(output, "emuVersion 22020")?;
+        writeln!(output, "rerecordCount 0")?;
+        writeln!(output, "palFlag 0")?;
+        writeln!(output, "romFilename {}", self.rom_name)?;
+        if let Some(checksum) = &self.rom_checksum {
+            writeln!(output, "romChecksum base64:{}", encode_base64(checksum))?;
+        }
+        writeln!(output, "guid 00000000-0000-0000-0000-000000000000")?;
+        writeln!(output, "fourscore {}", if self.multitap == Multitap::FourScore { 1 } else { 0 })?;
+        writeln!(output, "microphone 0")?;
+        writeln!(output, "port0 1")?;
+        writeln!(output, "port1 1")?;
+        writeln!(output, "port2 0")?;
+        writeln!(output, "FDS 0")?;
+        writeln!(output, "NewPPU 0")?;
+
+        for frame in &self.frames {
+            let mut commands = 0;
+            if frame.reset { commands |= RESET }
+            if frame.power { commands |= POWER }
+
+            write!(output, "|{}|", commands)?;
+            for &buttons in &frame.buttons[.. self.multitap.ports()] {
+                write!(output, "{}|", write_fm2_pad(buttons))?;
+            }
+            writeln!(output, "|")?;
+        }
+
+        Ok(())
+    }
+}
+
+// FM2 controller input is eight characters, for right, left, down, up, start,
+// select, B and A, where anything but a space or a dot is pressed.
+fn parse_fm2_pad(pad: &str) -> u8 {
+    pad.chars().enumerate()
+        .filter(|&(_, c)| c != '.' && c != ' ')
+        .fold(0, |buttons, (i, _)| buttons | 0x80 >> i)
+}
+
+fn write_fm2_pad(buttons: u8) -> String {
+    "RLDUTSBA".chars().enumerate()
+        .map(|(i, c)| if buttons & (0x80 >> i) != 0 { c } else { '.' })
+        .collect()
+}
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn encode_base64(data: &[u8]) -> String {
+    let mut text = String::new();
+
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0 .. 4 {
+            if i <= chunk.len() {
+                text.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+
+    text
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut n = 0u32;
+    let mut bits = 0;
+
+    for c in text.bytes().filter(|&c| c != b'=') {
+        let value = BASE64.iter().position(|&b| b == c)? as u32;
+        n = n << 6 | value;
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            data.push((n >> bits) as u8);
+        }
+    }
+
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_movie() -> Movie {
+        Movie {
+            rom_checksum: Some([0xab; 16]),
+            rom_name:     "test.nes".to_string(),
+            start:        None,
+            multitap:     Multitap::None,
+            frames:       vec![
+                Frame { buttons: [0x00, 0x00, 0, 0], reset: false, power: false },
+                Frame { buttons: [0x81, 0x02, 0, 0], reset: false, power: false },
+                Frame { buttons: [0x18, 0x00, 0, 0], reset: true,  power: false },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut movie = test_movie();
+        movie.start = Some(vec![1, 2, 3]);
+        movie.multitap = Multitap::Famicom;
+        movie.frames[2].buttons[3] = 0x40;
+
+        let mut data = Vec::new();
+        movie.write_to(&mut data).unwrap();
+        assert_eq!(Movie::read_from(&mut &data[..]).unwrap(), movie);
+    }
+
+    #[test]
+    fn test_corrupt_length() {
+        // A ROM name that claims to be far longer than the file
+        let mut data = MAGIC.to_vec();
+        serde::encode_u16(&mut data, VERSION).unwrap();
+        serde::encode_u8(&mut data, 0).unwrap();
+        serde::encode_u64(&mut data, u64::MAX).unwrap();
+        data.extend_from_slice(b"test.nes");
+
+        match Movie::read_from(&mut &data[..]) {
+            Err(MovieError::IO(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            _ => panic!("expected invalid data"),
+        }
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut data = MAGIC.to_vec();
+        serde::encode_u16(&mut data, VERSION + 1).unwrap();
+
+        match Movie::read_from(&mut &data[..]) {
+            Err(MovieError::UnsupportedVersion(v)) => assert_eq!(v, VERSION + 1),
+            _ => panic!("expected an unsupported version"),
+        }
+    }
+
+    #[test]
+    fn test_fm2_round_trip() {
+        let movie = test_movie();
+
+        let mut text = Vec::new();
+        movie.write_fm2(&mut text).unwrap();
+        assert_eq!(Movie::read_fm2(&mut &text[..]).unwrap(), movie);
+
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("\n|0|R......A|......B.||\n"));
+        assert!(text.contains("\n|1|...UT...|........||\n"));
+    }
+
+    #[test]
+    fn test_fm2_four_score() {
+        let mut movie = test_movie();
+        movie.multitap = Multitap::FourScore;
+        movie.frames[1].buttons[2] = 0x04;
+        movie.frames[1].buttons[3] = 0x80;
+
+        let mut text = Vec::new();
+        movie.write_fm2(&mut text).unwrap();
+        assert_eq!(Movie::read_fm2(&mut &text[..]).unwrap(), movie);
+
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("\nfourscore 1\n"));
+        assert!(text.contains("\n|0|R......A|......B.|.....S..|R.......||\n"));
+
+        movie.multitap = Multitap::Famicom;
+        assert!(movie.write_fm2(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_fm2_import() {
+        let text = "version 3\n\
+                    romFilename smb\n\
+                    romChecksum base64:jjYwGG411HcjG/j9UOVM3Q==\n\
+                    port0 1\n\
+                    port1 0\n\
+                    |0|........|||\n\
+                    |2|    T   |||\n\
+                    |0|.......A|...U....||\n";
+
+        let movie = Movie::read_fm2(&mut text.as_bytes()).unwrap();
+        assert_eq!(movie.rom_name, "smb");
+        assert_eq!(encode_base64(&movie.rom_checksum.unwrap()), "jjYwGG411HcjG/j9UOVM3Q==");
+        assert_eq!(movie.frames, vec![
+            Frame { buttons: [0x00, 0x00, 0, 0], reset: false, power: false },
+            Frame { buttons: [0x08, 0x00, 0, 0], reset: false, power: true },
+            Frame { buttons: [0x01, 0x10, 0, 0], reset: false, power: false },
+        ]);
+
+        let text = "version 3\nsavestate base64:AAAA\n";
+        assert!(Movie::read_fm2(&mut text.as_bytes()).is_err());
+
+        let text = "version 3\n|0|RLD|||\n";
+        match Movie::read_fm2(&mut text.as_bytes()) {
+            Err(MovieError::InvalidFM2(line, _)) => assert_eq!(line, 2),
+            _ => panic!("expected an invalid FM2 movie"),
+        }
+    }
+
+    #[test]
+    fn test_fm2_save_state_export() {
+        let mut movie = test_movie();
+        movie.start = Some(vec![1, 2, 3]);
+        assert!(movie.write_fm2(&mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_base64() {
+        for len in 0 .. 8 {
+            let data: Vec<u8> = (0 .. len).map(|i| i * 37).collect();
+            assert_eq!(decode_base64(&encode_base64(&data)).unwrap(), data);
+        }
+        assert_eq!(encode_base64(b"Man"), "TWFu");
+        assert_eq!(encode_base64(b"Ma"), "TWE=");
+    }
+}
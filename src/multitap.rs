@@ -0,0 +1,139 @@
+// Four player adapters
+//
+// http://wiki.nesdev.com/w/index.php/Four_player_adapters
+//
+// The NES Four Score plugs into both controller ports, and reads out 24 bits
+// from each: controller 1 or 2, then controller 3 or 4, and then a signature
+// that tells the game the Four Score is there.
+//
+// read   | 1 - 8        | 9 - 16       | 17 - 24
+// $4016  | controller 1 | controller 3 | 0 0 0 1 0 0 0 0
+// $4017  | controller 2 | controller 4 | 0 0 1 0 0 0 0 0
+//
+// The Famicom's adapters plug into the expansion port instead, and put
+// controllers 3 and 4 on bit 1 of $4016 and $4017, next to controllers 1 and 2
+// on bit 0.
+
+use std::io::{Read, Write};
+use std::io;
+
+use crate::controller::Controller;
+use crate::mem::Memory;
+use crate::serde;
+
+// What's plugged in between the controllers and the console
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Multitap {
+    #[default]
+    None,
+    FourScore,
+    Famicom,
+}
+
+impl Multitap {
+    // How many controllers can be used at once
+    pub fn ports(self) -> usize {
+        match self {
+            Multitap::None => 2,
+            _              => 4,
+        }
+    }
+}
+
+const SIGNATURES: [u8; 2] = [0x10, 0x20];
+
+// The Four Score's shift registers, one for each port
+pub struct FourScore {
+    index: [usize; 2],
+    strobe: u8,
+}
+
+impl FourScore {
+    pub fn new_four_score() -> Self {
+        Self {
+            index: [0; 2],
+            strobe: 0,
+        }
+    }
+
+    pub fn read_port(&mut self, port: usize, controllers: &[Controller; 4]) -> u8 {
+        let index = self.index[port];
+
+        let value = match index {
+            0  ..= 7  => controllers[port].buttons() >> index & 1,
+            8  ..= 15 => controllers[port + 2].buttons() >> (index - 8) & 1,
+            16 ..= 23 => SIGNATURES[port] >> (23 - index) & 1,
+            _         => 0,
+        };
+
+        if self.strobe & 1 == 1 {
+            self.index[port] = 0;
+        } else if index < 24 {
+            self.index[port] += 1;
+        }
+
+        value
+    }
+}
+
+impl Memory for FourScore {
+    fn write(&mut self, _address: u16, val: u8) {
+        self.strobe = val;
+
+        if self.strobe & 1 == 1 {
+            self.index = [0; 2];
+        }
+    }
+
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
+        serde::encode_usize(output, self.index[0])?;
+        serde::encode_usize(output, self.index[1])?;
+        serde::encode_u8(output, self.strobe)?;
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.index[0] = serde::decode_usize(input)?;
+        self.index[1] = serde::decode_usize(input)?;
+        self.strobe = serde::decode_u8(input)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::Button;
+
+    #[test]
+    fn test_four_score() {
+        let mut controllers = [
+            Controller::new_controller(),
+            Controller::new_controller(),
+            Controller::new_controller(),
+            Controller::new_controller(),
+        ];
+        controllers[0].set_button(Button::A, true);
+        controllers[1].set_button(Button::B, true);
+        controllers[2].set_button(Button::Start, true);
+        controllers[3].set_button(Button::Right, true);
+
+        let mut four_score = FourScore::new_four_score();
+        four_score.write(0x4016, 1);
+        four_score.write(0x4016, 0);
+
+        let one: Vec<u8> = (0 .. 24).map(|_| four_score.read_port(0, &controllers)).collect();
+        let two: Vec<u8> = (0 .. 24).map(|_| four_score.read_port(1, &controllers)).collect();
+
+        assert_eq!(one, [
+            1, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 1, 0, 0, 0, 0,
+            0, 0, 0, 1, 0, 0, 0, 0,
+        ]);
+        assert_eq!(two, [
+            0, 1, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 1,
+            0, 0, 1, 0, 0, 0, 0, 0,
+        ]);
+    }
+}
@@ -1,4 +1,15 @@
-use sdl2::pixels::Color;
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
 
 lazy_static!{
     pub static ref PALETTE: Vec<Color> = [
@@ -31,7 +42,7 @@ lazy_static!{
             let r = (c >> 16) as u8;
             let g = (c >> 8) as u8;
             let b = c as u8;
-            Color::RGB(r, g, b)
+            Color::rgb(r, g, b)
         } )
         .collect::<Vec<_>>();
 }
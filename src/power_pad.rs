@@ -0,0 +1,151 @@
+// Power Pad
+//
+// http://wiki.nesdev.com/w/index.php/Power_Pad
+//
+// The Power Pad is a mat with 12 buttons, which plugs in in place of the
+// second controller. Side B numbers them
+//
+//    1  2  3  4
+//    5  6  7  8
+//    9 10 11 12
+//
+// and side A has no numbers, and only uses eight of them. Strobing $4016
+// latches all of the buttons into two shift registers, which are read out
+// one bit at a time, and then read as 1s once they're empty.
+//
+// bit    | 7 - 5 |         4          |      3       | 2 - 0 |
+// $4017  |       | 2 1 5 9 6 10 11 7  | 4 3 12 8     |       |
+
+use std::io::{Read, Write};
+use std::io;
+
+use crate::frontend::InputEvent;
+use crate::input::{Device, InputDevice, Slot};
+use crate::mem::Memory;
+use crate::ppu::PPU;
+use crate::serde;
+
+// The order the buttons, numbered from 1, are read out of each shift register
+const HIGH_ORDER: [usize; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const LOW_ORDER: [usize; 4] = [4, 3, 12, 8];
+
+pub struct PowerPad {
+    // Buttons 1 to 12, at 0 to 11
+    buttons: [bool; 12],
+
+    high: u8,
+    low: u8,
+    strobe: u8,
+}
+
+impl Memory for PowerPad {
+    fn write(&mut self, _address: u16, val: u8) {
+        self.strobe = val;
+
+        if self.strobe & 1 == 1 {
+            self.latch();
+        }
+    }
+
+    // Like the controllers, only the shift registers are saved, and not what
+    // is being stood on
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
+        serde::encode_u8(output, self.high)?;
+        serde::encode_u8(output, self.low)?;
+        serde::encode_u8(output, self.strobe)?;
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.high = serde::decode_u8(input)?;
+        self.low = serde::decode_u8(input)?;
+        self.strobe = serde::decode_u8(input)?;
+        Ok(())
+    }
+}
+
+impl Device for PowerPad {
+    fn kind(&self) -> InputDevice {
+        InputDevice::PowerPad
+    }
+
+    fn slot(&self) -> Slot {
+        Slot::Port2
+    }
+
+    fn read_port(&mut self, port: usize, _ppu: &PPU) -> u8 {
+        if port == 0 {
+            return 0;
+        }
+
+        let value = (self.high & 1) << 4 | (self.low & 1) << 3;
+
+        if self.strobe & 1 == 1 {
+            self.latch();
+        } else {
+            self.high = self.high >> 1 | 0x80;
+            self.low = self.low >> 1 | 0x80;
+        }
+
+        value
+    }
+
+    // Buttons 1 to 12 on side B arrive as 0 to 11, and anything else is
+    // ignored
+    fn input(&mut self, event: &InputEvent) {
+        if let InputEvent::PowerPad(button, pressed) = *event {
+            if let Some(b) = self.buttons.get_mut(button) {
+                *b = pressed;
+            }
+        }
+    }
+}
+
+impl PowerPad {
+    pub fn new_power_pad() -> Self {
+        Self {
+            buttons: [false; 12],
+            high: 0xff,
+            low: 0xff,
+            strobe: 0,
+        }
+    }
+
+    fn latch(&mut self) {
+        let buttons = &self.buttons;
+        let bits = |order: &[usize]| {
+            order.iter().rev().fold(0, |bits, &button| bits << 1 | buttons[button - 1] as u8)
+        };
+
+        self.high = bits(&HIGH_ORDER);
+        self.low = bits(&LOW_ORDER) | 0xf0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_power_pad() {
+        let ppu = PPU::new_nes_ppu();
+        let mut pad = PowerPad::new_power_pad();
+        pad.input(&InputEvent::PowerPad(0, true));
+        pad.input(&InputEvent::PowerPad(11, true));
+
+        pad.write(0x4016, 1);
+        pad.write(0x4016, 0);
+
+        let reads: Vec<u8> = (0 .. 10).map(|_| pad.read_port(1, &ppu)).collect();
+        assert_eq!(reads, [
+            0x00, 0x10, 0x08, 0x00, 0x08, 0x08, 0x08, 0x08, 0x18, 0x18,
+        ]);
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let mut pad = PowerPad::new_power_pad();
+        pad.input(&InputEvent::PowerPad(12, true));
+        assert_eq!(pad.buttons, [false; 12]);
+    }
+}
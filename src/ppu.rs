@@ -1,12 +1,12 @@
 mod debug;
 mod regs;
 
-use std::cell::RefCell;
-use std::fs::File;
+pub use crate::ppu::debug::DebugView;
+
+use std::io::{Read, Write};
 use std::io;
-use std::rc::Rc;
 
-use crate::palette::PALETTE;
+use crate::palette::{Color, PALETTE};
 use crate::mapper::{Mapper, MapperEvent};
 use crate::mem::Memory;
 use crate::ppu::regs::PPUCtrl;
@@ -16,8 +16,6 @@ use crate::ppu::regs::OAM;
 use crate::ppu::regs::PPUData;
 use crate::serde;
 
-use sdl2::pixels::Color;
-
 pub struct PPU {
     // PPU registers
     ctrl: PPUCtrl,
@@ -71,146 +69,7 @@ pub struct PPU {
 }
 
 impl Memory for PPU {
-    fn read(&mut self, address: u16) -> u8 {
-        // The PPU registers exist from 0x2000 to 0x2007, the rest of the
-        // address space is just a mirror of these first eight bytes.
-        let address = address % 8 + 0x2000;
-        match address {
-            0x2000 => {
-                let PPUCtrl(n) = self.ctrl;
-                n
-            },
-            0x2001 => {
-                let PPUMask(n) = self.mask;
-                n
-            },
-            0x2002 => {
-                let PPUStatus(mut n) = self.status;
-
-                // Whatever the last value was written to the PPU (to any
-                // register), set the first 5 bits of the PPUSTATUS value to
-                // the first 5 bits of _that_ last value.
-                n &= ! 0x1f;
-                n |= self.last_value & 0x1f;
-
-                if self.nmi_occurred {
-                    n |= 1 << 7;
-                }
-                self.nmi_occurred = false;
-                self.nmi_change();
-
-                // w:                  = 0
-                self.w = false;
-
-                n
-            },
-            0x2003 => 0, // OAMADDR is write-only
-            0x2004 => self.oam.read(self.oam_addr as u16),
-            0x2005 => 0, // PPUSCROLL is write-only
-            0x2006 => 0, // PPUADDR is write-only
-            0x2007 => {
-                let rv;
-
-                // Emulate 1-byte delayed read
-                // Palette reads aren't buffered
-                if self.ppu_addr % 0x4000 <= 0x3eff {
-                    rv = self.buffered_data;
-                    self.buffered_data = self.data.read(self.ppu_addr);
-                } else {
-                    // TODO why do we subtract 0x1000 ?
-                    self.buffered_data = self.data.read(self.ppu_addr - 0x1000);
-                    rv = self.data.read(self.ppu_addr);
-                }
-
-                self.ppu_addr = self.ppu_addr.wrapping_add(
-                    self.ctrl.vram_addr_increment());
-
-                self.data.mapper.borrow_mut()
-                    .notify(MapperEvent::VRAMAddressChange(self.ppu_addr));
-
-                rv
-            },
-
-            _ => panic!("bad PPU address 0x{:04X}", address)
-        }
-    }
-
-    fn write(&mut self, address: u16, val: u8) {
-        self.last_value = val;
-
-        let address = address % 8 + 0x2000;
-        match address {
-            0x2000 => {
-                self.ctrl = PPUCtrl(val);
-
-                // t: ...BA.. ........ = d: ......BA
-                self.t = (self.t & 0xf3ff)
-                       | (((val as u16) & 0x03) << 10);
-
-                self.nmi_output = (val >> 7) & 1 == 1;
-                self.nmi_change();
-            },
-            0x2001 => { self.mask = PPUMask(val) },
-            0x2002 => { },
-            0x2003 => { self.oam_addr = val },
-            0x2004 => {
-                self.oam.write(self.oam_addr as u16, val);
-                self.oam_addr = self.oam_addr.wrapping_add(1);
-            },
-            0x2005 => {
-                if self.w {
-                    // t: CBA..HG FED..... = d: HGFEDCBA
-                    // w:                  = 0
-                    self.t = (self.t & 0x8fff)
-                           | (((val as u16) & 0x07) << 12);
-                    self.t = (self.t & 0xfc1f)
-                           | (((val as u16) & 0xf8) << 2);
-                    self.w = false;
-                } else {
-                    // t: ....... ...HGFED = d: HGFED...
-                    // x:              CBA = d: .....CBA
-                    // w:                  = 1
-                    self.t = (self.t & 0xffe0)
-                           | ((val as u16) >> 3);
-                    self.x = val & 0x07;
-                    self.w = true;
-                }
-            },
-            0x2006 => {
-                if self.w {
-                    // t: ....... HGFEDCBA = d: HGFEDCBA
-                    // v                   = t
-                    // w:                  = 0
-                    self.t = (self.t & 0xff00)
-                           | (val as u16);
-                    self.ppu_addr = self.t;
-                    self.w = false;
-
-                    self.data.mapper.borrow_mut()
-                        .notify(MapperEvent::VRAMAddressChange(self.ppu_addr));
-                } else {
-                    // t: .FEDCBA ........ = d: ..FEDCBA
-                    // t: X...... ........ = 0
-                    // w:                  = 1
-                    self.t = (self.t & 0x80ff)
-                           | (((val as u16) & 0x3f) << 8);
-                    self.w = true;
-                }
-            },
-            0x2007 => {
-                self.data.write(self.ppu_addr, val);
-                self.ppu_addr = self.ppu_addr.wrapping_add(
-                    self.ctrl.vram_addr_increment());
-
-                self.data.mapper.borrow_mut()
-                    .notify(MapperEvent::VRAMAddressChange(self.ppu_addr));
-            },
-
-            _ => panic!("bad PPU address 0x{:04X}", address)
-        }
-    }
-
-    fn save(&self, output: &mut File) -> io::Result<()> {
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
         let PPUCtrl(v) = self.ctrl;
         serde::encode_u8(output, v)?;
 
@@ -236,7 +95,7 @@ impl Memory for PPU {
         serde::encode_u64(output, self.tile_data)?;
 
         serde::encode_usize(output, self.sprite_count)?;
-        for i in 0 .. 7 {
+        for i in 0 .. 8 {
             serde::encode_u32(output, self.sprite_patterns[i])?;
             serde::encode_u8(output, self.sprite_positions[i])?;
             serde::encode_u8(output, self.sprite_priorities[i])?;
@@ -246,6 +105,7 @@ impl Memory for PPU {
         serde::encode_u8(output, self.odd_frame as u8)?;
         serde::encode_u8(output, self.nmi_occurred as u8)?;
         serde::encode_u8(output, self.nmi_output as u8)?;
+        serde::encode_u8(output, self.nmi_previous as u8)?;
         serde::encode_usize(output, self.nmi_delay)?;
 
         serde::encode_u16(output, self.t)?;
@@ -255,10 +115,17 @@ impl Memory for PPU {
         serde::encode_u8(output, self.buffered_data)?;
         serde::encode_u8(output, self.last_value)?;
 
+        // The frame on screen, so that it's right straight after loading
+        for row in &self.pixels {
+            for pixel in row {
+                output.write_all(&[pixel.r, pixel.g, pixel.b])?;
+            }
+        }
+
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
         self.ctrl = PPUCtrl(serde::decode_u8(input)?);
         self.mask = PPUMask(serde::decode_u8(input)?);
         self.status = PPUStatus(serde::decode_u8(input)?);
@@ -277,12 +144,24 @@ impl Memory for PPU {
         self.high_tile_byte = serde::decode_u8(input)?;
         self.tile_data = serde::decode_u64(input)?;
 
+        // These index the sprite arrays and OAM, so a corrupt state could
+        // otherwise take down the renderer
+        let invalid = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+
         self.sprite_count = serde::decode_usize(input)?;
-        for i in 0 .. 7 {
+        if self.sprite_count > 8 {
+            return Err(invalid("more than 8 sprites on a scanline"));
+        }
+
+        for i in 0 .. 8 {
             self.sprite_patterns[i] = serde::decode_u32(input)?;
             self.sprite_positions[i] = serde::decode_u8(input)?;
             self.sprite_priorities[i] = serde::decode_u8(input)?;
             self.sprite_indexes[i] = serde::decode_usize(input)?;
+
+            if self.sprite_indexes[i] >= 64 {
+                return Err(invalid("sprite index past the end of OAM"));
+            }
         }
 
         self.odd_frame = serde::decode_u8(input)? != 0;
@@ -298,6 +177,14 @@ impl Memory for PPU {
         self.buffered_data = serde::decode_u8(input)?;
         self.last_value = serde::decode_u8(input)?;
 
+        for row in self.pixels.iter_mut() {
+            for pixel in row.iter_mut() {
+                let mut rgb = [0; 3];
+                input.read_exact(&mut rgb)?;
+                *pixel = Color::rgb(rgb[0], rgb[1], rgb[2]);
+            }
+        }
+
         Ok(())
     }
 }
@@ -308,7 +195,7 @@ pub struct StepResult {
 }
 
 impl PPU {
-    pub fn new_nes_ppu(cartridge: Rc<RefCell<Box<dyn Mapper>>>) -> Self {
+    pub fn new_nes_ppu() -> Self {
         Self {
             ctrl: PPUCtrl(0),
             mask: PPUMask(0),
@@ -316,7 +203,7 @@ impl PPU {
             oam: OAM::new_nes_oam(),
             oam_addr: 0,
             ppu_addr: 0,
-            data: PPUData::new_ppu_data(cartridge),
+            data: PPUData::new_ppu_data(),
 
             dot: 0,
             scanline: 0,
@@ -348,14 +235,174 @@ impl PPU {
 
             last_value: 0,
 
-            pixels: vec![vec![Color::RGB(0, 0, 0); 256]; 240],
+            pixels: vec![vec![Color::rgb(0, 0, 0); 256]; 240],
         }
     }
 
-    pub fn get_pixels(&self) -> &Vec<Vec<Color>> {
+    pub fn read_register(&mut self, mapper: &mut dyn Mapper, address: u16) -> u8 {
+        // The PPU registers exist from 0x2000 to 0x2007, the rest of the
+        // address space is just a mirror of these first eight bytes.
+        let address = address % 8 + 0x2000;
+        match address {
+            0x2000 => {
+                let PPUCtrl(n) = self.ctrl;
+                n
+            },
+            0x2001 => {
+                let PPUMask(n) = self.mask;
+                n
+            },
+            0x2002 => {
+                let PPUStatus(mut n) = self.status;
+
+                // Whatever the last value was written to the PPU (to any
+                // register), set the first 5 bits of the PPUSTATUS value to
+                // the first 5 bits of _that_ last value.
+                n &= ! 0x1f;
+                n |= self.last_value & 0x1f;
+
+                if self.nmi_occurred {
+                    n |= 1 << 7;
+                }
+                self.nmi_occurred = false;
+                self.nmi_change();
+
+                // w:                  = 0
+                self.w = false;
+
+                n
+            },
+            0x2003 => 0, // OAMADDR is write-only
+            0x2004 => self.oam.read(self.oam_addr as u16),
+            0x2005 => 0, // PPUSCROLL is write-only
+            0x2006 => 0, // PPUADDR is write-only
+            0x2007 => {
+                let rv;
+
+                // Emulate 1-byte delayed read
+                // Palette reads aren't buffered
+                if self.ppu_addr % 0x4000 <= 0x3eff {
+                    rv = self.buffered_data;
+                    self.buffered_data = self.data.read(mapper, self.ppu_addr);
+                } else {
+                    // TODO why do we subtract 0x1000 ?
+                    self.buffered_data = self.data.read(mapper, self.ppu_addr - 0x1000);
+                    rv = self.data.read(mapper, self.ppu_addr);
+                }
+
+                self.ppu_addr = self.ppu_addr.wrapping_add(
+                    self.ctrl.vram_addr_increment());
+
+                mapper.notify(MapperEvent::VRAMAddressChange(self.ppu_addr));
+
+                rv
+            },
+
+            _ => panic!("bad PPU address 0x{:04X}", address)
+        }
+    }
+
+    // Reads a register without any of the side effects of reading it, for the
+    // debugger. PPUSTATUS is read as usual, and the rest as the last value
+    // that was written to the PPU.
+    pub fn peek_register(&self, address: u16) -> u8 {
+        match address % 8 + 0x2000 {
+            0x2002 => {
+                let PPUStatus(n) = self.status;
+                let vblank = if self.nmi_occurred { 1 << 7 } else { 0 };
+                n & ! 0x9f | self.last_value & 0x1f | vblank
+            },
+            _ => self.last_value,
+        }
+    }
+
+    pub fn write_register(&mut self, mapper: &mut dyn Mapper, address: u16, val: u8) {
+        self.last_value = val;
+
+        let address = address % 8 + 0x2000;
+        match address {
+            0x2000 => {
+                self.ctrl = PPUCtrl(val);
+
+                // t: ...BA.. ........ = d: ......BA
+                self.t = (self.t & 0xf3ff)
+                       | (((val as u16) & 0x03) << 10);
+
+                self.nmi_output = (val >> 7) & 1 == 1;
+                self.nmi_change();
+            },
+            0x2001 => { self.mask = PPUMask(val) },
+            0x2002 => { },
+            0x2003 => { self.oam_addr = val },
+            0x2004 => {
+                self.oam.write(self.oam_addr as u16, val);
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            },
+            0x2005 => {
+                if self.w {
+                    // t: CBA..HG FED..... = d: HGFEDCBA
+                    // w:                  = 0
+                    self.t = (self.t & 0x8fff)
+                           | (((val as u16) & 0x07) << 12);
+                    self.t = (self.t & 0xfc1f)
+                           | (((val as u16) & 0xf8) << 2);
+                    self.w = false;
+                } else {
+                    // t: ....... ...HGFED = d: HGFED...
+                    // x:              CBA = d: .....CBA
+                    // w:                  = 1
+                    self.t = (self.t & 0xffe0)
+                           | ((val as u16) >> 3);
+                    self.x = val & 0x07;
+                    self.w = true;
+                }
+            },
+            0x2006 => {
+                if self.w {
+                    // t: ....... HGFEDCBA = d: HGFEDCBA
+                    // v                   = t
+                    // w:                  = 0
+                    self.t = (self.t & 0xff00)
+                           | (val as u16);
+                    self.ppu_addr = self.t;
+                    self.w = false;
+
+                    mapper.notify(MapperEvent::VRAMAddressChange(self.ppu_addr));
+                } else {
+                    // t: .FEDCBA ........ = d: ..FEDCBA
+                    // t: X...... ........ = 0
+                    // w:                  = 1
+                    self.t = (self.t & 0x80ff)
+                           | (((val as u16) & 0x3f) << 8);
+                    self.w = true;
+                }
+            },
+            0x2007 => {
+                self.data.write(mapper, self.ppu_addr, val);
+                self.ppu_addr = self.ppu_addr.wrapping_add(
+                    self.ctrl.vram_addr_increment());
+
+                mapper.notify(MapperEvent::VRAMAddressChange(self.ppu_addr));
+            },
+
+            _ => panic!("bad PPU address 0x{:04X}", address)
+        }
+    }
+
+    pub fn get_pixels(&self) -> &[Vec<Color>] {
         &self.pixels
     }
 
+    // The VRAM address that the next PPUDATA access goes to
+    pub fn vram_address(&self) -> u16 {
+        self.ppu_addr % 0x4000
+    }
+
+    // The scanline and dot that the PPU is on
+    pub fn position(&self) -> (u16, u16) {
+        (self.scanline, self.dot)
+    }
+
     fn rendering_enabled(&self) -> bool {
         self.mask.show_background() || self.mask.show_sprites()
     }
@@ -456,7 +503,7 @@ impl PPU {
     // Fetches the sprite pattern for a single row of a tile. If you wanted the
     // pattern tables for every row of a sprite, you would call this with the
     // `row' parameter being the values from 0 to 7 (inclusive).
-    fn fetch_sprite_pattern(&mut self, i: u16, row: i16) -> u32 {
+    fn fetch_sprite_pattern(&mut self, mapper: &mut dyn Mapper, i: u16, row: i16) -> u32 {
         let mut tile = self.oam.read(i * 4 + 1) as u16;
         let attributes = self.oam.read(i * 4 + 2);
 
@@ -490,8 +537,8 @@ impl PPU {
         }
 
         let a = ((attributes & 3) << 2) as u32;
-        let mut low_tile_byte = self.data.read(address) as u32;
-        let mut high_tile_byte = self.data.read(address + 8) as u32;
+        let mut low_tile_byte = self.data.read(mapper, address) as u32;
+        let mut high_tile_byte = self.data.read(mapper, address + 8) as u32;
 
         // Now we need to return a 32-bit unsigned value, representing the 8
         // pixels of this row of the sprite. This means we have 4 bits per
@@ -528,7 +575,7 @@ impl PPU {
         } )
     }
 
-    fn evaluate_sprites(&mut self) {
+    fn evaluate_sprites(&mut self, mapper: &mut dyn Mapper) {
         let sz = self.ctrl.sprite_size() as i16;
 
         let mut count = 0;
@@ -546,7 +593,7 @@ impl PPU {
             }
 
             if count < 8 {
-                self.sprite_patterns[count] = self.fetch_sprite_pattern(sprite, row);
+                self.sprite_patterns[count] = self.fetch_sprite_pattern(mapper, sprite, row);
                 self.sprite_positions[count] = x;
                 self.sprite_priorities[count] = (a >> 5) & 1;
                 self.sprite_indexes[count] = i;
@@ -563,7 +610,7 @@ impl PPU {
         self.sprite_count = count;
     }
 
-    fn render_pixel(&mut self) {
+    fn render_pixel(&mut self, mapper: &mut dyn Mapper) {
         let x = self.dot - 1;
         let y = self.scanline;
 
@@ -609,7 +656,7 @@ impl PPU {
         // Set the base palette address
         let address = 0x3f00 | address_low_nyb;
 
-        let palette_index = self.data.read(address) % 64;
+        let palette_index = self.data.read(mapper, address) % 64;
         let color = PALETTE[palette_index as usize];
         //let rect = Rect::new((x as i32) * 3, (y as i32) * 3, 3, 3);
 
@@ -618,15 +665,15 @@ impl PPU {
         self.pixels[y][x] = color;
     }
 
-    fn fetch_nametable_byte(&mut self) -> u8 {
+    fn fetch_nametable_byte(&mut self, mapper: &mut dyn Mapper) -> u8 {
         let v = self.ppu_addr;
         // https://wiki.nesdev.com/w/index.php/PPU_scrolling#Tile_and_attribute_fetching
         let addr = 0x2000 | (v & 0x0fff);
         debug!("fetching NT byte from 0x{:04X}", addr);
-        self.data.read(addr)
+        self.data.read(mapper, addr)
     }
 
-    fn fetch_attrtable_byte(&mut self) -> u8 {
+    fn fetch_attrtable_byte(&mut self, mapper: &mut dyn Mapper) -> u8 {
         let v = self.ppu_addr;
 
         // https://wiki.nesdev.com/w/index.php/PPU_scrolling#Tile_and_attribute_fetching
@@ -636,13 +683,13 @@ impl PPU {
                  | ((v >> 2) & 0x07);
 
         debug!("fetching AT byte from 0x{:04X}", addr);
-        let attrbyte = self.data.read(addr);
+        let attrbyte = self.data.read(mapper, addr);
 
         let shift = ((v >> 4) & 4) | (v & 2);
         ((attrbyte >> shift) & 3) << 2
     }
 
-    fn fetch_low_tile_byte(&mut self) -> u8 {
+    fn fetch_low_tile_byte(&mut self, mapper: &mut dyn Mapper) -> u8 {
         let fine_y = (self.ppu_addr >> 12) & 0x07;
         let tile = self.nametable_byte as u16;
         let addr = self.ctrl.background_pattern_table_addr()
@@ -650,10 +697,10 @@ impl PPU {
             + (16 * tile);
 
         debug!("fetching low tile byte from 0x{:04X}", addr);
-        self.data.read(addr)
+        self.data.read(mapper, addr)
     }
 
-    fn fetch_high_tile_byte(&mut self) -> u8 {
+    fn fetch_high_tile_byte(&mut self, mapper: &mut dyn Mapper) -> u8 {
         let fine_y = (self.ppu_addr >> 12) & 0x07;
         let tile = self.nametable_byte as u16;
         let addr = self.ctrl.background_pattern_table_addr()
@@ -661,7 +708,7 @@ impl PPU {
             + (16 * tile);
 
         debug!("fetching high tile byte from 0x{:04X}", addr + 8);
-        self.data.read(addr + 8)
+        self.data.read(mapper, addr + 8)
     }
 
     fn fetch_tile_data(&self) -> u32 {
@@ -715,7 +762,7 @@ impl PPU {
         }
     }
 
-    pub fn step(&mut self) -> StepResult {
+    pub fn step(&mut self, mapper: &mut dyn Mapper) -> StepResult {
         // http://wiki.nesdev.com/w/index.php/PPU_rendering#Line-by-line_timing
         //
         // There are a total of 262 scanlines per frame
@@ -748,7 +795,7 @@ impl PPU {
         // background logic
         if self.rendering_enabled() {
             if visible_line && visible_cycle {
-                self.render_pixel();
+                self.render_pixel(mapper);
             }
 
             if render_line && fetch_cycle {
@@ -756,19 +803,19 @@ impl PPU {
 
                 match self.dot % 8 {
                     1 => {
-                        let b = self.fetch_nametable_byte();
+                        let b = self.fetch_nametable_byte(mapper);
                         self.nametable_byte = b;
                     },
                     3 => {
-                        let b = self.fetch_attrtable_byte();
+                        let b = self.fetch_attrtable_byte(mapper);
                         self.attrtable_byte = b;
                     },
                     5 => {
-                        let b = self.fetch_low_tile_byte();
+                        let b = self.fetch_low_tile_byte(mapper);
                         self.low_tile_byte = b;
                     },
                     7 => {
-                        let b = self.fetch_high_tile_byte();
+                        let b = self.fetch_high_tile_byte(mapper);
                         self.high_tile_byte = b;
                     },
                     0 => self.store_tile_data(),
@@ -802,7 +849,7 @@ impl PPU {
         // sprite logic
         if self.rendering_enabled() && self.dot == 257 {
             if visible_line {
-                self.evaluate_sprites();
+                self.evaluate_sprites(mapper);
             } else {
                 self.sprite_count = 0;
             }
@@ -826,7 +873,7 @@ impl PPU {
         // I have a feeling that the IRQ counting for MMC3 games still has
         // problems and needs to be looked at in its entirety again.
         if (pre_line || visible_line) && self.rendering_enabled() && self.dot == 280 {
-            self.data.mapper.borrow_mut().notify(MapperEvent::HBlank);
+            mapper.notify(MapperEvent::HBlank);
         }
 
         if pre_line && self.dot == 1 {
@@ -841,3 +888,28 @@ impl PPU {
         return res;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_corrupt_sprites() {
+        // A marker to find where the sprite count is saved
+        let mut ppu = PPU::new_nes_ppu();
+        ppu.sprite_count = 0x0123_4567_89ab_cdef;
+        let mut state = Vec::new();
+        ppu.save(&mut state).unwrap();
+
+        let marker = 0x0123_4567_89ab_cdef_u64.to_le_bytes();
+        let offset = state.windows(8).position(|bytes| bytes == marker).unwrap();
+        state[offset .. offset + 8].copy_from_slice(&9_u64.to_le_bytes());
+
+        let mut ppu = PPU::new_nes_ppu();
+        let e = ppu.load(&mut &state[..]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        state[offset .. offset + 8].copy_from_slice(&8_u64.to_le_bytes());
+        assert!(ppu.load(&mut &state[..]).is_ok());
+    }
+}
@@ -1,5 +1,5 @@
-use crate::mem::Memory;
-use crate::palette::PALETTE;
+use crate::mapper::Mapper;
+use crate::palette::{Color, PALETTE};
 use crate::ppu::PPU;
 use crate::ppu::regs::{
     BACKGROUND_PALETTE_ADDRESSES,
@@ -7,27 +7,43 @@ use crate::ppu::regs::{
     PATTERN_TABLE_ADDRESSES,
 };
 
-use sdl2::pixels::Color;
-use sdl2::rect::Rect;
-use sdl2::render::Canvas;
-use sdl2::video::Window;
+// A snapshot of the PPU's palettes and CHR data, for frontends to display
+// alongside the game screen.
+#[derive(Clone)]
+pub struct DebugView {
+    pub background_palettes: [[Color; 4]; 4],
+    pub sprite_palettes: [[Color; 4]; 4],
+
+    // Each pattern table is 16x16 tiles, so 128 rows of 128 pixels
+    pub pattern_tables: [Vec<Vec<Color>>; 2],
+
+    // The four nametables, as mirrored right now, laid out as they're
+    // scrolled over, so 480 rows of 512 pixels
+    pub nametables: Vec<Vec<Color>>,
+
+    // Where the top left of the screen is in the nametables, from the scroll
+    // that's been set up for the next frame. The screen wraps around at the
+    // edges.
+    pub scroll: (usize, usize),
+}
 
 impl PPU {
-    // For debugging purposes. Renders a pattern table at `x' and `y'.
+    // For debugging purposes. Renders a pattern table, in greyscale.
     fn render_pattern_table(&mut self,
-                            canvas: &mut Canvas<Window>,
-                            pattern_table: u16,
-                            x: i32,
-                            y: i32)
+                            mapper: &mut dyn Mapper,
+                            pattern_table: u16)
+        -> Vec<Vec<Color>>
     {
-        let mut y = y;
-        let mut temp_x = 0;
+        let mut pixels = vec![vec![Color::rgb(0, 0, 0); 128]; 128];
 
         for tile in 0 .. 256 {
+            let x = (tile as usize % 16) * 8;
+            let y = (tile as usize / 16) * 8;
+
             for row in 0 ..= 7 {
                 let addr = pattern_table + (tile * 16) + row;
-                let mut low_byte = self.data.read(addr);
-                let mut high_byte = self.data.read(addr + 8);
+                let mut low_byte = self.data.read(mapper, addr);
+                let mut high_byte = self.data.read(mapper, addr + 8);
 
                 for col in 0 .. 8 {
                     let p1 = (low_byte & 0x80) >> 7;
@@ -37,91 +53,166 @@ impl PPU {
 
                     let palette_index = p1 | p2;
                     let color = match palette_index {
-                        0 => Color::RGB(30, 30, 30),
-                        1 => Color::RGB(128, 128, 128),
-                        2 => Color::RGB(255, 255, 255),
-                        _ => Color::RGB(0, 0, 0),
+                        0 => Color::rgb(30, 30, 30),
+                        1 => Color::rgb(128, 128, 128),
+                        2 => Color::rgb(255, 255, 255),
+                        _ => Color::rgb(0, 0, 0),
                     };
 
-                    canvas.set_draw_color(color);
-
-                    let rect = Rect::new(x + temp_x + 2 * col,
-                                         y + 2 * row as i32,
-                                         2, 2);
-                    canvas.fill_rect(rect).unwrap();
+                    pixels[y + row as usize][x + col] = color;
                 }
             }
+        }
 
-            temp_x += 16;
+        pixels
+    }
 
-            if temp_x == 128 {
-                temp_x = 0;
-                y += 16;
+    // For debugging purposes. Renders all four nametables, with the
+    // background pattern table and the palettes picked by the attribute
+    // tables.
+    fn render_nametables(&mut self, mapper: &mut dyn Mapper) -> Vec<Vec<Color>> {
+        let mut pixels = vec![vec![Color::rgb(0, 0, 0); 512]; 480];
+        let pattern_table = self.ctrl.background_pattern_table_addr();
+
+        let mut palettes = [[Color::rgb(0, 0, 0); 4]; 4];
+        for (i, palette) in palettes.iter_mut().enumerate() {
+            for (offset, color) in palette.iter_mut().enumerate() {
+                // Colour 0 of every palette is the backdrop
+                let address = if offset == 0 { 0x3f00 } else { 0x3f00 + (i * 4 + offset) as u16 };
+                *color = PALETTE[self.data.read(mapper, address) as usize % 64];
             }
         }
-    }
 
-    // For debugging purposes. Displays the palettes and CHR data on the right
-    // side of the screen.
-    pub fn render_tile_data(&mut self, canvas: &mut Canvas<Window>) {
-        let mut x = 256 * 3 + 20;
-        let mut y = 10;
+        for nametable in 0 .. 4 {
+            let base = 0x2000 + nametable * 0x400;
+            let left = (nametable as usize % 2) * 256;
+            let top = (nametable as usize / 2) * 240;
+
+            for tile_row in 0 .. 30 {
+                for tile_col in 0 .. 32 {
+                    let tile = self.data.read(mapper, base + tile_row * 32 + tile_col) as u16;
+
+                    // Each attribute byte covers 4x4 tiles, 2 bits for each
+                    // 2x2 quarter
+                    let attribute = self.data.read(mapper, base + 0x3c0 + (tile_row / 4) * 8 + tile_col / 4);
+                    let shift = ((tile_row & 2) << 1) | (tile_col & 2);
+                    let palette = &palettes[((attribute >> shift) & 3) as usize];
+
+                    for row in 0 .. 8 {
+                        let addr = pattern_table + (tile * 16) + row;
+                        let low_byte = self.data.read(mapper, addr);
+                        let high_byte = self.data.read(mapper, addr + 8);
+
+                        let y = top + (tile_row * 8 + row) as usize;
+                        for col in 0 .. 8 {
+                            let p1 = (low_byte >> (7 - col)) & 1;
+                            let p2 = ((high_byte >> (7 - col)) & 1) << 1;
+
+                            let x = left + tile_col as usize * 8 + col;
+                            pixels[y][x] = palette[(p1 | p2) as usize];
+                        }
+                    }
+                }
+            }
+        }
 
-        //
-        // Palettes
-        //
+        pixels
+    }
 
-        let width = 12;
-        let height = 8;
+    // Where the screen starts in the nametables, from t and fine x, which are
+    // copied to v before the next frame is rendered
+    fn scroll_position(&self) -> (usize, usize) {
+        let t = self.t as usize;
+        let coarse_x = t & 0x1f;
+        let coarse_y = (t >> 5) & 0x1f;
+        let nametable = (t >> 10) & 3;
+        let fine_y = (t >> 12) & 7;
+
+        // Coarse Y can be set to 30 and 31, which wrap without changing the
+        // nametable, but that's past the bottom either way
+        let x = (nametable & 1) * 256 + coarse_x * 8 + self.x as usize;
+        let y = (nametable >> 1) * 240 + coarse_y * 8 + fine_y;
+
+        (x, y % 480)
+    }
 
-        for base in BACKGROUND_PALETTE_ADDRESSES.iter() {
-            for offset in 0 ..= 3 {
-                let i = self.data.read(*base + offset as u16) as usize;
-                canvas.set_draw_color(PALETTE[i % 64]);
+    fn render_palettes(&mut self,
+                       mapper: &mut dyn Mapper,
+                       addresses: &[u16; 4])
+        -> [[Color; 4]; 4]
+    {
+        let mut palettes = [[Color::rgb(0, 0, 0); 4]; 4];
 
-                let rect = Rect::new(x + (width as i32) * offset, y, width, height);
-                canvas.fill_rect(rect).unwrap();
+        for (palette, base) in palettes.iter_mut().zip(addresses.iter()) {
+            for (offset, color) in palette.iter_mut().enumerate() {
+                let i = self.data.read(mapper, *base + offset as u16) as usize;
+                *color = PALETTE[i % 64];
             }
-
-            y += 10;
         }
 
-        y = 10;
-        x = 256 * 3 + 20 + 48 + 16;
-        for base in SPRITE_PALETTE_ADDRESSES.iter() {
-            for offset in 0 ..= 3 {
-                let i = self.data.read(*base + offset as u16) as usize;
-                canvas.set_draw_color(PALETTE[i % 64]);
-
-                let rect = Rect::new(x + (width as i32) * offset, y, width, height);
-                canvas.fill_rect(rect).unwrap();
-            }
+        palettes
+    }
 
-            y += 10;
+    // For debugging purposes. Captures the palettes, CHR data and nametables.
+    pub fn debug_view(&mut self, mapper: &mut dyn Mapper) -> DebugView {
+        DebugView {
+            background_palettes: self.render_palettes(mapper, &BACKGROUND_PALETTE_ADDRESSES),
+            sprite_palettes: self.render_palettes(mapper, &SPRITE_PALETTE_ADDRESSES),
+            pattern_tables: [
+                self.render_pattern_table(mapper, PATTERN_TABLE_ADDRESSES[0]),
+                self.render_pattern_table(mapper, PATTERN_TABLE_ADDRESSES[1]),
+            ],
+            nametables: self.render_nametables(mapper),
+            scroll: self.scroll_position(),
         }
+    }
+}
 
-        y += 20;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::Mapper0;
 
-        //
-        // CHR
-        //
-        x = 256 * 3 + 20;
-        self.render_pattern_table(canvas, PATTERN_TABLE_ADDRESSES[0], x, y);
-        self.render_pattern_table(canvas, PATTERN_TABLE_ADDRESSES[1], x + 144, y);
+    fn write_vram(ppu: &mut PPU, mapper: &mut dyn Mapper, address: u16, val: u8) {
+        ppu.write_register(mapper, 0x2006, (address >> 8) as u8);
+        ppu.write_register(mapper, 0x2006, address as u8);
+        ppu.write_register(mapper, 0x2007, val);
     }
 
-    pub fn render_tile_borders(&mut self, canvas: &mut Canvas<Window>) {
-        let scale = 3;
-        canvas.set_draw_color(Color::RGB(200, 200, 200));
-
-        for x in 0 .. 32 {
-            for y in 0 .. 30 {
-                let rect = Rect::new(8 * x * scale,
-                                     8 * y * scale,
-                                     8 * scale as u32,
-                                     8 * scale as u32);
-                canvas.draw_rect(rect).unwrap();
-            }
-        }
+    #[test]
+    fn test_nametables() {
+        // Tile 1 is solid colour 1
+        let mut chr = vec![0; 0x2000];
+        chr[0x10 .. 0x18].copy_from_slice(&[0xff; 8]);
+
+        // Vertical mirroring, so the bottom nametables are the top ones
+        let mut mapper = Mapper0::new_mapper(vec![0; 0x8000], chr, 1);
+        let mut ppu = PPU::new_nes_ppu();
+
+        write_vram(&mut ppu, &mut mapper, 0x3f00, 0x0f);
+        write_vram(&mut ppu, &mut mapper, 0x3f01, 0x2a);
+        write_vram(&mut ppu, &mut mapper, 0x3f05, 0x16);
+        write_vram(&mut ppu, &mut mapper, 0x2000, 0x01);
+        write_vram(&mut ppu, &mut mapper, 0x2002, 0x01);
+        write_vram(&mut ppu, &mut mapper, 0x23c0, 0x01);
+
+        // Scrolled to (13, 33) in the right hand nametable
+        ppu.write_register(&mut mapper, 0x2000, 0x01);
+        ppu.write_register(&mut mapper, 0x2005, 13);
+        ppu.write_register(&mut mapper, 0x2005, 33);
+
+        let view = ppu.debug_view(&mut mapper);
+        let (red, green, black) = (PALETTE[0x16], PALETTE[0x2a], PALETTE[0x0f]);
+
+        assert_eq!(view.nametables[0][0], red);
+        assert_eq!(view.nametables[7][7], red);
+        assert_eq!(view.nametables[8][8], black);
+        assert_eq!(view.nametables[0][256], black);
+        assert_eq!(view.nametables[240][0], red);
+
+        // The attribute byte only picks a palette for the top left 2x2 tiles
+        assert_eq!(view.nametables[0][16], green);
+
+        assert_eq!(view.scroll, (256 + 13, 33));
     }
 }
@@ -1,14 +1,10 @@
-use std::cell::RefCell;
-use std::fs::File;
 use std::io::{Read, Write};
 use std::io;
-use std::rc::Rc;
 
 use crate::mapper::Mapper;
 use crate::mem::Memory;
 
 pub struct PPUData {
-    pub mapper:   Rc<RefCell<Box<dyn Mapper>>>,
     nametables:   [u8; 4096],
     palette:      [u8; 0x20],
 }
@@ -23,22 +19,52 @@ pub const PATTERN_TABLE_ADDRESSES: [u16; 2] =
     [0x0000, 0x1000];
 
 impl Memory for PPUData {
-    fn read(&mut self, address: u16) -> u8 {
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
+        output.write_all(&self.nametables)?;
+        output.write_all(&self.palette)?;
+
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        input.read_exact(&mut self.nametables)?;
+        input.read_exact(&mut self.palette)?;
+
+        Ok(())
+    }
+}
+
+impl PPUData {
+    pub fn new_ppu_data() -> Self {
+        Self {
+            nametables: [0; 4096],
+            palette: [
+                // These are the start-up palette values to pass blarrg's PPU tests
+                0x09,0x01,0x00,0x01,
+                0x00,0x02,0x02,0x0D,
+                0x08,0x10,0x08,0x24,
+                0x00,0x00,0x04,0x2C,
+                0x09,0x01,0x34,0x03,
+                0x00,0x04,0x00,0x14,
+                0x08,0x3A,0x00,0x02,
+                0x00,0x20,0x2C,0x08
+            ],
+        }
+    }
+
+    pub fn read(&mut self, mapper: &mut dyn Mapper, address: u16) -> u8 {
         // Check if the cartridge has mapped this address
-        {
-            let mut mapper = self.mapper.borrow_mut();
-            for range in mapper.address_maps() {
-                if range.contains(&address) {
-                    return mapper.read(address);
-                }
+        for range in mapper.address_maps() {
+            if range.contains(&address) {
+                return mapper.read(address);
             }
         }
 
         let address = address % 0x4000;
         match address {
-            0x0000 ..= 0x1fff => self.mapper.borrow_mut().read(address),
+            0x0000 ..= 0x1fff => mapper.read(address),
             0x2000 ..= 0x3eff => {
-                let mirrored_address = self.nametable_mirror_address(address);
+                let mirrored_address = self.nametable_mirror_address(mapper, address);
                 self.nametables[mirrored_address]
             },
             0x3f00 ..= 0x3fff => {
@@ -57,24 +83,21 @@ impl Memory for PPUData {
         }
     }
 
-    fn write(&mut self, address: u16, val: u8) {
+    pub fn write(&mut self, mapper: &mut dyn Mapper, address: u16, val: u8) {
         // Check if the cartridge has mapped this address
-        {
-            let mut mapper = self.mapper.borrow_mut();
-            for range in mapper.address_maps() {
-                if range.contains(&address) {
-                    mapper.write(address, val);
-                    return;
-                }
+        for range in mapper.address_maps() {
+            if range.contains(&address) {
+                mapper.write(address, val);
+                return;
             }
         }
 
         let address = address % 0x4000;
         match address {
-            0x0000 ..= 0x1fff => self.mapper.borrow_mut().write(address, val),
+            0x0000 ..= 0x1fff => mapper.write(address, val),
             0x2000 ..= 0x3eff => {
                 debug!("writing 0x{:02X} to nametable 0x{:04X}", val, address);
-                let mirrored_address = self.nametable_mirror_address(address);
+                let mirrored_address = self.nametable_mirror_address(mapper, address);
                 self.nametables[mirrored_address] = val;
             },
             0x3f00 ..= 0x3fff => {
@@ -94,43 +117,7 @@ impl Memory for PPUData {
         }
     }
 
-    fn save(&self, output: &mut File) -> io::Result<()> {
-        self.mapper.borrow_mut().save(output)?;
-        output.write(&self.nametables)?;
-        output.write(&self.palette)?;
-
-        Ok(())
-    }
-
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
-        self.mapper.borrow_mut().load(input)?;
-        input.read(&mut self.nametables)?;
-        input.read(&mut self.palette)?;
-
-        Ok(())
-    }
-}
-
-impl PPUData {
-    pub fn new_ppu_data(cartridge: Rc<RefCell<Box<dyn Mapper>>>) -> Self {
-        Self {
-            mapper: cartridge,
-            nametables: [0; 4096],
-            palette: [
-                // These are the start-up palette values to pass blarrg's PPU tests
-                0x09,0x01,0x00,0x01,
-                0x00,0x02,0x02,0x0D,
-                0x08,0x10,0x08,0x24,
-                0x00,0x00,0x04,0x2C,
-                0x09,0x01,0x34,0x03,
-                0x00,0x04,0x00,0x14,
-                0x08,0x3A,0x00,0x02,
-                0x00,0x20,0x2C,0x08
-            ],
-        }
-    }
-
-    fn nametable_mirror_address(&self, address: u16) -> usize {
+    fn nametable_mirror_address(&self, mapper: &dyn Mapper, address: u16) -> usize {
         // Calculates the mirrored nametable address (as an index into the
         // nametable array)
         // https://wiki.nesdev.com/w/index.php/Mirroring#Nametable_Mirroring
@@ -139,7 +126,7 @@ impl PPUData {
         let table = address / 0x400;
         let offset = address % 0x400;
         let index = 0x2000
-            + self.mapper.borrow().mirror_mode().coefficients()[table as usize] * 0x400
+            + mapper.mirror_mode().coefficients()[table as usize] * 0x400
             + offset as usize;
 
         index % 2048
@@ -1,6 +1,5 @@
 use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
 
 use crate::mem::Memory;
 
@@ -28,13 +27,13 @@ impl Memory for OAM {
         }
     }
 
-    fn save(&self, output: &mut File) -> io::Result<()> {
-        output.write(&self.data)?;
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
+        output.write_all(&self.data)?;
         Ok(())
     }
 
-    fn load(&mut self, input: &mut File) -> io::Result<()> {
-        input.read(&mut self.data)?;
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        input.read_exact(&mut self.data)?;
         Ok(())
     }
 }
@@ -0,0 +1,225 @@
+// Rewind
+//
+// Every few frames, the whole console state is saved into a bounded ring
+// buffer in memory, and rewinding loads those states back, newest first.
+//
+// States are mostly the same from one snapshot to the next (the ROM alone
+// makes up most of a mapper's state), so they can optionally be stored as
+// deltas. Only the newest snapshot is kept whole, and every other snapshot is
+// stored as the XOR against the one after it, with runs of zeroes squashed.
+// Dropping the oldest snapshot is free, and rewinding only ever needs to undo
+// a single delta.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RewindSettings {
+    // How many frames to run between snapshots
+    pub interval: u64,
+
+    // The maximum number of snapshots to keep
+    pub capacity: usize,
+
+    // Whether to store all but the newest snapshot as deltas
+    pub delta_compression: bool,
+}
+
+impl Default for RewindSettings {
+    // About 30 seconds of history
+    fn default() -> Self {
+        Self {
+            interval: 4,
+            capacity: 450,
+            delta_compression: true,
+        }
+    }
+}
+
+enum Snapshot {
+    Full(Vec<u8>),
+    Delta(Vec<u8>),
+}
+
+pub struct Rewind {
+    settings: RewindSettings,
+    snapshots: VecDeque<Snapshot>,
+    frames: u64,
+
+    // Whether the newest snapshot was taken on the frame that's running now
+    current: bool,
+}
+
+impl Rewind {
+    pub fn new(settings: RewindSettings) -> Self {
+        Self {
+            settings,
+            snapshots: VecDeque::new(),
+            frames: 0,
+            current: false,
+        }
+    }
+
+    // Called once a frame, and returns whether a snapshot is due
+    pub fn tick(&mut self) -> bool {
+        self.frames += 1;
+        self.current = false;
+        self.frames % self.settings.interval.max(1) == 0
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if self.settings.delta_compression {
+            if let Some(newest) = self.snapshots.back_mut() {
+                if let Snapshot::Full(previous) = newest {
+                    if previous.len() == state.len() {
+                        *newest = Snapshot::Delta(encode_delta(previous, &state));
+                    }
+                }
+            }
+        }
+
+        self.snapshots.push_back(Snapshot::Full(state));
+        self.current = true;
+
+        while self.snapshots.len() > self.settings.capacity.max(1) {
+            self.snapshots.pop_front();
+        }
+    }
+
+    // Whether the newest snapshot is the same as the state the console is in
+    // now, which is no use to rewind to
+    pub fn is_current(&self) -> bool {
+        self.current
+    }
+
+    // Removes and returns the newest snapshot
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.current = false;
+
+        let newest = match self.snapshots.pop_back()? {
+            Snapshot::Full(state) => state,
+            Snapshot::Delta(_)    => unreachable!("newest snapshot is a delta"),
+        };
+
+        if let Some(next) = self.snapshots.back_mut() {
+            if let Snapshot::Delta(delta) = next {
+                *next = Snapshot::Full(apply_delta(&newest, delta));
+            }
+        }
+
+        Some(newest)
+    }
+}
+
+// A delta is a series of records, each of which is a number of bytes that are
+// unchanged, followed by a number of bytes to XOR in, and then those bytes.
+// Both numbers are u32s.
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut i = 0;
+
+    while i < from.len() {
+        let start = i;
+        while i < from.len() && from[i] == to[i] {
+            i += 1;
+        }
+        let skip = i - start;
+
+        let start = i;
+        while i < from.len() && from[i] != to[i] {
+            i += 1;
+        }
+
+        delta.extend_from_slice(&(skip as u32).to_le_bytes());
+        delta.extend_from_slice(&((i - start) as u32).to_le_bytes());
+        delta.extend(from[start .. i].iter().zip(&to[start .. i]).map(|(a, b)| a ^ b));
+    }
+
+    delta
+}
+
+fn apply_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut state = base.to_vec();
+    let mut i = 0;
+    let mut d = 0;
+
+    while d < delta.len() {
+        let skip = u32::from_le_bytes(delta[d .. d + 4].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(delta[d + 4 .. d + 8].try_into().unwrap()) as usize;
+        d += 8;
+
+        i += skip;
+        for b in &delta[d .. d + len] {
+            state[i] ^= b;
+            i += 1;
+        }
+        d += len;
+    }
+
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(capacity: usize, delta_compression: bool) -> RewindSettings {
+        RewindSettings { interval: 1, capacity, delta_compression }
+    }
+
+    #[test]
+    fn test_delta() {
+        let a = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let b = vec![1, 9, 9, 4, 5, 6, 7, 0];
+
+        let delta = encode_delta(&a, &b);
+        assert_eq!(apply_delta(&b, &delta), a);
+        assert_eq!(apply_delta(&a, &delta), b);
+
+        assert!(encode_delta(&a, &a).len() == 8);
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        for &compress in &[false, true] {
+            let mut rewind = Rewind::new(settings(3, compress));
+
+            for i in 0 .. 5u8 {
+                rewind.push(vec![i; 16]);
+            }
+
+            // Newest first, and the oldest two have been dropped
+            assert_eq!(rewind.pop(), Some(vec![4; 16]));
+            assert_eq!(rewind.pop(), Some(vec![3; 16]));
+            assert_eq!(rewind.pop(), Some(vec![2; 16]));
+            assert_eq!(rewind.pop(), None);
+        }
+    }
+
+    #[test]
+    fn test_mismatched_lengths() {
+        let mut rewind = Rewind::new(settings(10, true));
+        rewind.push(vec![1; 4]);
+        rewind.push(vec![2; 8]);
+        rewind.push(vec![3; 8]);
+
+        assert_eq!(rewind.pop(), Some(vec![3; 8]));
+        assert_eq!(rewind.pop(), Some(vec![2; 8]));
+        assert_eq!(rewind.pop(), Some(vec![1; 4]));
+    }
+
+    #[test]
+    fn test_current() {
+        let mut rewind = Rewind::new(settings(10, true));
+        assert!(rewind.tick());
+        rewind.push(vec![1; 4]);
+        assert!(rewind.is_current());
+
+        // Only until the next frame, or until it's been popped
+        rewind.tick();
+        assert!(!rewind.is_current());
+        rewind.push(vec![2; 4]);
+        rewind.pop();
+        assert!(!rewind.is_current());
+    }
+}
@@ -0,0 +1,334 @@
+// Save state container
+//
+// Save states are split into named sections, one per component, so that a
+// state can be checked against the cartridge it was made with, and so that
+// each component's data can be found (and versioned) independently.
+//
+//   magic     4 bytes  "NESS"
+//   version   u16      version of the container format
+//   rom hash  16 bytes MD5 of the ROM file the state was made with
+//
+// followed by any number of sections, each of which is
+//
+//   tag       4 bytes  which component the section belongs to
+//   version   u16      version of the data in the section
+//   length    u32      number of bytes of data that follow
+//   data      `length` bytes
+//
+// All numbers are little endian. Sections that aren't recognised are skipped
+// over when loading. Sections from older versions are handed to the component
+// along with their version, so that it can read the layout it used to save.
+// Only sections from newer versions can't be loaded.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Write};
+use std::io;
+
+use crate::serde;
+
+const MAGIC: [u8; 4] = [0x4e, 0x45, 0x53, 0x53];
+
+// The version of the container format. Changes to what a component saves
+// should bump the version of its section instead.
+const VERSION: u16 = 1;
+
+// A section's tag, along with the version of the data stored in it
+pub struct Section(pub [u8; 4], pub u16);
+
+pub const CPU:         Section = Section(*b"CPU ", 1);
+pub const RAM:         Section = Section(*b"RAM ", 1);
+pub const PPU:         Section = Section(*b"PPU ", 1);
+pub const APU:         Section = Section(*b"APU ", 1);
+pub const MAPPER:      Section = Section(*b"MAPR", 1);
+pub const CONTROLLERS: Section = Section(*b"CTRL", 1);
+
+// When the state was saved, in seconds since the Unix epoch
+pub const TIMESTAMP:   Section = Section(*b"TIME", 1);
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    IO(io::Error),
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    ROMMismatch,
+    MissingSection(String),
+    UnsupportedSection(String, u16),
+    InvalidSection(String),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::IO(e) => write!(f, "{}", e),
+            SaveStateError::InvalidMagic => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(v) => {
+                write!(f, "unsupported save state version {}", v)
+            },
+            SaveStateError::ROMMismatch => {
+                write!(f, "save state was made with a different ROM")
+            },
+            SaveStateError::MissingSection(tag) => {
+                write!(f, "save state has no {} section", tag)
+            },
+            SaveStateError::UnsupportedSection(tag, v) => {
+                write!(f, "unsupported version {} of the {} section", v, tag)
+            },
+            SaveStateError::InvalidSection(tag) => {
+                write!(f, "the {} section is the wrong size", tag)
+            },
+        }
+    }
+}
+
+fn tag_name(tag: &[u8; 4]) -> String {
+    String::from_utf8_lossy(tag).trim_end().to_string()
+}
+
+pub struct Writer<'a> {
+    output: &'a mut dyn Write,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(output: &'a mut dyn Write, rom_hash: &[u8; 16])
+        -> Result<Self, SaveStateError>
+    {
+        output.write_all(&MAGIC).map_err(SaveStateError::IO)?;
+        serde::encode_u16(output, VERSION).map_err(SaveStateError::IO)?;
+        output.write_all(rom_hash).map_err(SaveStateError::IO)?;
+
+        Ok(Self { output })
+    }
+
+    // Writes a section, whose data is whatever `save' writes. The length has
+    // to come first, so the data is collected in memory beforehand.
+    pub fn section<F>(&mut self, section: &Section, save: F)
+        -> Result<(), SaveStateError>
+        where F: FnOnce(&mut dyn Write) -> io::Result<()>
+    {
+        self.write_section(section, save).map_err(SaveStateError::IO)
+    }
+
+    fn write_section<F>(&mut self, section: &Section, save: F) -> io::Result<()>
+        where F: FnOnce(&mut dyn Write) -> io::Result<()>
+    {
+        let Section(tag, version) = section;
+
+        let mut data = Vec::new();
+        save(&mut data)?;
+
+        self.output.write_all(tag)?;
+        serde::encode_u16(self.output, *version)?;
+        serde::encode_u32(self.output, data.len() as u32)?;
+        self.output.write_all(&data)?;
+
+        Ok(())
+    }
+}
+
+pub struct Reader {
+    // The version and data of each section
+    sections: HashMap<[u8; 4], (u16, Vec<u8>)>,
+}
+
+impl Reader {
+    // Checks the header against the cartridge that's loaded, and reads in all
+    // of the sections.
+    pub fn new(input: &mut dyn Read, rom_hash: &[u8; 16])
+        -> Result<Self, SaveStateError>
+    {
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic).map_err(SaveStateError::IO)?;
+        if magic != MAGIC {
+            return Err(SaveStateError::InvalidMagic);
+        }
+
+        let version = serde::decode_u16(input).map_err(SaveStateError::IO)?;
+        if version != VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+
+        let mut hash = [0; 16];
+        input.read_exact(&mut hash).map_err(SaveStateError::IO)?;
+        if hash != *rom_hash {
+            return Err(SaveStateError::ROMMismatch);
+        }
+
+        let mut sections = HashMap::new();
+
+        loop {
+            // The file can only end between sections, not part way through a
+            // tag
+            let mut tag = Vec::new();
+            input.take(4).read_to_end(&mut tag).map_err(SaveStateError::IO)?;
+            let tag: [u8; 4] = match tag.len() {
+                0 => break,
+                4 => [tag[0], tag[1], tag[2], tag[3]],
+                _ => {
+                    let message = "save state ends part way through a section";
+                    return Err(SaveStateError::IO(io::Error::new(io::ErrorKind::UnexpectedEof, message)));
+                },
+            };
+
+            let version = serde::decode_u16(input).map_err(SaveStateError::IO)?;
+            let length = serde::decode_u32(input).map_err(SaveStateError::IO)?;
+
+            // The length isn't trusted to allocate up front, since a corrupt
+            // state could claim gigabytes
+            let mut data = Vec::new();
+            input.take(length as u64).read_to_end(&mut data).map_err(SaveStateError::IO)?;
+            if data.len() != length as usize {
+                return Err(SaveStateError::InvalidSection(tag_name(&tag)));
+            }
+
+            sections.insert(tag, (version, data));
+        }
+
+        Ok(Self { sections })
+    }
+
+    // Reads a section using `load', which is given the version that the
+    // section was saved with, making sure it reads exactly the data that was
+    // written.
+    pub fn section<F>(&mut self, section: &Section, load: F)
+        -> Result<(), SaveStateError>
+        where F: FnOnce(&mut dyn Read, u16) -> io::Result<()>
+    {
+        let Section(tag, version) = section;
+
+        let (found_version, data) = self.sections.get(tag)
+            .ok_or_else(|| SaveStateError::MissingSection(tag_name(tag)))?;
+
+        if *found_version == 0 || *found_version > *version {
+            return Err(SaveStateError::UnsupportedSection(tag_name(tag), *found_version));
+        }
+
+        let mut remaining = &data[..];
+        match load(&mut remaining, *found_version) {
+            Ok(()) if remaining.is_empty() => Ok(()),
+            Ok(()) => Err(SaveStateError::InvalidSection(tag_name(tag))),
+            Err(ref e) if matches!(e.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData) => {
+                Err(SaveStateError::InvalidSection(tag_name(tag)))
+            },
+            Err(e) => Err(SaveStateError::IO(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: [u8; 16] = [7; 16];
+    const OTHER: Section = Section(*b"OTHR", 2);
+
+    fn write_test_state() -> Vec<u8> {
+        let mut state = Vec::new();
+
+        {
+            let mut writer = Writer::new(&mut state, &HASH).unwrap();
+            writer.section(&CPU, |fh| serde::encode_u16(fh, 0xbeef)).unwrap();
+            writer.section(&OTHER, |fh| serde::encode_u64(fh, 42)).unwrap();
+            writer.section(&RAM, |fh| serde::encode_u8(fh, 0x12)).unwrap();
+        }
+
+        state
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let state = write_test_state();
+        let mut reader = Reader::new(&mut &state[..], &HASH).unwrap();
+
+        // Sections can be read in any order
+        let mut ram = 0;
+        reader.section(&RAM, |fh, _| { ram = serde::decode_u8(fh)?; Ok(()) }).unwrap();
+        assert_eq!(ram, 0x12);
+
+        let mut cpu = 0;
+        reader.section(&CPU, |fh, _| { cpu = serde::decode_u16(fh)?; Ok(()) }).unwrap();
+        assert_eq!(cpu, 0xbeef);
+    }
+
+    #[test]
+    fn test_older_section() {
+        let state = write_test_state();
+        let mut reader = Reader::new(&mut &state[..], &HASH).unwrap();
+
+        // A section from before its layout changed is loaded with its version
+        let mut found_version = 0;
+        reader.section(&Section(OTHER.0, OTHER.1 + 1), |fh, version| {
+            found_version = version;
+            serde::decode_u64(fh).map(|_| ())
+        }).unwrap();
+        assert_eq!(found_version, OTHER.1);
+    }
+
+    #[test]
+    fn test_rom_mismatch() {
+        let state = write_test_state();
+        match Reader::new(&mut &state[..], &[0; 16]) {
+            Err(SaveStateError::ROMMismatch) => { },
+            _ => panic!("expected a ROM mismatch"),
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        let state = write_test_state();
+
+        // Cut short in the last section's data, and in the middle of a tag
+        match Reader::new(&mut &state[.. state.len() - 1], &HASH) {
+            Err(SaveStateError::InvalidSection(tag)) => assert_eq!(tag, "RAM"),
+            _ => panic!("expected an invalid section"),
+        }
+
+        let mut state = state;
+        state.extend_from_slice(b"TI");
+        match Reader::new(&mut &state[..], &HASH) {
+            Err(SaveStateError::IO(e)) => assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof),
+            _ => panic!("expected an unexpected EOF"),
+        }
+
+        // A huge length fails when the data runs out, rather than up front
+        let mut state = write_test_state();
+        state.extend_from_slice(b"HUGE");
+        state.extend_from_slice(&[1, 0, 0xff, 0xff, 0xff, 0xff]);
+        match Reader::new(&mut &state[..], &HASH) {
+            Err(SaveStateError::InvalidSection(tag)) => assert_eq!(tag, "HUGE"),
+            _ => panic!("expected an invalid section"),
+        }
+    }
+
+    #[test]
+    fn test_bad_sections() {
+        let state = write_test_state();
+        let mut reader = Reader::new(&mut &state[..], &HASH).unwrap();
+
+        match reader.section(&PPU, |_, _| Ok(())) {
+            Err(SaveStateError::MissingSection(tag)) => assert_eq!(tag, "PPU"),
+            _ => panic!("expected a missing section"),
+        }
+
+        // A section from a newer version than this one
+        match reader.section(&Section(OTHER.0, OTHER.1 - 1), |_, _| Ok(())) {
+            Err(SaveStateError::UnsupportedSection(tag, v)) => {
+                assert_eq!(tag, "OTHR");
+                assert_eq!(v, OTHER.1);
+            },
+            _ => panic!("expected an unsupported section"),
+        }
+
+        match reader.section(&CPU, |fh, _| serde::decode_u8(fh).map(|_| ())) {
+            Err(SaveStateError::InvalidSection(tag)) => assert_eq!(tag, "CPU"),
+            _ => panic!("expected an invalid section"),
+        }
+
+        // Data that the component rejects
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "bad data");
+        match reader.section(&RAM, |_, _| Err(invalid())) {
+            Err(SaveStateError::InvalidSection(tag)) => assert_eq!(tag, "RAM"),
+            _ => panic!("expected an invalid section"),
+        }
+    }
+}
@@ -0,0 +1,647 @@
+// The SDL frontend
+//
+// Implements the video, audio and input frontend traits on top of an SDL
+// window, audio queue, event pump and game controllers.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nes::{AudioSink, Button, DebugView, InputEvent, InputSource, Key, VideoSink};
+use nes::{Color, NES_PPU_DEBUG};
+
+use crate::bindings::{Action, Bindings, PadAction};
+
+use sdl2::EventPump;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::controller;
+use sdl2::controller::{Axis, GameController};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Scancode;
+use sdl2::mouse::MouseButton;
+use sdl2::pixels;
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+
+const NES_FPS: f64 = 60.0;
+const FRAME_DURATION: Duration = Duration::from_millis(((1.0 / NES_FPS) * 1000.0) as u64);
+
+// The queue is full of f32s, and we want to maintain roughly 16384 samples in
+// the queue at all times, so 4 * 16384 is the goal size.
+const AUDIO_QUEUE_HIGH_WATER_MARK: u32 = 4 * 16384;
+
+const SCALE: u32 = 3;
+
+// Where the nametables go in PPU debug mode, to the right of the pattern
+// tables
+const NAMETABLES_X: i32 = 256 * SCALE as i32 + 440;
+const NAMETABLES_Y: i32 = 10;
+
+const ALL_BUTTONS: [Button; 8] = [
+    Button::A, Button::B, Button::Select, Button::Start,
+    Button::Up, Button::Down, Button::Left, Button::Right,
+];
+
+// How far the left stick has to be pushed before it counts as the d-pad
+const STICK_DEAD_ZONE: i16 = 16384;
+
+// The Family BASIC key in the same place as a key on a US keyboard. The keys
+// that the US keyboard doesn't have go where they are on a Japanese keyboard,
+// or somewhere nearby.
+fn family_basic_key(scancode: Scancode) -> Option<Key> {
+    let key = match scancode {
+        Scancode::A => Key::A, Scancode::B => Key::B, Scancode::C => Key::C,
+        Scancode::D => Key::D, Scancode::E => Key::E, Scancode::F => Key::F,
+        Scancode::G => Key::G, Scancode::H => Key::H, Scancode::I => Key::I,
+        Scancode::J => Key::J, Scancode::K => Key::K, Scancode::L => Key::L,
+        Scancode::M => Key::M, Scancode::N => Key::N, Scancode::O => Key::O,
+        Scancode::P => Key::P, Scancode::Q => Key::Q, Scancode::R => Key::R,
+        Scancode::S => Key::S, Scancode::T => Key::T, Scancode::U => Key::U,
+        Scancode::V => Key::V, Scancode::W => Key::W, Scancode::X => Key::X,
+        Scancode::Y => Key::Y, Scancode::Z => Key::Z,
+
+        Scancode::Num0 => Key::Num0, Scancode::Num1 => Key::Num1,
+        Scancode::Num2 => Key::Num2, Scancode::Num3 => Key::Num3,
+        Scancode::Num4 => Key::Num4, Scancode::Num5 => Key::Num5,
+        Scancode::Num6 => Key::Num6, Scancode::Num7 => Key::Num7,
+        Scancode::Num8 => Key::Num8, Scancode::Num9 => Key::Num9,
+
+        Scancode::F1 => Key::F1, Scancode::F2 => Key::F2,
+        Scancode::F3 => Key::F3, Scancode::F4 => Key::F4,
+        Scancode::F5 => Key::F5, Scancode::F6 => Key::F6,
+        Scancode::F7 => Key::F7, Scancode::F8 => Key::F8,
+
+        Scancode::Minus          => Key::Minus,
+        Scancode::Equals         => Key::Caret,
+        Scancode::Grave          => Key::Yen,
+        Scancode::LeftBracket    => Key::At,
+        Scancode::RightBracket   => Key::LeftBracket,
+        Scancode::Backslash      => Key::RightBracket,
+        Scancode::Semicolon      => Key::Semicolon,
+        Scancode::Apostrophe     => Key::Colon,
+        Scancode::Comma          => Key::Comma,
+        Scancode::Period         => Key::Period,
+        Scancode::Slash          => Key::Slash,
+        Scancode::NonUsBackslash => Key::Underscore,
+
+        Scancode::Escape    => Key::Escape,
+        Scancode::LCtrl     => Key::Control,
+        Scancode::LShift    => Key::LeftShift,
+        Scancode::RShift    => Key::RightShift,
+        Scancode::LAlt      => Key::Graph,
+        Scancode::RAlt      => Key::Kana,
+        Scancode::End       => Key::Stop,
+        Scancode::Return    => Key::Return,
+        Scancode::Space     => Key::Space,
+        Scancode::Home      => Key::ClearHome,
+        Scancode::Insert    => Key::Insert,
+        Scancode::Delete    => Key::Delete,
+        Scancode::Backspace => Key::Delete,
+        Scancode::Up        => Key::Up,
+        Scancode::Down      => Key::Down,
+        Scancode::Left      => Key::Left,
+        Scancode::Right     => Key::Right,
+
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+// A key event for the Family BASIC keyboard, if the key is on it
+fn typed_key(event: &Event) -> Option<InputEvent> {
+    let (scancode, pressed) = match *event {
+        Event::KeyDown { scancode: Some(scancode), .. } => (scancode, true),
+        Event::KeyUp { scancode: Some(scancode), .. }   => (scancode, false),
+        _                                               => return None,
+    };
+
+    family_basic_key(scancode).map(|key| InputEvent::Key(key, pressed))
+}
+
+// The NES pixel under a point in the window, if it's over the NES screen
+fn screen_pixel(x: i32, y: i32) -> Option<(u8, u8)> {
+    let scale = SCALE as i32;
+    if x < 0 || y < 0 || x >= 256 * scale || y >= 240 * scale {
+        return None;
+    }
+
+    Some(((x / scale) as u8, (y / scale) as u8))
+}
+
+fn sdl_color(color: Color) -> pixels::Color {
+    pixels::Color::RGB(color.r, color.g, color.b)
+}
+
+pub fn init() -> (sdl2::Sdl, Canvas<Window>) {
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+
+    let mut width = 256 * SCALE;
+    let height = 240 * SCALE;
+
+    if *NES_PPU_DEBUG {
+        // Make room for the two pattern tables, side by side, and the
+        // nametables
+        width = NAMETABLES_X as u32 + 512 + 10;
+    }
+
+    let window = video_subsystem.window("nes", width, height)
+        .position_centered()
+        .build()
+        .unwrap();
+
+    let mut canvas = window.into_canvas()
+        .target_texture()
+        .build()
+        .unwrap();
+    debug!("canvas: {}", canvas.info().name);
+
+    for _ in 0 .. 2 {
+        canvas.clear();
+        canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
+        canvas.fill_rect(Rect::new(0, 0, width, height)).unwrap();
+        canvas.present();
+    }
+
+    (sdl_context, canvas)
+}
+
+pub struct Video<'a> {
+    canvas: Canvas<Window>,
+    texture: Texture<'a>,
+    nametables: Texture<'a>,
+    debug: Option<DebugView>,
+    fps_start: Instant,
+}
+
+impl<'a> Video<'a> {
+    pub fn new(canvas: Canvas<Window>,
+               texture_creator: &'a TextureCreator<WindowContext>)
+        -> Self
+    {
+        let texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, 256 * SCALE, 240 * SCALE)
+            .unwrap();
+
+        let nametables = texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, 512, 480)
+            .unwrap();
+
+        Self {
+            canvas,
+            texture,
+            nametables,
+            debug: None,
+            fps_start: Instant::now(),
+        }
+    }
+
+    // For debugging purposes. Renders a pattern table at `x' and `y'.
+    fn render_pattern_table(&mut self, pattern_table: &[Vec<Color>], x: i32, y: i32) {
+        for (row, pixels) in pattern_table.iter().enumerate() {
+            for (col, color) in pixels.iter().enumerate() {
+                self.canvas.set_draw_color(sdl_color(*color));
+
+                let rect = Rect::new(x + 2 * col as i32,
+                                     y + 2 * row as i32,
+                                     2, 2);
+                self.canvas.fill_rect(rect).unwrap();
+            }
+        }
+    }
+
+    // For debugging purposes. Displays the palettes and CHR data on the right
+    // side of the screen.
+    fn render_tile_data(&mut self, debug: &DebugView) {
+        let mut x = 256 * SCALE as i32 + 20;
+        let mut y = 10;
+
+        //
+        // Palettes
+        //
+
+        let width = 12;
+        let height = 8;
+
+        for palette in debug.background_palettes.iter() {
+            for (offset, color) in palette.iter().enumerate() {
+                self.canvas.set_draw_color(sdl_color(*color));
+
+                let rect = Rect::new(x + (width as i32) * offset as i32, y, width, height);
+                self.canvas.fill_rect(rect).unwrap();
+            }
+
+            y += 10;
+        }
+
+        y = 10;
+        x = 256 * SCALE as i32 + 20 + 48 + 16;
+        for palette in debug.sprite_palettes.iter() {
+            for (offset, color) in palette.iter().enumerate() {
+                self.canvas.set_draw_color(sdl_color(*color));
+
+                let rect = Rect::new(x + (width as i32) * offset as i32, y, width, height);
+                self.canvas.fill_rect(rect).unwrap();
+            }
+
+            y += 10;
+        }
+
+        y += 20;
+
+        //
+        // CHR
+        //
+        x = 256 * SCALE as i32 + 20;
+        self.render_pattern_table(&debug.pattern_tables[0], x, y);
+        self.render_pattern_table(&debug.pattern_tables[1], x + 144, y);
+    }
+
+    // For debugging purposes. Displays the four nametables, with the part that
+    // will be on screen outlined.
+    fn render_nametables(&mut self, debug: &DebugView) {
+        self.nametables.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+            for (y, row) in debug.nametables.iter().enumerate() {
+                for (x, color) in row.iter().enumerate() {
+                    let offset = y * pitch + 3 * x;
+
+                    buffer[offset]   = color.r;
+                    buffer[offset+1] = color.g;
+                    buffer[offset+2] = color.b;
+                }
+            }
+        }).unwrap();
+
+        let area = Rect::new(NAMETABLES_X, NAMETABLES_Y, 512, 480);
+        self.canvas.copy(&self.nametables, None, area).unwrap();
+
+        // The screen wraps around to the other side of the nametables, so it's
+        // outlined in up to four pieces
+        let (x, y) = (debug.scroll.0 as i32, debug.scroll.1 as i32);
+        self.canvas.set_clip_rect(area);
+        self.canvas.set_draw_color(pixels::Color::RGB(255, 0, 255));
+
+        for &dx in &[0, -512] {
+            for &dy in &[0, -480] {
+                let rect = Rect::new(NAMETABLES_X + x + dx, NAMETABLES_Y + y + dy, 256, 240);
+                self.canvas.draw_rect(rect).unwrap();
+            }
+        }
+
+        self.canvas.set_clip_rect(None);
+    }
+
+    fn render_tile_borders(&mut self) {
+        let scale = SCALE as i32;
+        self.canvas.set_draw_color(pixels::Color::RGB(200, 200, 200));
+
+        for x in 0 .. 32 {
+            for y in 0 .. 30 {
+                let rect = Rect::new(8 * x * scale,
+                                     8 * y * scale,
+                                     8 * scale as u32,
+                                     8 * scale as u32);
+                self.canvas.draw_rect(rect).unwrap();
+            }
+        }
+    }
+}
+
+impl<'a> VideoSink for Video<'a> {
+    fn present(&mut self, frame: &[Vec<Color>]) {
+        let scale = SCALE as usize;
+
+        self.texture.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+            for (y, row) in frame.iter().enumerate() {
+                for (x, color) in row.iter().enumerate() {
+                    let offset = scale*(y*pitch) + 3*(x*scale);
+
+                    for y2 in 0 .. scale {
+                        let offset = offset + (y2 * pitch);
+
+                        for x2 in 0 .. scale {
+                            let offset = offset + (x2 * 3);
+
+                            buffer[offset]   = color.r;
+                            buffer[offset+1] = color.g;
+                            buffer[offset+2] = color.b;
+                        }
+                    }
+                }
+            }
+        }).unwrap();
+
+        self.canvas.clear();
+        let dst = Rect::new(0, 0, 256 * SCALE, 240 * SCALE);
+        self.canvas.copy(&self.texture, None, dst).unwrap();
+
+        if let Some(debug) = self.debug.take() {
+            self.render_tile_data(&debug);
+            self.render_nametables(&debug);
+            self.render_tile_borders();
+        }
+
+        self.canvas.present();
+
+        if let Some(delay) = FRAME_DURATION.checked_sub(self.fps_start.elapsed()) {
+            thread::sleep(delay);
+        }
+
+        self.fps_start = Instant::now();
+    }
+
+    fn present_debug(&mut self, debug: &DebugView) {
+        self.debug = Some(debug.clone());
+    }
+}
+
+pub struct Audio {
+    device: AudioQueue<f32>,
+    samples: Vec<f32>,
+}
+
+impl Audio {
+    pub fn new(sdl_context: &sdl2::Sdl) -> Self {
+        let audio_subsystem = sdl_context.audio().unwrap();
+        debug!("audio driver: {}", audio_subsystem.current_audio_driver());
+
+        let desired_spec = AudioSpecDesired {
+            freq:     Some(44_100),
+            channels: Some(2),
+            samples:  Some(1024),
+        };
+        let device = audio_subsystem.open_queue(None, &desired_spec).unwrap();
+        device.resume();
+
+        Self {
+            device,
+            samples: Vec::new(),
+        }
+    }
+}
+
+impl AudioSink for Audio {
+    fn queue(&mut self, samples: &[f32]) {
+        // Super basic dynamic sampling implementation.
+        //
+        // If the number of samples is too low, we'll end up with crackling and
+        // popping because the audio backend is consuming the samples faster
+        // than we can produce them, but if we have too many samples, the audio
+        // will get more and more out of sync with the video.
+        //
+        // We want to keep the audio queue full of samples, and we want to
+        // maintain at roughly AUDIO_QUEUE_HIGH_WATER_MARK samples. So if we've
+        // got more than that many in the queue, we drop this frame's samples,
+        // and if we drop below, we queue them again.
+        //
+        // This is much better than past attempts, and only occasionally results
+        // in some cracking and popping. I can live with this for now :)
+        if self.device.size() >= AUDIO_QUEUE_HIGH_WATER_MARK {
+            return;
+        }
+
+        // The queue is stereo, so every sample goes to both channels
+        self.samples.clear();
+        for signal in samples {
+            self.samples.push(*signal);
+            self.samples.push(*signal);
+        }
+
+        self.device.queue(&self.samples);
+    }
+}
+
+pub struct Input {
+    event_pump: EventPump,
+    bindings: Bindings,
+
+    // Game controllers are opened as they're plugged in, including the ones
+    // that are already plugged in at startup, and take the first free port
+    controller_subsystem: Option<sdl2::GameControllerSubsystem>,
+    pads: [Option<GameController>; 4],
+
+    // Which way the left stick on each pad is pushed, horizontally and
+    // vertically, as -1, 0 or 1
+    sticks: [(i8, i8); 4],
+
+    // Whether keys are typed on the Family BASIC keyboard
+    keyboard: bool,
+}
+
+impl Input {
+    pub fn new(sdl_context: &sdl2::Sdl, bindings: Bindings) -> Self {
+        let controller_subsystem = match sdl_context.game_controller() {
+            Ok(subsystem) => Some(subsystem),
+            Err(e) => {
+                warn!("game controllers are unavailable: {}", e);
+                None
+            },
+        };
+
+        Self {
+            event_pump: sdl_context.event_pump().unwrap(),
+            bindings,
+            controller_subsystem,
+            pads: [None, None, None, None],
+            sticks: [(0, 0); 4],
+            keyboard: false,
+        }
+    }
+
+    // With the Family BASIC keyboard plugged in, the keys it has are typed on
+    // it, by where they are rather than what they're labelled, instead of
+    // doing whatever they're bound to.
+    pub fn set_keyboard(&mut self, keyboard: bool) {
+        self.keyboard = keyboard;
+    }
+
+    // The port that the pad with the given instance ID is plugged into
+    fn port_for_pad(&self, id: u32) -> Option<usize> {
+        self.pads.iter().position(|pad| {
+            pad.as_ref().map(|pad| pad.instance_id()) == Some(id)
+        })
+    }
+
+    fn add_pad(&mut self, index: u32) {
+        let subsystem = match &self.controller_subsystem {
+            Some(subsystem) => subsystem,
+            None            => return,
+        };
+
+        if !subsystem.is_game_controller(index) {
+            return;
+        }
+
+        let pad = match subsystem.open(index) {
+            Ok(pad) => pad,
+            Err(e)  => {
+                println!("unable to open game controller: {}", e);
+                return;
+            },
+        };
+
+        // Pads that were plugged in at startup can be announced twice
+        if self.port_for_pad(pad.instance_id()).is_some() {
+            return;
+        }
+
+        match self.pads.iter().position(|pad| pad.is_none()) {
+            Some(port) => {
+                println!("{} plugged into port {}", pad.name(), port + 1);
+                self.pads[port] = Some(pad);
+                self.sticks[port] = (0, 0);
+            },
+            None => println!("ignoring {}, all of the ports are in use", pad.name()),
+        }
+    }
+
+    // Unplugging a pad lets go of all of its buttons
+    fn remove_pad(&mut self, id: u32, events: &mut Vec<InputEvent>) {
+        if let Some(port) = self.port_for_pad(id) {
+            if let Some(pad) = self.pads[port].take() {
+                println!("{} unplugged from port {}", pad.name(), port + 1);
+            }
+
+            for &button in &ALL_BUTTONS {
+                events.push(InputEvent::Button(port, button, false));
+                events.push(InputEvent::Turbo(port, button, false));
+            }
+        }
+    }
+
+    fn press_pad_button(&self,
+                        id: u32,
+                        button: controller::Button,
+                        pressed: bool,
+                        events: &mut Vec<InputEvent>)
+    {
+        let port = match self.port_for_pad(id) {
+            Some(port) => port,
+            None       => return,
+        };
+
+        match self.bindings.action_for_pad_button(button) {
+            Some(PadAction::Button(button)) => events.push(InputEvent::Button(port, button, pressed)),
+            Some(PadAction::Turbo(button))  => events.push(InputEvent::Turbo(port, button, pressed)),
+            None                            => { },
+        }
+    }
+
+    // Moving the left stick presses and releases d-pad buttons, but only when
+    // it crosses the dead zone, so that it doesn't fight with the d-pad.
+    fn move_stick(&mut self, port: usize, axis: Axis, value: i16, events: &mut Vec<InputEvent>) {
+        let direction = if value <= -STICK_DEAD_ZONE {
+            -1
+        } else if value >= STICK_DEAD_ZONE {
+            1
+        } else {
+            0
+        };
+
+        let (previous, buttons) = match axis {
+            Axis::LeftX => (&mut self.sticks[port].0, (Button::Left, Button::Right)),
+            Axis::LeftY => (&mut self.sticks[port].1, (Button::Up, Button::Down)),
+            _           => return,
+        };
+
+        if *previous == direction {
+            return;
+        }
+        *previous = direction;
+
+        events.push(InputEvent::Button(port, buttons.0, direction == -1));
+        events.push(InputEvent::Button(port, buttons.1, direction == 1));
+    }
+}
+
+impl InputSource for Input {
+    fn poll(&mut self) -> Vec<InputEvent> {
+        let mut events = Vec::new();
+
+        let sdl_events: Vec<Event> = self.event_pump.poll_iter().collect();
+        for event in sdl_events {
+            if self.keyboard {
+                if let Some(key) = typed_key(&event) {
+                    events.push(key);
+                    continue;
+                }
+            }
+
+            match event {
+                Event::Quit { .. } => { events.push(InputEvent::Quit) },
+
+                Event::KeyDown { keycode: Some(key), .. } => {
+                    match self.bindings.action_for_key(key) {
+                        Some(Action::Button(port, button)) => {
+                            events.push(InputEvent::Button(port, button, true));
+                        },
+                        Some(Action::Turbo(port, button)) => {
+                            events.push(InputEvent::Turbo(port, button, true));
+                        },
+                        Some(Action::PowerPad(button)) => {
+                            events.push(InputEvent::PowerPad(button, true));
+                        },
+                        Some(Action::Hotkey(hotkey)) => events.push(InputEvent::Hotkey(hotkey)),
+                        Some(Action::Rewind)         => events.push(InputEvent::Rewind(true)),
+                        None                         => { },
+                    }
+                },
+
+                Event::KeyUp { keycode: Some(key), .. } => {
+                    match self.bindings.action_for_key(key) {
+                        Some(Action::Button(port, button)) => {
+                            events.push(InputEvent::Button(port, button, false));
+                        },
+                        Some(Action::Turbo(port, button)) => {
+                            events.push(InputEvent::Turbo(port, button, false));
+                        },
+                        Some(Action::PowerPad(button)) => {
+                            events.push(InputEvent::PowerPad(button, false));
+                        },
+                        Some(Action::Rewind) => events.push(InputEvent::Rewind(false)),
+                        _                    => { },
+                    }
+                },
+
+                Event::ControllerDeviceAdded { which, .. } => self.add_pad(which),
+                Event::ControllerDeviceRemoved { which, .. } => self.remove_pad(which, &mut events),
+
+                Event::ControllerButtonDown { which, button, .. } => {
+                    self.press_pad_button(which, button, true, &mut events);
+                },
+
+                Event::ControllerButtonUp { which, button, .. } => {
+                    self.press_pad_button(which, button, false, &mut events);
+                },
+
+                Event::ControllerAxisMotion { which, axis, value, .. } => {
+                    if let Some(port) = self.port_for_pad(which) {
+                        self.move_stick(port, axis, value, &mut events);
+                    }
+                },
+
+                Event::MouseMotion { x, y, .. } => {
+                    events.push(InputEvent::Mouse(screen_pixel(x, y)));
+                },
+
+                Event::Window { win_event: WindowEvent::Leave, .. } => {
+                    events.push(InputEvent::Mouse(None));
+                },
+
+                Event::MouseButtonDown { mouse_btn: MouseButton::Left, .. } => {
+                    events.push(InputEvent::MouseButton(true));
+                },
+
+                Event::MouseButtonUp { mouse_btn: MouseButton::Left, .. } => {
+                    events.push(InputEvent::MouseButton(false));
+                },
+
+                _ => {},
+            }
+        }
+
+        events
+    }
+}
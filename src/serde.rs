@@ -1,85 +1,92 @@
+use std::convert::TryFrom;
 use std::io::{Read, Write};
 use std::io;
-use std::fs::File;
 
 // u8
-pub fn encode_u8(output: &mut File, d: u8) -> io::Result<()> {
-    output.write(&[d])?;
+pub fn encode_u8(output: &mut dyn Write, d: u8) -> io::Result<()> {
+    output.write_all(&[d])?;
     Ok(())
 }
 
-pub fn decode_u8(input: &mut File) -> io::Result<u8> {
+pub fn decode_u8(input: &mut dyn Read) -> io::Result<u8> {
     let mut buf = [0; 1];
-    input.read(&mut buf)?;
+    input.read_exact(&mut buf)?;
     Ok(buf[0])
 }
 
 // u16
-pub fn encode_u16(output: &mut File, d: u16) -> io::Result<()> {
-    output.write(&d.to_le_bytes())?;
+pub fn encode_u16(output: &mut dyn Write, d: u16) -> io::Result<()> {
+    output.write_all(&d.to_le_bytes())?;
     Ok(())
 }
 
-pub fn decode_u16(input: &mut File) -> io::Result<u16> {
+pub fn decode_u16(input: &mut dyn Read) -> io::Result<u16> {
     let mut buf = [0; 2];
-    input.read(&mut buf)?;
+    input.read_exact(&mut buf)?;
     Ok(u16::from_le_bytes(buf))
 }
 
 // u32
-pub fn encode_u32(output: &mut File, d: u32) -> io::Result<()> {
-    output.write(&d.to_le_bytes())?;
+pub fn encode_u32(output: &mut dyn Write, d: u32) -> io::Result<()> {
+    output.write_all(&d.to_le_bytes())?;
     Ok(())
 }
 
-pub fn decode_u32(input: &mut File) -> io::Result<u32> {
+pub fn decode_u32(input: &mut dyn Read) -> io::Result<u32> {
     let mut buf = [0; 4];
-    input.read(&mut buf)?;
+    input.read_exact(&mut buf)?;
     Ok(u32::from_le_bytes(buf))
 }
 
 // u64
-pub fn encode_u64(output: &mut File, d: u64) -> io::Result<()> {
-    output.write(&d.to_le_bytes())?;
+pub fn encode_u64(output: &mut dyn Write, d: u64) -> io::Result<()> {
+    output.write_all(&d.to_le_bytes())?;
     Ok(())
 }
 
-pub fn decode_u64(input: &mut File) -> io::Result<u64> {
+pub fn decode_u64(input: &mut dyn Read) -> io::Result<u64> {
     let mut buf = [0; 8];
-    input.read(&mut buf)?;
+    input.read_exact(&mut buf)?;
     Ok(u64::from_le_bytes(buf))
 }
 
-// usize
-pub fn encode_usize(output: &mut File, d: usize) -> io::Result<()> {
-    // TODO usize isn't fixed size
-    output.write(&d.to_le_bytes())?;
-    Ok(())
+// f32
+pub fn encode_f32(output: &mut dyn Write, d: f32) -> io::Result<()> {
+    encode_u32(output, d.to_bits())
 }
 
-pub fn decode_usize(input: &mut File) -> io::Result<usize> {
-    // TODO usize isn't fixed size
-    let mut buf = [0; 8];
-    input.read(&mut buf)?;
-    Ok(usize::from_le_bytes(buf))
+pub fn decode_f32(input: &mut dyn Read) -> io::Result<f32> {
+    Ok(f32::from_bits(decode_u32(input)?))
 }
 
-// Vec<u8>
-pub fn encode_vec(output: &mut File, d: &Vec<u8>) -> io::Result<()> {
-    encode_usize(output, d.len())?;
+// usize, which is always stored as 64 bits, whatever the platform
+pub fn encode_usize(output: &mut dyn Write, d: usize) -> io::Result<()> {
+    encode_u64(output, d as u64)
+}
 
-    for x in 0 .. d.len() {
-        encode_u8(output, d[x])?;
-    }
+pub fn decode_usize(input: &mut dyn Read) -> io::Result<usize> {
+    let d = decode_u64(input)?;
+    usize::try_from(d).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, "value too large for usize")
+    })
+}
 
+// Vec<u8>
+pub fn encode_vec(output: &mut dyn Write, d: &[u8]) -> io::Result<()> {
+    encode_usize(output, d.len())?;
+    output.write_all(d)?;
     Ok(())
 }
 
-pub fn decode_vec(input: &mut File) -> io::Result<Vec<u8>> {
-    let mut v = vec![0; decode_usize(input)?];
+// The length comes from a file, so it's read up to rather than allocated up
+// front, in case the file is corrupt
+pub fn decode_vec(input: &mut dyn Read) -> io::Result<Vec<u8>> {
+    let len = decode_u64(input)?;
+    let mut v = Vec::new();
+    input.take(len).read_to_end(&mut v)?;
 
-    for x in 0 .. v.len() {
-        v[x] = decode_u8(input)?;
+    if v.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "vector is shorter than its length"));
     }
 
     Ok(v)
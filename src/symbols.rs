@@ -0,0 +1,339 @@
+// Symbol files
+//
+// Labels are read from the debug info files that ca65's linker writes with
+// --dbgfile, and from FCEUX's .nl files, which are loaded from next to the ROM
+// whenever there are any.
+//
+// The same CPU address can mean a different thing in each PRG-ROM bank, so
+// labels in PRG-ROM are kept by where they are in PRG-ROM, and only match an
+// address while their bank is mapped there. Labels anywhere else, like RAM and
+// registers, are kept by their CPU address.
+//
+// FCEUX keeps a file per 16KB bank, named after the ROM and the bank number in
+// hex, and another for RAM, with a label on each line:
+//
+//   game.nes.0.nl, game.nes.1.nl, ..., game.nes.ram.nl
+//
+//   $C0A5#nmi_handler#Called every frame
+//   $0300/10#buffer#
+//
+// ca65's debug info has a line for each segment and symbol, among other
+// things. The segments say where they are in the ROM file, and the symbols say
+// which segment they're in:
+//
+//   seg id=1,name="CODE",start=0x00C000,size=0x0123,addrsize=absolute,type=ro,oname="game.nes",ooffs=16
+//   sym id=4,name="nmi_handler",addrsize=absolute,scope=0,def=10,val=0xC0A5,seg=1,type=lab
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const INES_HEADER_SIZE: usize = 16;
+const NL_BANK_SIZE: usize = 0x4000;
+
+// Labels only describe addresses in the same 8KB of PRG-ROM
+const NEAREST_RANGE: usize = 0x2000;
+
+#[derive(Debug)]
+pub enum SymbolError {
+    IO(io::Error),
+    Invalid(String, usize, String),
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::IO(e) => write!(f, "{}", e),
+            SymbolError::Invalid(file, line, reason) => {
+                write!(f, "invalid symbol file {}, line {}: {}", file, line, reason)
+            },
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Symbols {
+    // PRG-ROM labels by offset, along with the CPU address that they're at
+    rom: BTreeMap<usize, (u16, String)>,
+
+    // Every other label, by CPU address
+    ram: HashMap<u16, String>,
+
+    // Where every label is, by name
+    names: HashMap<String, (u16, Option<usize>)>,
+}
+
+impl Symbols {
+    pub fn new_symbols() -> Self {
+        Self::default()
+    }
+
+    // Loads whichever symbol files there are next to a ROM: `game.dbg' for
+    // `game.nes', and FCEUX's `game.nes.*.nl' files.
+    pub fn load_for_rom(rom_path: &Path) -> Result<Self, SymbolError> {
+        let mut symbols = Self::new_symbols();
+
+        let dbg_path = rom_path.with_extension("dbg");
+        if dbg_path.exists() {
+            symbols.load_dbg(&read_file(&dbg_path)?, &dbg_path.display().to_string())?;
+        }
+
+        let nl_path = |suffix: &str| {
+            let mut path = rom_path.as_os_str().to_os_string();
+            path.push(format!(".{}.nl", suffix));
+            path
+        };
+
+        let ram_path = nl_path("ram");
+        if Path::new(&ram_path).exists() {
+            let name = Path::new(&ram_path).display().to_string();
+            symbols.load_nl(&read_file(Path::new(&ram_path))?, None, &name)?;
+        }
+
+        for bank in 0 ..= 0xff {
+            let bank_path = nl_path(&format!("{:X}", bank));
+            if Path::new(&bank_path).exists() {
+                let name = Path::new(&bank_path).display().to_string();
+                symbols.load_nl(&read_file(Path::new(&bank_path))?, Some(bank), &name)?;
+            }
+        }
+
+        Ok(symbols)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    // Adds a label at a CPU address, and where it is in PRG-ROM if it's there
+    pub fn add(&mut self, name: &str, address: u16, rom_offset: Option<usize>) {
+        match rom_offset {
+            Some(offset) => { self.rom.insert(offset, (address, name.to_string())); },
+            None         => { self.ram.insert(address, name.to_string()); },
+        }
+
+        self.names.insert(name.to_string(), (address, rom_offset));
+    }
+
+    // The label at an address, given where in PRG-ROM it's mapped to right now
+    pub fn label(&self, address: u16, rom_offset: Option<usize>) -> Option<&str> {
+        match rom_offset {
+            Some(offset) => self.rom.get(&offset).map(|(_, name)| name.as_str()),
+            None         => self.ram.get(&address).map(String::as_str),
+        }
+    }
+
+    // Where a label is, as a CPU address and where it is in PRG-ROM
+    pub fn lookup(&self, name: &str) -> Option<(u16, Option<usize>)> {
+        self.names.get(name).copied()
+    }
+
+    // An address as the nearest label before it and how far past it it is,
+    // like `main_loop+3', or just the address if there isn't one
+    pub fn describe(&self, address: u16, rom_offset: Option<usize>) -> String {
+        if let Some(label) = self.label(address, rom_offset) {
+            return label.to_string();
+        }
+
+        if let Some(offset) = rom_offset {
+            let start = offset.saturating_sub(NEAREST_RANGE - 1);
+            if let Some((label_offset, (_, name))) = self.rom.range(start ..= offset).next_back() {
+                if label_offset / NEAREST_RANGE == offset / NEAREST_RANGE {
+                    return format!("{}+{}", name, offset - label_offset);
+                }
+            }
+        }
+
+        format!("${:04X}", address)
+    }
+
+    // Loads an FCEUX .nl file, which is for RAM when there's no bank
+    pub fn load_nl(&mut self, text: &str, bank: Option<usize>, file: &str) -> Result<(), SymbolError> {
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = |reason: &str| SymbolError::Invalid(file.to_string(), i + 1, reason.to_string());
+
+            let mut fields = line.splitn(3, '#');
+            let address = fields.next().unwrap_or("");
+            let name = fields.next().ok_or_else(|| invalid("expected $ADDRESS#label#"))?;
+
+            // Arrays have a size after the address, but only their start is
+            // labelled
+            let address = address.split('/').next().unwrap_or("");
+            let address = address.strip_prefix('$')
+                .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| invalid("expected an address like $C000"))?;
+
+            if name.is_empty() {
+                continue;
+            }
+
+            let rom_offset = match bank {
+                Some(bank) if address >= 0x8000 => {
+                    Some(bank * NL_BANK_SIZE + (address as usize & (NL_BANK_SIZE - 1)))
+                },
+                _ => None,
+            };
+
+            self.add(name, address, rom_offset);
+        }
+
+        Ok(())
+    }
+
+    // Loads the labels from ca65's debug info
+    pub fn load_dbg(&mut self, text: &str, file: &str) -> Result<(), SymbolError> {
+        // Where each segment starts, in the CPU's address space and in
+        // PRG-ROM, if it's in ROM
+        let mut segments = HashMap::new();
+        let mut labels = Vec::new();
+
+        for (i, line) in text.lines().enumerate() {
+            let (kind, fields) = match line.split_once(char::is_whitespace) {
+                Some((kind, fields)) => (kind, parse_dbg_fields(fields)),
+                None                 => continue,
+            };
+
+            let invalid = |reason: &str| SymbolError::Invalid(file.to_string(), i + 1, reason.to_string());
+            let number = |name: &str| -> Result<Option<usize>, SymbolError> {
+                match fields.get(name) {
+                    Some(value) => parse_dbg_number(value)
+                        .map(Some)
+                        .ok_or_else(|| invalid(&format!("invalid {}", name))),
+                    None => Ok(None),
+                }
+            };
+
+            match kind {
+                "seg" => {
+                    let id = number("id")?.ok_or_else(|| invalid("segment without an id"))?;
+                    let start = number("start")?.unwrap_or(0);
+
+                    // Only segments that are written to the ROM have an offset
+                    // in it, and the offset includes the iNES header
+                    let rom_start = number("ooffs")?
+                        .filter(|_| fields.contains_key("oname"))
+                        .and_then(|offset| offset.checked_sub(INES_HEADER_SIZE));
+
+                    segments.insert(id, (start, rom_start));
+                },
+                "sym" if fields.get("type").map(String::as_str) == Some("lab") => {
+                    let name = fields.get("name").ok_or_else(|| invalid("symbol without a name"))?;
+                    let value = number("val")?.ok_or_else(|| invalid("label without a value"))?;
+                    labels.push((name.clone(), value, number("seg")?, i + 1));
+                },
+                _ => { },
+            }
+        }
+
+        for (name, value, segment, line) in labels {
+            if value > 0xffff {
+                return Err(SymbolError::Invalid(file.to_string(), line, format!("{} is out of range", name)));
+            }
+
+            let rom_offset = match segment.and_then(|id| segments.get(&id)) {
+                Some(&(start, Some(rom_start))) if value >= 0x8000 && value >= start => {
+                    Some(rom_start + value - start)
+                },
+                _ => None,
+            };
+
+            self.add(&name, value as u16, rom_offset);
+        }
+
+        Ok(())
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, SymbolError> {
+    fs::read_to_string(path).map_err(SymbolError::IO)
+}
+
+// Splits `id=1,name="CODE",start=0x00C000' up into its fields, allowing for
+// commas in quoted strings
+fn parse_dbg_fields(fields: &str) -> HashMap<String, String> {
+    let mut result = HashMap::new();
+    let mut field = String::new();
+    let mut quoted = false;
+
+    for c in fields.trim().chars().chain(std::iter::once(',')) {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                if let Some((name, value)) = field.split_once('=') {
+                    result.insert(name.to_string(), value.to_string());
+                }
+                field.clear();
+            },
+            _ => field.push(c),
+        }
+    }
+
+    result
+}
+
+fn parse_dbg_number(value: &str) -> Option<usize> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None      => value.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nl() {
+        let mut symbols = Symbols::new_symbols();
+        symbols.load_nl("$0300/10#buffer#\n$0010#frame##\n", None, "ram.nl").unwrap();
+        symbols.load_nl("$8000#reset#Starts here\n$8010#nmi_handler#\n", Some(1), "1.nl").unwrap();
+        symbols.load_nl("$C000#other_bank#\n", Some(3), "3.nl").unwrap();
+
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols.label(0x0300, None), Some("buffer"));
+        assert_eq!(symbols.label(0x8010, Some(0x4010)), Some("nmi_handler"));
+        assert_eq!(symbols.lookup("other_bank"), Some((0xc000, Some(0xc000))));
+
+        // The same address in another bank isn't labelled
+        assert_eq!(symbols.label(0x8010, Some(0x0010)), None);
+
+        assert_eq!(symbols.describe(0x8014, Some(0x4014)), "nmi_handler+4");
+        assert_eq!(symbols.describe(0x8014, Some(0x0014)), "$8014");
+
+        match symbols.load_nl("C000#no_dollar#\n", Some(0), "0.nl") {
+            Err(SymbolError::Invalid(_, 1, _)) => { },
+            _ => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn test_dbg() {
+        let dbg = "\
+version\tmajor=2,minor=0
+seg\tid=0,name=\"ZEROPAGE\",start=0x000000,size=0x0010,addrsize=zeropage,type=rw
+seg\tid=1,name=\"CODE\",start=0x00C000,size=0x0100,addrsize=absolute,type=ro,oname=\"game.nes\",ooffs=16400
+sym\tid=0,name=\"frame\",addrsize=zeropage,scope=0,def=1,val=0x2,seg=0,type=lab
+sym\tid=1,name=\"nmi_handler\",addrsize=absolute,scope=0,def=2,val=0xC0A5,seg=1,type=lab
+sym\tid=2,name=\"SPEED\",addrsize=zeropage,scope=0,def=3,val=0x5,type=equ
+";
+
+        let mut symbols = Symbols::new_symbols();
+        symbols.load_dbg(dbg, "game.dbg").unwrap();
+
+        assert_eq!(symbols.len(), 2);
+        assert_eq!(symbols.lookup("frame"), Some((0x0002, None)));
+        assert_eq!(symbols.lookup("nmi_handler"), Some((0xc0a5, Some(0x40a5))));
+        assert_eq!(symbols.lookup("SPEED"), None);
+    }
+}
@@ -0,0 +1,174 @@
+// Arkanoid controller, or Vaus
+//
+// http://wiki.nesdev.com/w/index.php/Arkanoid_controller
+//
+// The Vaus has a knob connected to a potentiometer, and a button. Strobing
+// $4016 latches the knob's position as an 8 bit number, which is then read
+// out one bit at a time, most significant bit first, and inverted.
+//
+// The NES version replaces the second controller,
+//
+// bit    | 7 - 5 |  4   |   3    | 2 - 0 |
+// $4017  |       | knob | button |       |
+//
+// and the Famicom version plugs into the expansion port instead, next to the
+// built in controllers.
+//
+// bit    | 7 - 2 |   1    |   0          |
+// $4016  |       | button | controller 1 |
+// $4017  |       | knob   | controller 2 |
+
+use std::io::{Read, Write};
+use std::io;
+
+use crate::frontend::InputEvent;
+use crate::input::{Device, InputDevice, Slot};
+use crate::mem::Memory;
+use crate::ppu::PPU;
+use crate::serde;
+
+// The range of the knob, from all the way left to all the way right
+const KNOB_MIN: u32 = 0x54;
+const KNOB_MAX: u32 = 0xf4;
+
+pub struct Vaus {
+    famicom: bool,
+
+    // How far the knob is turned, from 0 (left) to 255 (right), and whether
+    // the button is pressed
+    position: u8,
+    button: bool,
+
+    shift: u8,
+    strobe: u8,
+}
+
+impl Memory for Vaus {
+    fn write(&mut self, _address: u16, val: u8) {
+        self.strobe = val;
+
+        if self.strobe & 1 == 1 {
+            self.latch();
+        }
+    }
+
+    fn save(&self, output: &mut dyn Write) -> io::Result<()> {
+        serde::encode_u8(output, self.shift)?;
+        serde::encode_u8(output, self.strobe)?;
+        Ok(())
+    }
+
+    fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.shift = serde::decode_u8(input)?;
+        self.strobe = serde::decode_u8(input)?;
+        Ok(())
+    }
+}
+
+impl Device for Vaus {
+    fn kind(&self) -> InputDevice {
+        if self.famicom { InputDevice::FamicomVaus } else { InputDevice::Vaus }
+    }
+
+    fn slot(&self) -> Slot {
+        if self.famicom { Slot::Expansion } else { Slot::Port2 }
+    }
+
+    fn read_port(&mut self, port: usize, _ppu: &PPU) -> u8 {
+        match (self.famicom, port) {
+            (false, 0) => 0,
+            (false, _) => self.next_bit() << 4 | (self.button as u8) << 3,
+            (true, 0)  => (self.button as u8) << 1,
+            (true, _)  => self.next_bit() << 1,
+        }
+    }
+
+    // The knob follows the mouse across the screen, and stays where it is
+    // when the mouse leaves it
+    fn input(&mut self, event: &InputEvent) {
+        match *event {
+            InputEvent::Mouse(Some((x, _)))  => self.position = x,
+            InputEvent::MouseButton(pressed) => self.button = pressed,
+            _                                => { },
+        }
+    }
+}
+
+impl Vaus {
+    pub fn new_vaus() -> Self {
+        Self {
+            famicom: false,
+            position: 0x80,
+            button: false,
+            shift: 0,
+            strobe: 0,
+        }
+    }
+
+    pub fn new_famicom_vaus() -> Self {
+        Self {
+            famicom: true,
+            ..Self::new_vaus()
+        }
+    }
+
+    fn next_bit(&mut self) -> u8 {
+        let bit = !self.shift >> 7 & 1;
+
+        if self.strobe & 1 == 1 {
+            self.latch();
+        } else {
+            self.shift <<= 1;
+        }
+
+        bit
+    }
+
+    fn latch(&mut self) {
+        self.shift = self.knob();
+    }
+
+    // The potentiometer's reading for the knob's position
+    fn knob(&self) -> u8 {
+        (KNOB_MIN + self.position as u32 * (KNOB_MAX - KNOB_MIN) / 255) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_knob(vaus: &mut Vaus, ppu: &PPU) -> u8 {
+        vaus.write(0x4016, 1);
+        vaus.write(0x4016, 0);
+        (0 .. 8).fold(0, |value, _| value << 1 | (vaus.read_port(1, ppu) >> 4 & 1 ^ 1))
+    }
+
+    #[test]
+    fn test_vaus() {
+        let ppu = PPU::new_nes_ppu();
+        let mut vaus = Vaus::new_vaus();
+
+        vaus.input(&InputEvent::Mouse(Some((0, 100))));
+        assert_eq!(read_knob(&mut vaus, &ppu), 0x54);
+        vaus.input(&InputEvent::Mouse(Some((255, 100))));
+        assert_eq!(read_knob(&mut vaus, &ppu), 0xf4);
+
+        // Moving the mouse off the screen leaves the knob where it was
+        vaus.input(&InputEvent::Mouse(None));
+        assert_eq!(read_knob(&mut vaus, &ppu), 0xf4);
+
+        // The knob is only read when it's latched
+        vaus.write(0x4016, 1);
+        vaus.write(0x4016, 0);
+        vaus.input(&InputEvent::Mouse(Some((0, 100))));
+        assert_eq!(vaus.read_port(1, &ppu) & 0x10, 0x00);
+
+        vaus.input(&InputEvent::MouseButton(true));
+        assert_eq!(vaus.read_port(1, &ppu) & 0x08, 0x08);
+
+        let mut vaus = Vaus::new_famicom_vaus();
+        vaus.input(&InputEvent::MouseButton(true));
+        assert_eq!(vaus.read_port(0, &ppu), 0x02);
+    }
+}
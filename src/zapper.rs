@@ -0,0 +1,168 @@
+// Zapper light gun
+//
+// http://wiki.nesdev.com/w/index.php/Zapper
+//
+// The Zapper has a light sensor behind its lens, which sees the spot on the
+// screen that it's pointed at light up as the beam passes over it, and keeps
+// seeing it for a few scanlines after, until the phosphor fades. Games black
+// out the screen and draw bright targets when the trigger is pulled, and then
+// check whether the Zapper saw any light.
+//
+// bit    | 7 - 5 |    4    |   3   | 2 - 0 |
+// $4017  |       | trigger | light |       |
+//
+// The trigger bit is 1 while the trigger is pulled, and the light bit is 0
+// while light is seen, and 1 otherwise.
+
+use crate::frontend::InputEvent;
+use crate::input::{Device, InputDevice, Slot};
+use crate::mem::Memory;
+use crate::palette::Color;
+use crate::ppu::PPU;
+
+// How many scanlines a pixel stays visible to the Zapper after it's drawn
+const LIGHT_SCANLINES: usize = 26;
+
+// How far, in pixels, from the spot it's pointed at the Zapper can see
+const RADIUS: usize = 2;
+
+// How bright a pixel needs to be for the Zapper to see it, out of 255
+const BRIGHTNESS: u32 = 0xc0;
+
+#[derive(Default)]
+pub struct Zapper {
+    // The pixel the Zapper is pointed at, or None if it's pointed away from
+    // the screen
+    aim: Option<(u8, u8)>,
+    trigger: bool,
+}
+
+// The Zapper has nothing to strobe, and nothing to save
+impl Memory for Zapper { }
+
+impl Device for Zapper {
+    fn kind(&self) -> InputDevice {
+        InputDevice::Zapper
+    }
+
+    fn slot(&self) -> Slot {
+        Slot::Port2
+    }
+
+    // Reading has no side effects, the Zapper just reports what it sees right
+    // now, which depends on where the PPU has got to in the frame.
+    fn read_port(&mut self, port: usize, ppu: &PPU) -> u8 {
+        if port == 0 {
+            return 0;
+        }
+
+        let mut value = 0;
+
+        if !self.sees_light(ppu.get_pixels(), ppu.position()) {
+            value |= 0x08;
+        }
+
+        if self.trigger {
+            value |= 0x10;
+        }
+
+        value
+    }
+
+    // The Zapper follows the mouse
+    fn input(&mut self, event: &InputEvent) {
+        match *event {
+            InputEvent::Mouse(position)      => self.aim = position,
+            InputEvent::MouseButton(pressed) => self.trigger = pressed,
+            _                                => { },
+        }
+    }
+}
+
+impl Zapper {
+    pub fn new_zapper() -> Self {
+        Self::default()
+    }
+
+    // Whether any bright pixels near the aim were drawn recently, given the
+    // frame so far and the scanline and dot the PPU is on
+    fn sees_light(&self, pixels: &[Vec<Color>], (scanline, dot): (u16, u16)) -> bool {
+        let (x, y) = match self.aim {
+            Some((x, y)) => (x as usize, y as usize),
+            None         => return false,
+        };
+
+        let (scanline, dot) = (scanline as usize, dot as usize);
+
+        let top = y.saturating_sub(RADIUS);
+        let left = x.saturating_sub(RADIUS);
+
+        pixels.iter().enumerate().skip(top).take(y + RADIUS + 1 - top).any(|(py, row)| {
+            // Only pixels that were drawn in the last few scanlines are lit
+            if py > scanline || scanline - py >= LIGHT_SCANLINES {
+                return false;
+            }
+
+            row.iter().enumerate().skip(left).take(x + RADIUS + 1 - left).any(|(px, &color)| {
+                // The pixel on dot n is at x = n - 1
+                let drawn = py < scanline || px + 1 < dot;
+                drawn && brightness(color) >= BRIGHTNESS
+            })
+        })
+    }
+}
+
+// Perceived brightness, out of 255
+fn brightness(color: Color) -> u32 {
+    (299 * color.r as u32 + 587 * color.g as u32 + 114 * color.b as u32) / 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_brightness() {
+        assert_eq!(brightness(Color::rgb(0xff, 0xff, 0xff)), 0xff);
+        assert_eq!(brightness(Color::rgb(0, 0, 0)), 0);
+        assert!(brightness(Color::rgb(0xff, 0xfe, 0xff)) >= BRIGHTNESS);
+        assert!(brightness(Color::rgb(0x66, 0x66, 0x66)) < BRIGHTNESS);
+    }
+
+    #[test]
+    fn test_sees_light() {
+        let black = Color::rgb(0, 0, 0);
+        let white = Color::rgb(0xff, 0xfe, 0xff);
+
+        // A white box from (100, 100) to (107, 107)
+        let mut pixels = vec![vec![black; 256]; 240];
+        for row in &mut pixels[100 .. 108] {
+            for pixel in &mut row[100 .. 108] {
+                *pixel = white;
+            }
+        }
+
+        let mut zapper = Zapper::new_zapper();
+        zapper.aim = Some((104, 104));
+
+        // The beam hasn't got to the box yet
+        assert!(!zapper.sees_light(&pixels, (50, 0)));
+        assert!(!zapper.sees_light(&pixels, (102, 100)));
+
+        // It's just been drawn
+        assert!(zapper.sees_light(&pixels, (102, 110)));
+        assert!(zapper.sees_light(&pixels, (120, 0)));
+
+        // And faded away again
+        assert!(!zapper.sees_light(&pixels, (140, 0)));
+
+        // Close to the box is close enough, but not any further away
+        zapper.aim = Some((109, 104));
+        assert!(zapper.sees_light(&pixels, (120, 0)));
+        zapper.aim = Some((110, 104));
+        assert!(!zapper.sees_light(&pixels, (120, 0)));
+
+        zapper.aim = None;
+        assert!(!zapper.sees_light(&pixels, (120, 0)));
+    }
+}